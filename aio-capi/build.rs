@@ -0,0 +1,26 @@
+extern crate cbindgen;
+
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").expect("Cargo build scripts always have CARGO_MANIFEST_DIR");
+    let crate_dir = PathBuf::from(crate_dir);
+
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let out_dir = env::var("OUT_DIR").expect("Cargo build scripts always have OUT_DIR");
+    let out_dir = PathBuf::from(out_dir);
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).expect("Unable to read cbindgen.toml");
+
+    // The header goes with the build outputs rather than the sources, so that it always matches
+    // the library it was built with
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(out_dir.join("include/tokio_linux_aio.h"));
+}
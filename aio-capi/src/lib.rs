@@ -0,0 +1,545 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+//! C API for tokio-linux-aio
+//!
+//! This crate exposes an `AioContext` to C and C++ code through a stable C ABI, so that those
+//! parts of a program share the submission machinery, queue-depth limits and rate limits of the
+//! context with its Rust parts. The crate builds a shared and a static library; the header
+//! `tokio_linux_aio.h` is generated from this file into the `include` directory below the
+//! `OUT_DIR` of the build.
+//!
+//! Requests are identified by a cookie chosen by the caller. Submitting a request only hands it
+//! to the context; the request waits for a submission slot like any other request of the
+//! context. Its result is reported as a completion carrying the cookie, which is retrieved via
+//! `tla_poll_completions` or `tla_wait_completions`. Requests that fail before reaching the
+//! kernel are reported the same way.
+//!
+//! Functions returning `int` return 0 on success and a negated `errno` value on failure.
+//! Results of completions follow the kernel convention: the number of bytes transferred, or a
+//! negated `errno` value.
+
+#![allow(non_camel_case_types)]
+
+extern crate futures;
+extern crate futures_cpupool;
+extern crate libc;
+extern crate parking_lot;
+extern crate tokio_linux_aio;
+
+use std::collections;
+use std::convert;
+use std::io;
+use std::panic;
+use std::ptr;
+use std::slice;
+use std::sync;
+use std::time;
+
+use std::os::unix::io::RawFd;
+
+use libc::{c_int, c_void};
+
+use futures::future::Executor;
+use futures::Future;
+
+use tokio_linux_aio::{AioContext, AioContextBuilder, AioError, ClassLimit, OpClass, ReaperConfig, SyncLevel};
+
+/// No synchronization requirement for a write
+pub const TLA_SYNC_NONE: c_int = 0;
+
+/// Data is written to the device, but not necessarily meta data
+pub const TLA_SYNC_DATA: c_int = 1;
+
+/// Data and associated meta data is written to the device
+pub const TLA_SYNC_FULL: c_int = 2;
+
+/// Configuration of a context created via `tla_context_create`. Fields set to 0 select the
+/// default, except for the number of slots.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct tla_config {
+    /// The number of submission slots
+    pub slots: usize,
+
+    /// The limit on the bytes transferred by requests in flight; 0 for no limit
+    pub max_bytes_in_flight: usize,
+
+    /// The maximum number of reads in flight; 0 for no limit beyond the slots
+    pub max_reads: usize,
+
+    /// The maximum number of writes in flight; 0 for no limit beyond the slots
+    pub max_writes: usize,
+
+    /// The maximum number of syncs and synchronized writes in flight; 0 for no limit beyond the
+    /// slots
+    pub max_syncs: usize,
+}
+
+/// A completed request, as retrieved via `tla_poll_completions` or `tla_wait_completions`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct tla_completion {
+    /// The cookie passed when submitting the request
+    pub cookie: u64,
+
+    /// The number of bytes transferred, or a negated `errno` value
+    pub result: i64,
+}
+
+/// A snapshot of the activity of a context, as retrieved via `tla_get_stats`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct tla_stats {
+    /// The number of submission slots of the context
+    pub slots: usize,
+
+    /// The number of requests that have been submitted to the kernel and not completed yet
+    pub requests_in_flight: usize,
+
+    /// The number of bytes transferred by the requests in flight
+    pub bytes_in_flight: usize,
+
+    /// The number of requests waiting for a submission slot or for their share of the byte budget
+    pub requests_waiting: usize,
+
+    /// The number of requests held back by the rate limits of the context
+    pub requests_throttled: usize,
+
+    /// The number of `io_submit` calls made
+    pub submit_calls: usize,
+
+    /// The number of completions of requests submitted through this API that have not been
+    /// retrieved yet
+    pub completions_pending: usize,
+}
+
+// The requests issued through this API that have not completed yet, and the completions
+// waiting to be retrieved by the caller
+#[derive(Default)]
+struct Queue {
+    outstanding: usize,
+    ready: collections::VecDeque<tla_completion>,
+}
+
+struct Completions {
+    queue: parking_lot::Mutex<Queue>,
+    available: parking_lot::Condvar,
+}
+
+impl Completions {
+    // Account for a request about to be issued, until its completion has been pushed
+    fn pending(self: &sync::Arc<Self>) -> Pending {
+        self.queue.lock().outstanding += 1;
+        Pending(self.clone())
+    }
+
+    fn push(&self, completion: tla_completion) {
+        self.queue.lock().ready.push_back(completion);
+        self.available.notify_all();
+    }
+
+    // Wait until every request issued has completed
+    fn settle(&self) {
+        let mut queue = self.queue.lock();
+
+        while queue.outstanding > 0 {
+            self.available.wait(&mut queue);
+        }
+    }
+
+    // Move up to `max` completions into the output array, waiting until there are at least
+    // `min` of them or until the deadline has passed
+    fn take(&self, out: &mut [tla_completion], min: usize, deadline: Option<time::Instant>) -> usize {
+        let mut queue = self.queue.lock();
+
+        while queue.ready.len() < min {
+            match deadline {
+                None => self.available.wait(&mut queue),
+                Some(deadline) => {
+                    if self.available.wait_until(&mut queue, deadline).timed_out() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let count = out.len().min(queue.ready.len());
+
+        for (slot, completion) in out.iter_mut().zip(queue.ready.drain(..count)) {
+            *slot = completion;
+        }
+
+        count
+    }
+}
+
+// Held by the code reporting the completion of a request, which has either pushed the completion
+// or been dropped without doing so once this goes away
+struct Pending(sync::Arc<Completions>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.queue.lock().outstanding -= 1;
+        self.0.available.notify_all();
+    }
+}
+
+/// A context shared with C code, as created via `tla_context_create` or `wrap_context`.
+pub struct tla_context {
+    // the context through which requests are issued
+    context: AioContext,
+
+    // drives requests that wait for admission to the context until they are submitted
+    pool: futures_cpupool::CpuPool,
+
+    // the completions of requests issued through this API
+    completions: sync::Arc<Completions>,
+}
+
+// A buffer owned by the caller, which guarantees that it stays around until the completion of
+// the request has been reported
+struct RawBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for RawBuffer {}
+
+impl convert::AsRef<[u8]> for RawBuffer {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl convert::AsMut<[u8]> for RawBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+// The negated `errno` value for an error
+fn errno(error: &io::Error) -> c_int {
+    match error.raw_os_error() {
+        Some(code) => -code,
+        None if error.kind() == io::ErrorKind::TimedOut => -libc::ETIMEDOUT,
+        None => -libc::EIO,
+    }
+}
+
+// Run the body of an exported function, which must not unwind into the C code calling it; a
+// panic is reported as the fallback value instead
+fn guard<T, F: FnOnce() -> T>(fallback: T, body: F) -> T {
+    panic::catch_unwind(panic::AssertUnwindSafe(body)).unwrap_or(fallback)
+}
+
+/// Make a context available to C code, which issues requests through the same submission
+/// machinery and limits as the Rust code using the context. The returned pointer is released
+/// via `tla_context_destroy`, which leaves the context itself alone.
+pub fn wrap_context(context: &AioContext) -> *mut tla_context {
+    let context = tla_context {
+        context: context.clone(),
+        pool: futures_cpupool::Builder::new()
+            .pool_size(1)
+            .name_prefix("aio-capi-")
+            .create(),
+        completions: sync::Arc::new(Completions {
+            queue: parking_lot::Mutex::new(Queue::default()),
+            available: parking_lot::Condvar::new(),
+        }),
+    };
+
+    Box::into_raw(Box::new(context))
+}
+
+impl tla_context {
+    // Report the outcome of a request issued with the given cookie as its completion
+    fn reporter<Handle>(&self, cookie: u64) -> impl FnOnce(Result<(Handle, usize), AioError<Handle>>) + Send + 'static {
+        let pending = self.completions.pending();
+
+        move |result| {
+            let result = match result {
+                Ok((_, len)) => len as i64,
+                Err(err) => i64::from(errno(&err.error)),
+            };
+
+            pending.0.push(tla_completion { cookie, result });
+        }
+    }
+
+    // Hand a request to the thread driving its admission
+    fn issue<F>(&self, request: F) -> c_int
+        where
+            F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        match self.pool.execute(request) {
+            Ok(()) => 0,
+            Err(_) => -libc::ESHUTDOWN,
+        }
+    }
+}
+
+/// Create a context with the given configuration, whose completions are retrieved by a
+/// dedicated thread. Returns NULL on failure, with `errno` set.
+///
+/// # Safety
+/// `config` needs to point to a valid configuration.
+#[no_mangle]
+pub unsafe extern "C" fn tla_context_create(config: *const tla_config) -> *mut tla_context {
+    match guard(Err(libc::EIO), || create_context(config.as_ref())) {
+        Ok(ctx) => ctx,
+        Err(code) => {
+            *libc::__errno_location() = code;
+            ptr::null_mut()
+        }
+    }
+}
+
+// Create a context for `tla_context_create`, or return the `errno` value of the failure
+fn create_context(config: Option<&tla_config>) -> Result<*mut tla_context, c_int> {
+    let config = match config {
+        Some(config) if config.slots > 0 => *config,
+        _ => return Err(libc::EINVAL),
+    };
+
+    let mut builder = AioContextBuilder::new(config.slots);
+
+    if config.max_bytes_in_flight > 0 {
+        builder.max_bytes_in_flight(config.max_bytes_in_flight);
+    }
+
+    let classes = [
+        (OpClass::Read, config.max_reads),
+        (OpClass::Write, config.max_writes),
+        (OpClass::Sync, config.max_syncs),
+    ];
+
+    for &(class, max) in classes.iter() {
+        if max > 0 {
+            builder.class_limit(class, ClassLimit { reserved: 0, max: Some(max) });
+        }
+    }
+
+    let reaper = ReaperConfig {
+        name: Some("aio-capi-reaper".to_string()),
+        ..ReaperConfig::default()
+    };
+
+    match builder.build_with_reaper(&reaper) {
+        Ok(context) => Ok(wrap_context(&context)),
+        Err(err) => Err(-errno(&err)),
+    }
+}
+
+/// Release a context, blocking until every request submitted through it has completed, whether
+/// it is in flight or still waits for a submission slot, so that its buffer may be freed
+/// afterwards. Completions that have not been retrieved are discarded. The kernel context is torn
+/// down once the Rust code sharing it has let go of it as well.
+///
+/// # Safety
+/// `ctx` needs to have been returned by `tla_context_create` or `wrap_context`, and must not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn tla_context_destroy(ctx: *mut tla_context) {
+    guard((), || {
+        if !ctx.is_null() {
+            let ctx = Box::from_raw(ctx);
+            ctx.completions.settle();
+            drop(ctx);
+        }
+    })
+}
+
+/// Submit a read of `len` bytes from the file offset into the buffer. The buffer needs to stay
+/// valid until the completion of the request has been retrieved.
+///
+/// # Safety
+/// `ctx` needs to be a valid context, and `buf` needs to point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tla_submit_read(
+    ctx: *mut tla_context,
+    fd: c_int,
+    offset: u64,
+    buf: *mut c_void,
+    len: usize,
+    cookie: u64,
+) -> c_int {
+    guard(-libc::EIO, || {
+        let ctx = match ctx.as_ref() {
+            Some(ctx) if !buf.is_null() => ctx,
+            _ => return -libc::EINVAL,
+        };
+
+        let buffer = RawBuffer { ptr: buf as *mut u8, len };
+        ctx.issue(ctx.context.read_with(fd as RawFd, offset, buffer, ctx.reporter(cookie)))
+    })
+}
+
+/// Submit a write of `len` bytes from the buffer to the file offset, applying the given
+/// synchronization level (`TLA_SYNC_NONE`, `TLA_SYNC_DATA` or `TLA_SYNC_FULL`). The buffer needs
+/// to stay valid until the completion of the request has been retrieved.
+///
+/// # Safety
+/// `ctx` needs to be a valid context, and `buf` needs to point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tla_submit_write(
+    ctx: *mut tla_context,
+    fd: c_int,
+    offset: u64,
+    buf: *const c_void,
+    len: usize,
+    sync_level: c_int,
+    cookie: u64,
+) -> c_int {
+    guard(-libc::EIO, || {
+        let ctx = match ctx.as_ref() {
+            Some(ctx) if !buf.is_null() => ctx,
+            _ => return -libc::EINVAL,
+        };
+
+        let sync_level = match sync_level {
+            TLA_SYNC_NONE => SyncLevel::None,
+            TLA_SYNC_DATA => SyncLevel::Data,
+            TLA_SYNC_FULL => SyncLevel::Full,
+            _ => return -libc::EINVAL,
+        };
+
+        let buffer = RawBuffer { ptr: buf as *mut u8, len };
+        let write = ctx.context.write_sync_with(fd as RawFd, offset, buffer, sync_level, ctx.reporter(cookie));
+        ctx.issue(write)
+    })
+}
+
+/// Submit a sync of the file. The same caveat as for `AioContext::sync` applies: many file
+/// systems fail the request with `EINVAL`.
+///
+/// # Safety
+/// `ctx` needs to be a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn tla_submit_fsync(ctx: *mut tla_context, fd: c_int, cookie: u64) -> c_int {
+    guard(-libc::EIO, || submit_sync(ctx, fd, cookie, false))
+}
+
+/// Submit a sync of the data of the file. The same caveat as for `AioContext::data_sync`
+/// applies: many file systems fail the request with `EINVAL`.
+///
+/// # Safety
+/// `ctx` needs to be a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn tla_submit_fdatasync(ctx: *mut tla_context, fd: c_int, cookie: u64) -> c_int {
+    guard(-libc::EIO, || submit_sync(ctx, fd, cookie, true))
+}
+
+unsafe fn submit_sync(ctx: *mut tla_context, fd: c_int, cookie: u64, data_only: bool) -> c_int {
+    let ctx = match ctx.as_ref() {
+        Some(ctx) => ctx,
+        None => return -libc::EINVAL,
+    };
+
+    let sync = if data_only {
+        ctx.context.data_sync(fd as RawFd)
+    } else {
+        ctx.context.sync(fd as RawFd)
+    };
+
+    let pending = ctx.completions.pending();
+
+    ctx.issue(sync.then(move |result| {
+        let result = result.map_or_else(|err| i64::from(errno(&err)), |()| 0);
+        pending.0.push(tla_completion { cookie, result });
+        Ok(())
+    }))
+}
+
+/// Retrieve up to `max` completions without blocking. Returns the number of completions stored
+/// in `out`.
+///
+/// # Safety
+/// `ctx` needs to be a valid context, and `out` needs to have room for `max` completions.
+#[no_mangle]
+pub unsafe extern "C" fn tla_poll_completions(ctx: *mut tla_context, out: *mut tla_completion, max: usize) -> usize {
+    guard(0, || {
+        match ctx.as_ref() {
+            Some(ctx) if !out.is_null() => ctx.completions.take(slice::from_raw_parts_mut(out, max), 0, None),
+            _ => 0,
+        }
+    })
+}
+
+/// Retrieve up to `max` completions, blocking until at least `min` of them are available or
+/// until `timeout_ms` milliseconds have passed; a negative timeout waits indefinitely. Returns
+/// the number of completions stored in `out`.
+///
+/// # Safety
+/// `ctx` needs to be a valid context, and `out` needs to have room for `max` completions.
+#[no_mangle]
+pub unsafe extern "C" fn tla_wait_completions(
+    ctx: *mut tla_context,
+    out: *mut tla_completion,
+    min: usize,
+    max: usize,
+    timeout_ms: c_int,
+) -> usize {
+    guard(0, || {
+        let deadline = if timeout_ms < 0 {
+            None
+        } else {
+            Some(time::Instant::now() + time::Duration::from_millis(timeout_ms as u64))
+        };
+
+        match ctx.as_ref() {
+            Some(ctx) if !out.is_null() => {
+                ctx.completions
+                    .take(slice::from_raw_parts_mut(out, max), min.min(max), deadline)
+            }
+            _ => 0,
+        }
+    })
+}
+
+/// Retrieve a snapshot of the activity of the context.
+///
+/// # Safety
+/// `ctx` needs to be a valid context, and `out` needs to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn tla_get_stats(ctx: *mut tla_context, out: *mut tla_stats) -> c_int {
+    guard(-libc::EIO, || {
+        let (ctx, out) = match (ctx.as_ref(), out.as_mut()) {
+            (Some(ctx), Some(out)) => (ctx, out),
+            _ => return -libc::EINVAL,
+        };
+
+        let stats = ctx.context.stats();
+
+        *out = tla_stats {
+            slots: stats.slots,
+            requests_in_flight: stats.requests_in_flight,
+            bytes_in_flight: stats.bytes_in_flight,
+            requests_waiting: stats.requests_waiting,
+            requests_throttled: stats.requests_throttled,
+            submit_calls: stats.submit_calls,
+            completions_pending: ctx.completions.queue.lock().ready.len(),
+        };
+
+        0
+    })
+}
@@ -0,0 +1,70 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+// Builds the C test program against the shared library and the generated header, and runs it on
+// a temporary file.
+
+use std::env;
+use std::fs;
+use std::path;
+use std::process;
+
+#[test]
+fn c_program() {
+    let crate_dir = path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+    // the build script generates the header into the output directory of the crate
+    let include_dir = path::Path::new(env!("OUT_DIR")).join("include");
+
+    // the shared library is placed next to the directory holding the test executable
+    let exe = env::current_exe().unwrap();
+    let lib_dir = exe.parent().and_then(path::Path::parent).unwrap().to_path_buf();
+    assert!(lib_dir.join("libtokio_linux_aio_capi.so").exists(), "shared library not built");
+
+    let program = lib_dir.join("capi_test");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = process::Command::new(compiler)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(include_dir)
+        .arg(crate_dir.join("tests/capi_test.c"))
+        .arg("-o")
+        .arg(&program)
+        .arg(format!("-L{}", lib_dir.display()))
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-ltokio_linux_aio_capi")
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "failed to compile the C test program");
+
+    // the file needs to support direct I/O, which rules out tmpfs
+    let file_name = env::current_dir().unwrap().join(format!("test-capi-{}.dat", process::id()));
+    let output = process::Command::new(&program).arg(&file_name).output().unwrap();
+    let _ = fs::remove_file(&file_name);
+
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "C test program failed");
+}
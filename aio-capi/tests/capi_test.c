@@ -0,0 +1,163 @@
+/*
+ * Exercises the C API of tokio-linux-aio: writes a few blocks to the file given on the command
+ * line, reads them back with more requests than submission slots, and checks the completions and
+ * statistics along the way. Exits with a non-zero status on failure.
+ */
+
+#define _GNU_SOURCE
+
+#include <errno.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "tokio_linux_aio.h"
+
+#define BLOCK_SIZE 8192
+#define BLOCKS 16
+#define SLOTS 4
+
+#define CHECK(condition)                                                         \
+    do {                                                                         \
+        if (!(condition)) {                                                      \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__,     \
+                    #condition);                                                 \
+            exit(1);                                                             \
+        }                                                                        \
+    } while (0)
+
+static void fill_block(unsigned char *block, int key) {
+    for (int index = 0; index < BLOCK_SIZE; index++) {
+        block[index] = (unsigned char)(key + index);
+    }
+}
+
+static int validate_block(const unsigned char *block, int key) {
+    for (int index = 0; index < BLOCK_SIZE; index++) {
+        if (block[index] != (unsigned char)(key + index)) {
+            return 0;
+        }
+    }
+
+    return 1;
+}
+
+/* Wait for `count` completions, marking their cookies as seen */
+static void wait_for(tla_context *ctx, int count, int *seen, int64_t *results) {
+    tla_completion completions[BLOCKS];
+    int received = 0;
+
+    while (received < count) {
+        size_t n = tla_wait_completions(ctx, completions, 1, BLOCKS, 10000);
+        CHECK(n > 0);
+
+        for (size_t index = 0; index < n; index++) {
+            uint64_t cookie = completions[index].cookie;
+            CHECK(cookie < BLOCKS && !seen[cookie]);
+            seen[cookie] = 1;
+            results[cookie] = completions[index].result;
+        }
+
+        received += (int)n;
+    }
+}
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+
+    int fd = open(argv[1], O_RDWR | O_CREAT | O_DIRECT, 0600);
+    CHECK(fd >= 0);
+    CHECK(ftruncate(fd, BLOCK_SIZE * BLOCKS) == 0);
+
+    /* a context needs submission slots */
+    tla_config config = {0};
+    CHECK(tla_context_create(&config) == NULL && errno == EINVAL);
+
+    config.slots = SLOTS;
+    config.max_writes = 2;
+
+    tla_context *ctx = tla_context_create(&config);
+    CHECK(ctx != NULL);
+
+    unsigned char *blocks[BLOCKS];
+    int seen[BLOCKS];
+    int64_t results[BLOCKS];
+
+    for (int index = 0; index < BLOCKS; index++) {
+        CHECK(posix_memalign((void **)&blocks[index], 4096, BLOCK_SIZE) == 0);
+        fill_block(blocks[index], index);
+    }
+
+    CHECK(tla_submit_write(ctx, fd, 0, blocks[0], BLOCK_SIZE, 42, 0) == -EINVAL);
+
+    /* writes beyond the write limit wait for their turn */
+    memset(seen, 0, sizeof(seen));
+
+    for (int index = 0; index < BLOCKS; index++) {
+        int sync_level = index == BLOCKS - 1 ? TLA_SYNC_DATA : TLA_SYNC_NONE;
+        CHECK(tla_submit_write(ctx, fd, (uint64_t)index * BLOCK_SIZE, blocks[index], BLOCK_SIZE,
+                               sync_level, (uint64_t)index) == 0);
+    }
+
+    wait_for(ctx, BLOCKS, seen, results);
+
+    for (int index = 0; index < BLOCKS; index++) {
+        CHECK(results[index] == BLOCK_SIZE);
+        memset(blocks[index], 0, BLOCK_SIZE);
+    }
+
+    /* reads, with one of them failing on an invalid file descriptor */
+    memset(seen, 0, sizeof(seen));
+
+    for (int index = 0; index < BLOCKS; index++) {
+        int target = index == 3 ? -1 : fd;
+        CHECK(tla_submit_read(ctx, target, (uint64_t)index * BLOCK_SIZE, blocks[index], BLOCK_SIZE,
+                              (uint64_t)index) == 0);
+    }
+
+    wait_for(ctx, BLOCKS, seen, results);
+
+    for (int index = 0; index < BLOCKS; index++) {
+        if (index == 3) {
+            CHECK(results[index] == -EBADF);
+        } else {
+            CHECK(results[index] == BLOCK_SIZE);
+            CHECK(validate_block(blocks[index], index));
+        }
+    }
+
+    /* the outcome of a sync depends on the file system, but it is reported all the same */
+    CHECK(tla_submit_fsync(ctx, fd, 7) == 0);
+
+    tla_completion completion;
+    CHECK(tla_wait_completions(ctx, &completion, 1, 1, 10000) == 1);
+    CHECK(completion.cookie == 7 && (completion.result == 0 || completion.result == -EINVAL));
+    CHECK(tla_poll_completions(ctx, &completion, 1) == 0);
+
+    tla_stats stats;
+    CHECK(tla_get_stats(ctx, &stats) == 0);
+    CHECK(stats.slots == SLOTS);
+    CHECK(stats.requests_in_flight == 0 && stats.requests_waiting == 0);
+    CHECK(stats.completions_pending == 0);
+    CHECK(stats.submit_calls >= 2 * BLOCKS);
+
+    /* releasing the context waits for the requests submitted through it */
+    for (int index = 0; index < BLOCKS; index++) {
+        memset(blocks[index], 0, BLOCK_SIZE);
+        CHECK(tla_submit_read(ctx, fd, (uint64_t)index * BLOCK_SIZE, blocks[index], BLOCK_SIZE,
+                              (uint64_t)index) == 0);
+    }
+
+    tla_context_destroy(ctx);
+
+    for (int index = 0; index < BLOCKS; index++) {
+        CHECK(validate_block(blocks[index], index));
+        free(blocks[index]);
+    }
+
+    close(fd);
+    printf("C API test passed\n");
+    return 0;
+}
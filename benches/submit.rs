@@ -0,0 +1,151 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+// Benchmark of the submission and completion path of `AioContext`. Reports the throughput and
+// the numbers of heap allocations and `io_submit` calls per request, for requests issued one at a
+// time and in batches, with and without batched submission.
+//
+// Run via `cargo bench`.
+
+extern crate futures;
+extern crate futures_cpupool;
+extern crate libc;
+extern crate tokio_linux_aio;
+
+use std::alloc;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::time;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{future, Future};
+
+use tokio_linux_aio::{AioContext, AlignedBuffer, SubmitPolicy};
+
+// The global allocator, instrumented to count allocations
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::Layout) {
+        alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const BLOCK_SIZE: usize = 4096;
+const FILE_SIZE: usize = 1024 * 1024;
+const REQUESTS: usize = 20_000;
+
+// Run `requests` reads in batches of `depth` and report the results
+fn run(context: &AioContext, label: &str, fd: libc::c_int, depth: usize, requests: usize, report: bool) {
+    let mut buffers: Vec<_> = (0..depth)
+        .map(|_| AlignedBuffer::new(BLOCK_SIZE, BLOCK_SIZE).unwrap())
+        .collect();
+    let mut futures = Vec::with_capacity(depth);
+    let mut offset = 0;
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let calls = context.stats().submit_calls;
+    let start = time::Instant::now();
+
+    for _ in 0..requests / depth {
+        for buffer in buffers.drain(..) {
+            futures.push(context.read(fd, offset as u64, buffer));
+            offset = (offset + BLOCK_SIZE) % FILE_SIZE;
+        }
+
+        // futures submit their request when first polled, so they need to be polled together
+        buffers.extend(future::join_all(futures.drain(..)).wait().unwrap());
+    }
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let calls = context.stats().submit_calls - calls;
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+
+    if !report {
+        return;
+    }
+
+    println!(
+        "{:<9} read depth {:>3}: {:>8.0} requests/s, {:>6.0} ns/request, {:>5.2} allocations/request, \
+         {:>5.2} submit calls/request",
+        label,
+        depth,
+        requests as f64 * 1e9 / nanos as f64,
+        nanos as f64 / requests as f64,
+        allocations as f64 / requests as f64,
+        calls as f64 / requests as f64,
+    );
+}
+
+fn main() {
+    let path = env::temp_dir().join(format!("bench-aio-{}.dat", process::id()));
+
+    {
+        let mut file = fs::File::create(&path).unwrap();
+        let data: Vec<u8> = (0..FILE_SIZE).map(|index| index as u8).collect();
+        file.write_all(&data).and_then(|_| file.sync_all()).unwrap();
+    }
+
+    {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path)
+            .unwrap();
+
+        let pool = futures_cpupool::CpuPool::new(1);
+
+        let batched = SubmitPolicy::Batched {
+            window: time::Duration::from_secs(0),
+            max_batch: 64,
+        };
+
+        for &(label, policy) in &[("immediate", SubmitPolicy::Immediate), ("batched", batched)] {
+            let context = AioContext::builder(64).submit_policy(policy).build(&pool).unwrap();
+
+            // warm up
+            run(&context, label, file.as_raw_fd(), 32, REQUESTS / 10, false);
+
+            for &depth in &[1, 8, 32] {
+                run(&context, label, file.as_raw_fd(), depth, REQUESTS, true);
+            }
+        }
+    }
+
+    let _ = fs::remove_file(&path);
+}
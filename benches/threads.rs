@@ -0,0 +1,132 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+// Benchmark of the submission and completion path of `AioContext` under contention. A number of
+// threads share one context, each issuing reads in batches; reports the aggregate throughput.
+//
+// Run via `cargo bench`.
+
+extern crate futures;
+extern crate futures_cpupool;
+extern crate libc;
+extern crate tokio_linux_aio;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync;
+use std::thread;
+use std::time;
+
+use futures::{future, Future};
+
+use tokio_linux_aio::{AioContext, AlignedBuffer};
+
+const BLOCK_SIZE: usize = 4096;
+const FILE_SIZE: usize = 1024 * 1024;
+const DEPTH: usize = 16;
+const REQUESTS_PER_THREAD: usize = 10_000;
+
+// Issue reads in batches from the current thread until `requests` have completed
+fn issue(context: &AioContext, fd: libc::c_int, seed: usize, requests: usize) {
+    let mut buffers: Vec<_> = (0..DEPTH)
+        .map(|_| AlignedBuffer::new(BLOCK_SIZE, BLOCK_SIZE).unwrap())
+        .collect();
+    let mut futures = Vec::with_capacity(DEPTH);
+    let mut offset = (seed * BLOCK_SIZE * 7) % FILE_SIZE;
+
+    for _ in 0..requests / DEPTH {
+        for buffer in buffers.drain(..) {
+            futures.push(context.read(fd, offset as u64, buffer));
+            offset = (offset + BLOCK_SIZE) % FILE_SIZE;
+        }
+
+        buffers.extend(future::join_all(futures.drain(..)).wait().unwrap());
+    }
+}
+
+fn run(context: &AioContext, fd: libc::c_int, threads: usize) {
+    let barrier = sync::Arc::new(sync::Barrier::new(threads + 1));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|seed| {
+            let context = context.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                issue(&context, fd, seed, REQUESTS_PER_THREAD);
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = time::Instant::now();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    let requests = threads * REQUESTS_PER_THREAD;
+
+    println!(
+        "read threads {:>2}: {:>8.0} requests/s, {:>6.0} ns/request",
+        threads,
+        requests as f64 * 1e9 / nanos as f64,
+        nanos as f64 / requests as f64,
+    );
+}
+
+fn main() {
+    let path = env::temp_dir().join(format!("bench-aio-threads-{}.dat", process::id()));
+
+    {
+        let mut file = fs::File::create(&path).unwrap();
+        let data: Vec<u8> = (0..FILE_SIZE).map(|index| index as u8).collect();
+        file.write_all(&data).and_then(|_| file.sync_all()).unwrap();
+    }
+
+    {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path)
+            .unwrap();
+
+        let pool = futures_cpupool::CpuPool::new(1);
+        let context = AioContext::new(&pool, 256).unwrap();
+
+        // warm up
+        issue(&context, file.as_raw_fd(), 0, REQUESTS_PER_THREAD / 10);
+
+        for &threads in &[1, 2, 4, 8] {
+            run(&context, file.as_raw_fd(), threads);
+        }
+    }
+
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,278 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::time;
+
+// -----------------------------------------------------------------------------------------------
+// Configuration
+// -----------------------------------------------------------------------------------------------
+
+/// Configuration of adaptive concurrency control. The controller observes the latency of
+/// completed requests and adjusts the number of requests admitted to the kernel at once, within
+/// the submission slots of the context, by additive increase and multiplicative decrease: the
+/// limit grows while latency stays within the target and the limit is actually used up, and
+/// shrinks when latency exceeds the target.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdaptiveConcurrency {
+    /// The latency target; the limit is reduced when the average latency over a window exceeds it
+    pub target_latency: time::Duration,
+
+    /// The number of completions observed before each decision
+    pub window: usize,
+
+    /// The lower bound on the limit
+    pub min_limit: usize,
+
+    /// The initial limit; the number of submission slots of the context if unset
+    pub initial_limit: Option<usize>,
+
+    /// The amount by which the limit is raised per decision
+    pub increase: usize,
+
+    /// The factor by which the limit is reduced per decision, between 0 and 1
+    pub backoff: f64,
+}
+
+impl AdaptiveConcurrency {
+    /// Create a configuration for the given latency target, with default values otherwise.
+    ///
+    /// # Params
+    /// - target_latency: The latency target
+    pub fn new(target_latency: time::Duration) -> AdaptiveConcurrency {
+        AdaptiveConcurrency {
+            target_latency,
+            window: 32,
+            min_limit: 1,
+            initial_limit: None,
+            increase: 1,
+            backoff: 0.75,
+        }
+    }
+
+    // Check that the configuration is usable for a context with `nr` slots
+    pub(crate) fn validate(&self, nr: usize) -> Result<(), &'static str> {
+        if self.window == 0 {
+            Err("adaptive concurrency window must not be empty")
+        } else if self.min_limit == 0 || self.min_limit > nr {
+            Err("adaptive concurrency minimum must be between 1 and the number of slots")
+        } else if self.initial_limit.map_or(false, |limit| limit < self.min_limit || limit > nr) {
+            Err("initial adaptive concurrency limit out of range")
+        } else if !(self.backoff > 0.0 && self.backoff < 1.0) {
+            Err("adaptive concurrency backoff must be between 0 and 1")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A decision of the adaptive concurrency controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The limit has been raised
+    Increase,
+
+    /// The limit has been kept
+    Hold,
+
+    /// The limit has been reduced
+    Decrease,
+}
+
+/// Statistics on adaptive concurrency control, as part of `AioStats`.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyStats {
+    /// The current limit on requests in flight
+    pub limit: usize,
+
+    /// The latency target
+    pub target_latency: time::Duration,
+
+    /// The average latency over the last completed window, if any
+    pub window_latency: Option<time::Duration>,
+
+    /// The most recent decision, if any
+    pub last_decision: Option<Decision>,
+
+    /// The number of times the limit has been raised
+    pub increases: u64,
+
+    /// The number of times the limit has been kept
+    pub holds: u64,
+
+    /// The number of times the limit has been reduced
+    pub decreases: u64,
+}
+
+// -----------------------------------------------------------------------------------------------
+// Controller
+// -----------------------------------------------------------------------------------------------
+
+// Adjusts the limit on requests in flight based on the latency of completions
+#[derive(Debug)]
+pub(crate) struct Controller {
+    config: AdaptiveConcurrency,
+
+    // upper bound on the limit; the number of submission slots
+    max_limit: usize,
+
+    // the number of samples in the current window
+    samples: usize,
+
+    // the sum of latencies in the current window
+    total: time::Duration,
+
+    // whether the limit has been used up during the current window
+    saturated: bool,
+
+    stats: ConcurrencyStats,
+}
+
+impl Controller {
+    pub(crate) fn new(config: AdaptiveConcurrency, max_limit: usize) -> Controller {
+        Controller {
+            config,
+            max_limit,
+            samples: 0,
+            total: time::Duration::from_secs(0),
+            saturated: false,
+            stats: ConcurrencyStats {
+                limit: config.initial_limit.unwrap_or(max_limit),
+                target_latency: config.target_latency,
+                window_latency: None,
+                last_decision: None,
+                increases: 0,
+                holds: 0,
+                decreases: 0,
+            },
+        }
+    }
+
+    // The current limit on requests in flight
+    pub(crate) fn limit(&self) -> usize {
+        self.stats.limit
+    }
+
+    // Record the latency of a completed request, given the number of requests that have been in
+    // flight including it; returns the decision if the sample completes a window
+    pub(crate) fn record(&mut self, latency: time::Duration, in_flight: usize) -> Option<Decision> {
+        self.samples += 1;
+        self.total += latency;
+        self.saturated |= in_flight >= self.stats.limit;
+
+        if self.samples < self.config.window {
+            return None;
+        }
+
+        let average = self.total / self.samples as u32;
+        let limit = self.stats.limit;
+
+        let decision = if average > self.config.target_latency {
+            let reduced = ((limit as f64 * self.config.backoff) as usize).max(self.config.min_limit);
+
+            if reduced < limit {
+                self.stats.limit = reduced;
+                Decision::Decrease
+            } else {
+                Decision::Hold
+            }
+        } else if self.saturated && limit < self.max_limit {
+            // only probe for more concurrency if the current limit is actually in the way
+            self.stats.limit = (limit + self.config.increase).min(self.max_limit);
+            Decision::Increase
+        } else {
+            Decision::Hold
+        };
+
+        match decision {
+            Decision::Increase => self.stats.increases += 1,
+            Decision::Hold => self.stats.holds += 1,
+            Decision::Decrease => self.stats.decreases += 1,
+        }
+
+        self.stats.window_latency = Some(average);
+        self.stats.last_decision = Some(decision);
+        self.samples = 0;
+        self.total = time::Duration::from_secs(0);
+        self.saturated = false;
+
+        Some(decision)
+    }
+
+    pub(crate) fn stats(&self) -> ConcurrencyStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(millis: u64) -> time::Duration {
+        time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn additive_increase_multiplicative_decrease() {
+        let mut config = AdaptiveConcurrency::new(millis(10));
+        config.window = 4;
+        config.initial_limit = Some(8);
+        config.min_limit = 2;
+        let mut controller = Controller::new(config, 16);
+
+        // fast completions while the limit is in the way raise it
+        for _ in 0..3 {
+            assert!(controller.record(millis(1), 8).is_none());
+        }
+
+        assert!(controller.record(millis(1), 8) == Some(Decision::Increase));
+        assert!(controller.limit() == 9);
+
+        // fast completions well below the limit keep it
+        for _ in 0..3 {
+            controller.record(millis(1), 2);
+        }
+
+        assert!(controller.record(millis(1), 2) == Some(Decision::Hold));
+        assert!(controller.limit() == 9);
+
+        // slow completions reduce it, down to the minimum
+        let decisions: Vec<_> = (0..20).filter_map(|_| controller.record(millis(20), 9)).collect();
+        assert!(decisions.iter().take(4).all(|&decision| decision == Decision::Decrease));
+        assert!(decisions[4] == Decision::Hold);
+        assert!(controller.limit() == 2);
+
+        let stats = controller.stats();
+        assert!(stats.increases == 1 && stats.holds == 2 && stats.decreases == 4);
+        assert!(stats.window_latency == Some(millis(20)));
+        assert!(stats.last_decision == Some(Decision::Hold));
+    }
+
+    #[test]
+    fn configuration_checks() {
+        let config = AdaptiveConcurrency::new(millis(1));
+        assert!(config.validate(4).is_ok());
+        assert!(AdaptiveConcurrency { window: 0, ..config }.validate(4).is_err());
+        assert!(AdaptiveConcurrency { min_limit: 5, ..config }.validate(4).is_err());
+        assert!(AdaptiveConcurrency { initial_limit: Some(5), ..config }.validate(4).is_err());
+        assert!(AdaptiveConcurrency { backoff: 1.0, ..config }.validate(4).is_err());
+    }
+}
@@ -20,11 +20,11 @@
 // SOFTWARE.
 // ===============================================================================================
 
-pub use libc::c_long;
+pub use libc::{c_long, sigset_t};
 
 // Relevant symbols from the native bindings exposed via aio-bindings
 pub use aio_bindings::{aio_context_t, io_event, iocb, syscall, timespec, 
-                       __NR_io_destroy, __NR_io_getevents, __NR_io_setup, __NR_io_submit, 
+                       __NR_io_destroy, __NR_io_getevents, __NR_io_pgetevents, __NR_io_setup, __NR_io_submit, 
                        IOCB_CMD_PREAD, IOCB_CMD_PWRITE, IOCB_CMD_FSYNC, IOCB_CMD_FDSYNC, IOCB_FLAG_RESFD, 
                        RWF_DSYNC, RWF_SYNC};
 
@@ -77,3 +77,37 @@ pub unsafe fn io_getevents(
         timeout,
     )
 }
+
+// The size of the signal set as understood by the kernel, which is smaller than `sigset_t`
+pub const KERNEL_SIGSET_SIZE: usize = 8;
+
+// The signal mask argument of `io_pgetevents`, which the kernel headers do not export.
+#[repr(C)]
+pub struct aio_sigset {
+    pub sigmask: *const sigset_t,
+    pub sigsetsize: usize,
+}
+
+// Retrieve completion events like `io_getevents`, while applying the given signal mask for the
+// duration of the call. Available from Linux 4.18 onwards.
+//
+// See [io_pgetevents(2)](http://man7.org/linux/man-pages/man2/io_pgetevents.2.html) for details.
+#[inline(always)]
+pub unsafe fn io_pgetevents(
+    ctx: aio_context_t,
+    min_nr: c_long,
+    max_nr: c_long,
+    events: *mut io_event,
+    timeout: *mut timespec,
+    usig: *const aio_sigset,
+) -> c_long {
+    syscall(
+        __NR_io_pgetevents as c_long,
+        ctx,
+        min_nr,
+        max_nr,
+        events,
+        timeout,
+        usig,
+    )
+}
@@ -0,0 +1,398 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync;
+use std::thread;
+use std::time;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use libc;
+use parking_lot;
+
+use raw::{CompletionQueue, IocbBuilder, Kernel, SubmissionQueue};
+use slab::{Capacity, Ticket};
+use timer::Timer;
+
+// -----------------------------------------------------------------------------------------------
+// Submission policy
+// -----------------------------------------------------------------------------------------------
+
+/// How requests that have secured a submission slot are handed to the kernel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SubmitPolicy {
+    /// Submit every request with an `io_submit` call of its own, for the lowest latency
+    Immediate,
+
+    /// Queue requests and submit them together with a single `io_submit` call, trading latency
+    /// for fewer system calls. The queued requests are submitted once there are `max_batch` of
+    /// them, or once `window` has passed since the first one was queued. With a zero window,
+    /// they are submitted as soon as one of the queuing tasks is polled again, which happens
+    /// after the executor has run the other tasks that were ready, so that the requests issued
+    /// during one executor tick go out together.
+    ///
+    /// With a zero window, nothing submits a batch on behalf of futures that are no longer
+    /// polled. If every future of a batch is leaked with `mem::forget` rather than dropped, the
+    /// batch is only submitted when the context shuts down, and leaving an `AioContext::scope`
+    /// that issued one of the requests blocks forever. Use a non-zero window where this can
+    /// happen.
+    Batched {
+        /// The time requests may wait for others to join their batch
+        window: time::Duration,
+
+        /// The maximum number of requests submitted with one call
+        max_batch: usize,
+    },
+}
+
+impl Default for SubmitPolicy {
+    fn default() -> SubmitPolicy {
+        SubmitPolicy::Immediate
+    }
+}
+
+impl SubmitPolicy {
+    // Check the policy for settings that cannot work
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match *self {
+            SubmitPolicy::Batched { max_batch: 0, .. } => Err("batches need to hold at least one request"),
+            _ => Ok(()),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Submitter
+// -----------------------------------------------------------------------------------------------
+
+// The requests queued for the next batch
+struct Batch {
+    // control blocks of the queued requests, in order of arrival
+    queue: SubmissionQueue,
+
+    // the time the first request of the batch was queued
+    started: Option<time::Instant>,
+
+    // whether a timer callback is pending to submit the batch
+    timer_armed: bool,
+}
+
+// the longest time a drain waits for completions before checking whether it is done
+const DRAIN_WAIT: time::Duration = time::Duration::from_millis(10);
+
+// Hands requests to the kernel through submission queues according to the submission policy of
+// a context, and keeps track of the number of `io_submit` calls this takes. A batch collects its
+// requests in a queue of its own; under the immediate policy, each request goes through a queue
+// of its own, so that concurrent submissions do not wait for each other.
+//
+// The kernel context is destroyed along with the last queue on it. Once the completions of the
+// context are no longer retrieved, the context is closed: new requests fail, and the requests in
+// flight are drained, so that no request ever gives back its buffer while the kernel may still
+// access it.
+pub(crate) struct Submitter {
+    // the kernel context to submit AIO requests to
+    kernel: Kernel,
+
+    // the number of submission slots
+    nr: usize,
+
+    // the policy in effect, with the batch size limited to the number of slots
+    policy: SubmitPolicy,
+
+    // the slab entries backing the requests, through which rejected requests are failed
+    capacity: sync::Arc<Capacity>,
+
+    // submits batches once their window has passed
+    timer: sync::Arc<Timer>,
+
+    // the requests waiting to be submitted
+    batch: parking_lot::Mutex<Batch>,
+
+    // the number of `io_submit` calls made, and the number of requests they carried
+    calls: AtomicUsize,
+    submitted: AtomicUsize,
+
+    // set once the completions of the context are no longer retrieved
+    closed: AtomicBool,
+
+    // the number of requests past the check for a closed context, which a drain waits for
+    submitting: AtomicUsize,
+}
+
+impl fmt::Debug for Submitter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Submitter {{ policy: {:?}, calls: {} }}", self.policy, self.calls())
+    }
+}
+
+impl Submitter {
+    pub(crate) fn new(
+        kernel: Kernel,
+        policy: SubmitPolicy,
+        nr: usize,
+        capacity: sync::Arc<Capacity>,
+        timer: sync::Arc<Timer>,
+    ) -> Submitter {
+        let (policy, reserve) = match policy {
+            SubmitPolicy::Immediate => (policy, 0),
+            SubmitPolicy::Batched { window, max_batch } => {
+                let max_batch = max_batch.min(nr.max(1));
+                (SubmitPolicy::Batched { window, max_batch }, max_batch)
+            }
+        };
+
+        let queue = SubmissionQueue::new(&kernel, reserve);
+
+        Submitter {
+            kernel,
+            nr,
+            policy,
+            capacity,
+            timer,
+            batch: parking_lot::Mutex::new(Batch {
+                queue,
+                started: None,
+                timer_armed: false,
+            }),
+            calls: AtomicUsize::new(0),
+            submitted: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            submitting: AtomicUsize::new(0),
+        }
+    }
+
+    // Whether queued requests wait for the submitting task to be polled again
+    pub(crate) fn flushes_on_poll(&self) -> bool {
+        match self.policy {
+            SubmitPolicy::Immediate => false,
+            SubmitPolicy::Batched { window, .. } => window == time::Duration::from_secs(0),
+        }
+    }
+
+    // The number of `io_submit` calls made so far
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    // The number of `io_submit` calls saved by submitting requests in batches
+    pub(crate) fn calls_saved(&self) -> usize {
+        // the two counters are updated separately, so a snapshot may be off by a batch
+        self.submitted.load(Ordering::Relaxed).saturating_sub(self.calls())
+    }
+
+    // Submit a request under the given ticket. Under the immediate policy, a request refused by
+    // the kernel is failed right away and its entry released; in a batch, the error is reported
+    // as the result of the request instead. Requests on a closed context fail with `ESHUTDOWN`.
+    //
+    // The memory referenced by the control block is kept alive by the slab entry of the request
+    // until its completion has been retrieved.
+    pub(crate) fn submit(submitter: &sync::Arc<Submitter>, ticket: Ticket, iocb: &IocbBuilder) -> io::Result<()> {
+        // announce the request before checking for a closed context, so that a drain either
+        // turns it away or waits for it to be handed over
+        submitter.submitting.fetch_add(1, Ordering::SeqCst);
+        let result = Submitter::submit_unless_closed(submitter, ticket, iocb);
+        submitter.submitting.fetch_sub(1, Ordering::SeqCst);
+
+        result
+    }
+
+    fn submit_unless_closed(submitter: &sync::Arc<Submitter>, ticket: Ticket, iocb: &IocbBuilder) -> io::Result<()> {
+        if submitter.closed.load(Ordering::SeqCst) {
+            submitter.capacity.cancel(ticket, libc::ESHUTDOWN);
+            return Err(io::Error::from_raw_os_error(libc::ESHUTDOWN));
+        }
+
+        let (window, max_batch) = match submitter.policy {
+            SubmitPolicy::Immediate => {
+                let mut queue = SubmissionQueue::new(&submitter.kernel, 1);
+                let queued = unsafe { queue.push(ticket.to_data(), iocb) };
+                debug_assert!(queued);
+
+                let result = queue.submit();
+                submitter.record(queue.calls(), 1);
+
+                let error = match result {
+                    Ok(1) => return Ok(()),
+
+                    // the kernel context is full
+                    Ok(_) => io::Error::from_raw_os_error(libc::EAGAIN),
+                    Err(rejected) => rejected.error,
+                };
+
+                // the kernel will never report a completion for this request, so we release
+                // its slab entry and thereby its slot right away
+                submitter.capacity.cancel(ticket, error.raw_os_error().unwrap_or(libc::EIO));
+                return Err(error);
+            }
+            SubmitPolicy::Batched { window, max_batch } => (window, max_batch),
+        };
+
+        let mut batch = submitter.batch.lock();
+
+        // a batch is flushed once it is full, so there is always room for another request
+        let queued = unsafe { batch.queue.push(ticket.to_data(), iocb) };
+        debug_assert!(queued);
+
+        if batch.queue.len() >= max_batch {
+            submitter.flush_batch(batch);
+        } else if batch.started.is_none() {
+            let now = time::Instant::now();
+            batch.started = Some(now);
+
+            if window > time::Duration::from_secs(0) && !batch.timer_armed {
+                batch.timer_armed = true;
+                Submitter::schedule(submitter, now + window);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Close the context and retrieve the completions of the requests in flight from the given
+    // queue, blocking until the kernel has reported every one of them. Used by the party that
+    // retrieves the completions of the context once it stops doing so: new requests fail with
+    // `ESHUTDOWN` from then on, and the buffers of the requests in flight are not handed back
+    // while the kernel may still access them.
+    pub(crate) fn drain(&self, completions: &mut CompletionQueue) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        while self.submitting.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+
+        // queued requests hold entries, and will only complete once submitted
+        self.flush();
+
+        let mut events = Vec::with_capacity(self.nr);
+
+        // a request being flushed by another thread is in flight, but may not have reached the
+        // kernel yet, so the waits are bounded
+        while self.capacity.requests_in_flight() > 0 {
+            events.clear();
+
+            match completions.retrieve(1, self.nr, Some(DRAIN_WAIT), None, &mut events) {
+                Ok(_) => self.capacity.complete_all(&events, |_, _| {}),
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+
+                // the requests left in flight keep their entries rather than being failed
+                Err(error) => {
+                    println!("WARN: failed to drain the AIO requests in flight: {}", error);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Submit the queued requests, if any
+    pub(crate) fn flush(&self) {
+        let batch = self.batch.lock();
+
+        if !batch.queue.is_empty() {
+            self.flush_batch(batch);
+        }
+    }
+
+    // A queue of its own for submitting requests to the kernel context of the submitter, which
+    // bypasses the slots and the accounting of the context
+    pub(crate) fn submission_queue(&self, capacity: usize) -> SubmissionQueue {
+        SubmissionQueue::new(&self.kernel, capacity)
+    }
+
+    // Arrange for the batch to be submitted at the given time
+    fn schedule(submitter: &sync::Arc<Submitter>, at: time::Instant) {
+        let weak = sync::Arc::downgrade(submitter);
+
+        submitter.timer.schedule(at, move || {
+            if let Some(submitter) = weak.upgrade() {
+                submitter.expire();
+            }
+        });
+    }
+
+    // Submit the batch if its window has passed; a batch started after the timer was armed gets
+    // a timer of its own
+    fn expire(self: sync::Arc<Submitter>) {
+        let window = match self.policy {
+            SubmitPolicy::Batched { window, .. } => window,
+            SubmitPolicy::Immediate => return,
+        };
+
+        let mut batch = self.batch.lock();
+        batch.timer_armed = false;
+
+        if let Some(started) = batch.started {
+            if started + window <= time::Instant::now() {
+                self.flush_batch(batch);
+            } else {
+                batch.timer_armed = true;
+                Submitter::schedule(&self, started + window);
+            }
+        }
+    }
+
+    // Take the queue of the batch and hand its requests to the kernel. The lock is released
+    // before submitting, so that other requests can join the next batch in the meantime.
+    fn flush_batch(&self, mut batch: parking_lot::MutexGuard<Batch>) {
+        let max_batch = match self.policy {
+            SubmitPolicy::Batched { max_batch, .. } => max_batch,
+            SubmitPolicy::Immediate => 0,
+        };
+
+        let queue = mem::replace(&mut batch.queue, SubmissionQueue::new(&self.kernel, max_batch));
+        batch.started = None;
+        drop(batch);
+
+        self.submit_batch(queue);
+    }
+
+    // Hand the requests of a batch to the kernel. The queue keeps the requests the kernel does not
+    // accept, and hands back a request it refuses, so the requests after a refused one are
+    // submitted anew.
+    fn submit_batch(&self, mut queue: SubmissionQueue) {
+        let requests = queue.len();
+
+        loop {
+            let (tag, error) = match queue.submit() {
+                Ok(_) => match queue.pop() {
+                    None => break,
+
+                    // the kernel context is full
+                    Some(tag) => (tag, libc::EAGAIN),
+                },
+                Err(rejected) => (rejected.tag, rejected.error.raw_os_error().unwrap_or(libc::EIO)),
+            };
+
+            // the kernel will never report a completion for the refused request
+            self.capacity.complete(tag, -i64::from(error));
+        }
+
+        self.record(queue.calls(), requests);
+    }
+
+    fn record(&self, calls: usize, requests: usize) {
+        self.calls.fetch_add(calls, Ordering::Relaxed);
+        self.submitted.fetch_add(requests, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,298 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::convert;
+use std::fmt;
+use std::io;
+use std::sync;
+
+use std::os::unix::io::RawFd;
+
+use futures;
+use futures::executor;
+use futures::future::{self, Future};
+
+use {AioContext, AioContextBuilder, AioError, AioReadResultFuture, AioStats, AioWriteResultFuture, ReaperConfig,
+     SyncLevel};
+
+/// A request issued as part of a group via `BlockingAioContext::submit_many`.
+#[derive(Debug)]
+pub enum BlockingRequest<Handle> {
+    /// Read into the buffer from the given file offset
+    Read {
+        /// The file descriptor of the file from which to read
+        fd: RawFd,
+
+        /// The file offset where we want to read from
+        offset: u64,
+
+        /// A buffer to receive the read results
+        buffer: Handle,
+    },
+
+    /// Write the buffer to the given file offset
+    Write {
+        /// The file descriptor of the file to which to write
+        fd: RawFd,
+
+        /// The file offset where we want to write to
+        offset: u64,
+
+        /// A buffer holding the data to be written
+        buffer: Handle,
+
+        /// A synchronization level to apply for this write operation
+        sync_level: SyncLevel,
+    },
+}
+
+// The future of a request within a group, which never fails itself so that the outcome of every
+// request of the group is retained
+enum Pending<Handle>
+    where
+        Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+{
+    Read(AioReadResultFuture<Handle>),
+    Write(AioWriteResultFuture<Handle>),
+}
+
+impl<Handle> futures::Future for Pending<Handle>
+    where
+        Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+{
+    type Item = Result<Handle, AioError<Handle>>;
+    type Error = ();
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        let result = match *self {
+            Pending::Read(ref mut read) => read.poll(),
+            Pending::Write(ref mut write) => write.poll(),
+        };
+
+        match result {
+            Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+            Ok(futures::Async::Ready(buffer)) => Ok(futures::Async::Ready(Ok(buffer))),
+            Err(err) => Ok(futures::Async::Ready(Err(err))),
+        }
+    }
+}
+
+// Lets the first round of submissions happen without waiting for any of them
+struct NoopNotify;
+
+impl executor::Notify for NoopNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+/// A group of requests issued via `BlockingAioContext::submit_many`, whose results are collected
+/// by `wait_all`.
+pub struct PendingRequests<Handle>
+    where
+        Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+{
+    // the requests of the group, unless they have all completed right away
+    requests: Option<executor::Spawn<future::JoinAll<Vec<Pending<Handle>>>>>,
+
+    // the results, if they were available right away
+    results: Option<Vec<Result<Handle, AioError<Handle>>>>,
+}
+
+impl<Handle> fmt::Debug for PendingRequests<Handle>
+    where
+        Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "PendingRequests {{ completed: {} }}", self.results.is_some())
+    }
+}
+
+impl<Handle> PendingRequests<Handle>
+    where
+        Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+{
+    /// Block until every request of the group has completed, and return their results in the
+    /// order in which the requests were passed to `submit_many`.
+    pub fn wait_all(mut self) -> Vec<Result<Handle, AioError<Handle>>> {
+        if let Some(results) = self.results.take() {
+            return results;
+        }
+
+        match self.requests.take().unwrap().wait_future() {
+            Ok(results) => results,
+            Err(()) => unreachable!(),
+        }
+    }
+}
+
+/// A context for callers without an event loop, which blocks the calling thread until a request
+/// has completed.
+///
+/// Completions are retrieved by a dedicated reaper thread, as for contexts built via
+/// `AioContextBuilder::build_with_reaper`, so that nothing needs to be driven by the caller.
+/// The requests of a group issued via `submit_many` are in flight at the same time, which
+/// retains the parallelism of kernel AIO.
+#[derive(Clone, Debug)]
+pub struct BlockingAioContext {
+    context: AioContext,
+}
+
+impl BlockingAioContext {
+    /// Create a new blocking context with the given number of submission slots and a reaper
+    /// thread of the default configuration.
+    ///
+    /// # Params
+    /// - nr: Number of submission slots for IO requests
+    pub fn new(nr: usize) -> Result<BlockingAioContext, io::Error> {
+        AioContextBuilder::new(nr).build_blocking(&ReaperConfig::default())
+    }
+
+    pub(crate) fn from_context(context: AioContext) -> BlockingAioContext {
+        BlockingAioContext { context }
+    }
+
+    /// The asynchronous context underneath, for issuing requests without blocking.
+    pub fn context(&self) -> &AioContext {
+        &self.context
+    }
+
+    /// Retrieve a snapshot of the current activity of this context.
+    pub fn stats(&self) -> AioStats {
+        self.context.stats()
+    }
+
+    /// Read data from the provided absolute file offset into the buffer, blocking until the
+    /// request has completed. The buffer also determines the number of bytes to be read.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    pub fn read<ReadWriteHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadWriteHandle,
+    ) -> Result<ReadWriteHandle, AioError<ReadWriteHandle>>
+        where
+            ReadWriteHandle: convert::AsMut<[u8]>,
+    {
+        self.context.read(fd, offset, buffer).wait()
+    }
+
+    /// Write data from the buffer to the provided absolute file offset, blocking until the
+    /// request has completed. The buffer also determines the number of bytes to be written.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    pub fn write<ReadOnlyHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+    ) -> Result<ReadOnlyHandle, AioError<ReadOnlyHandle>>
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]>,
+    {
+        self.write_sync(fd, offset, buffer, SyncLevel::None)
+    }
+
+    /// Write data from the buffer to the provided absolute file offset, applying the given
+    /// synchronization level and blocking until the request has completed.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - sync_level: A synchronization level to apply for this write operation
+    pub fn write_sync<ReadOnlyHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+        sync_level: SyncLevel,
+    ) -> Result<ReadOnlyHandle, AioError<ReadOnlyHandle>>
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]>,
+    {
+        self.context.write_sync(fd, offset, buffer, sync_level).wait()
+    }
+
+    /// Sync the given file, blocking until the request has completed. The same caveat as for
+    /// `AioContext::sync` applies.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to sync
+    pub fn sync(&self, fd: RawFd) -> Result<(), io::Error> {
+        self.context.sync(fd).wait()
+    }
+
+    /// Sync the data of the given file, blocking until the request has completed. The same
+    /// caveat as for `AioContext::data_sync` applies.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to sync
+    pub fn data_sync(&self, fd: RawFd) -> Result<(), io::Error> {
+        self.context.data_sync(fd).wait()
+    }
+
+    /// Issue a group of requests, which are in flight at the same time as far as submission slots
+    /// are available. Requests holding a slot are submitted before the call returns, unless they
+    /// wait for a batch to go out; the others are submitted as slots free up while waiting for
+    /// the group via `wait_all`.
+    ///
+    /// # Params:
+    /// - requests: The requests of the group
+    pub fn submit_many<Handle, I>(&self, requests: I) -> PendingRequests<Handle>
+        where
+            Handle: convert::AsRef<[u8]> + convert::AsMut<[u8]>,
+            I: IntoIterator<Item = BlockingRequest<Handle>>,
+    {
+        let pending: Vec<_> = requests
+            .into_iter()
+            .map(|request| match request {
+                BlockingRequest::Read { fd, offset, buffer } => Pending::Read(self.context.read(fd, offset, buffer)),
+                BlockingRequest::Write {
+                    fd,
+                    offset,
+                    buffer,
+                    sync_level,
+                } => Pending::Write(self.context.write_sync(fd, offset, buffer, sync_level)),
+            })
+            .collect();
+
+        let mut requests = executor::spawn(future::join_all(pending));
+
+        match requests.poll_future_notify(&sync::Arc::new(NoopNotify), 0) {
+            Ok(futures::Async::Ready(results)) => PendingRequests {
+                requests: None,
+                results: Some(results),
+            },
+            Ok(futures::Async::NotReady) => PendingRequests {
+                requests: Some(requests),
+                results: None,
+            },
+            Err(()) => unreachable!(),
+        }
+    }
+}
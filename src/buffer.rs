@@ -0,0 +1,508 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::alloc;
+use std::convert;
+use std::io;
+use std::slice;
+
+#[cfg(feature = "bytes")]
+use std::cmp;
+
+#[cfg(feature = "bytes")]
+use std::mem;
+
+#[cfg(feature = "bytes")]
+use std::ptr;
+
+#[cfg(feature = "bytes")]
+use bytes::{Bytes, BytesMut};
+
+#[cfg(feature = "bytes")]
+use futures;
+
+#[cfg(feature = "bytes")]
+use {AioError, AioReadResultFuture, AioWriteResultFuture};
+
+/// Default alignment for direct I/O transfers; this matches the logical block size of most
+/// block devices.
+pub const DEFAULT_ALIGNMENT: usize = 512;
+
+// -----------------------------------------------------------------------------------------------
+// Aligned heap memory
+// -----------------------------------------------------------------------------------------------
+
+/// A heap-allocated, zero-initialized buffer whose start address is aligned to a given
+/// power of two. This is the simplest memory handle that satisfies the requirements of `O_DIRECT`
+/// transfers, and it is what the library uses internally as bounce buffer.
+pub struct AlignedBuffer {
+    // start of the allocation
+    ptr: *mut u8,
+
+    // the layout used for allocation; needed to release the memory again
+    layout: alloc::Layout,
+
+    // number of bytes exposed through the slice accessors
+    len: usize,
+}
+
+// The buffer exclusively owns its allocation
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a new buffer of `len` bytes, starting at an address that is a multiple of `align`.
+    ///
+    /// # Params
+    /// - len: Number of bytes in the buffer
+    /// - align: Required alignment; needs to be a power of two
+    pub fn new(len: usize, align: usize) -> Result<AlignedBuffer, io::Error> {
+        // zero-sized allocations are not permitted, so we always reserve at least one byte
+        let layout = alloc::Layout::from_size_align(len.max(1), align)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        Ok(AlignedBuffer { ptr, layout, len })
+    }
+
+    /// Allocate a new aligned buffer holding a copy of the provided data.
+    pub fn copy_from(data: &[u8], align: usize) -> Result<AlignedBuffer, io::Error> {
+        let mut result = AlignedBuffer::new(data.len(), align)?;
+        result.as_mut().copy_from_slice(data);
+        Ok(result)
+    }
+
+    /// The number of bytes in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is this buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+impl convert::AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl convert::AsMut<[u8]> for AlignedBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+// Does the memory region satisfy the alignment requirements for direct I/O? The alignment needs
+// to be a power of two.
+#[cfg(feature = "bytes")]
+pub(crate) fn is_aligned(data: &[u8], align: usize) -> bool {
+    (data.as_ptr() as usize | data.len()) & (align - 1) == 0
+}
+
+// Check that the alignment of a policy is usable
+#[cfg(feature = "bytes")]
+fn check_alignment(align: usize) -> Result<(), io::Error> {
+    if align.is_power_of_two() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("alignment of {} bytes is not a power of two", align),
+        ))
+    }
+}
+
+// The error reported for buffers that violate the alignment requirements
+#[cfg(feature = "bytes")]
+pub(crate) fn misaligned(align: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("buffer address and length need to be aligned to {} bytes", align),
+    )
+}
+
+// -----------------------------------------------------------------------------------------------
+// Integration with the bytes crate
+// -----------------------------------------------------------------------------------------------
+
+/// How to deal with `Bytes` and `BytesMut` buffers that do not satisfy the alignment required
+/// for direct I/O.
+#[cfg(feature = "bytes")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlignmentPolicy {
+    /// Fail the request with an `InvalidInput` error if the memory is not aligned to the
+    /// given number of bytes.
+    Require(usize),
+
+    /// Transfer through a temporary aligned buffer if the memory is not aligned to the given
+    /// number of bytes. Aligned memory is still transferred without copying. Reads of a length
+    /// that is not a multiple of the alignment are rounded up, with the excess discarded; such
+    /// writes fail with an `InvalidInput` error, as they would extend beyond the data.
+    Bounce(usize),
+}
+
+#[cfg(feature = "bytes")]
+impl AlignmentPolicy {
+    /// The alignment requirement expressed by this policy
+    pub fn alignment(&self) -> usize {
+        match *self {
+            AlignmentPolicy::Require(align) | AlignmentPolicy::Bounce(align) => align,
+        }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Default for AlignmentPolicy {
+    fn default() -> AlignmentPolicy {
+        AlignmentPolicy::Require(DEFAULT_ALIGNMENT)
+    }
+}
+
+/// Allocate a `BytesMut` of `len` zero bytes whose memory starts at a multiple of `align`.
+///
+/// The buffer is carved out of a slightly larger allocation, so it can be frozen and passed on
+/// without copying, and the resulting `Bytes` remains aligned. The allocation is always made on
+/// the heap, even for buffers small enough to be stored inline, which would move along with the
+/// value.
+#[cfg(feature = "bytes")]
+pub fn aligned_bytes_mut(len: usize, align: usize) -> BytesMut {
+    assert!(align.is_power_of_two());
+
+    // `BytesMut` stores up to four words less one byte inline
+    let capacity = cmp::max(len + align, 4 * mem::size_of::<usize>());
+    let mut buffer = BytesMut::with_capacity(capacity);
+    buffer.resize(capacity, 0);
+
+    let addr = buffer.as_ptr() as usize;
+    let padding = (align - addr % align) % align;
+
+    buffer.advance(padding);
+    buffer.truncate(len);
+    buffer
+}
+
+// Memory handle used to write a `Bytes` value
+#[cfg(feature = "bytes")]
+pub(crate) enum BytesHandle {
+    // aligned memory that is handed to the kernel as is
+    Direct(Bytes),
+
+    // unaligned memory that has been copied into a bounce buffer
+    Bounced(Bytes, AlignedBuffer),
+}
+
+#[cfg(feature = "bytes")]
+impl BytesHandle {
+    pub(crate) fn new(data: Bytes, policy: AlignmentPolicy) -> Result<BytesHandle, (Bytes, io::Error)> {
+        if let Err(err) = check_alignment(policy.alignment()) {
+            return Err((data, err));
+        }
+
+        if is_aligned(&data, policy.alignment()) {
+            return Ok(BytesHandle::Direct(data));
+        }
+
+        match policy {
+            AlignmentPolicy::Require(align) => Err((data, misaligned(align))),
+
+            // the bounce buffer fixes the address, but padding the length would write past the data
+            AlignmentPolicy::Bounce(align) if data.len() & (align - 1) != 0 => {
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("length of a write needs to be a multiple of {} bytes", align),
+                );
+
+                Err((data, err))
+            }
+
+            AlignmentPolicy::Bounce(align) => match AlignedBuffer::copy_from(&data, align) {
+                Ok(bounce) => Ok(BytesHandle::Bounced(data, bounce)),
+                Err(err) => Err((data, err)),
+            },
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> Bytes {
+        match self {
+            BytesHandle::Direct(data) | BytesHandle::Bounced(data, _) => data,
+        }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl convert::AsRef<[u8]> for BytesHandle {
+    fn as_ref(&self) -> &[u8] {
+        match *self {
+            BytesHandle::Direct(ref data) => data.as_ref(),
+            BytesHandle::Bounced(_, ref bounce) => bounce.as_ref(),
+        }
+    }
+}
+
+// Memory handle used to read into a `BytesMut` value
+#[cfg(feature = "bytes")]
+pub(crate) enum BytesMutHandle {
+    // aligned memory that is handed to the kernel as is
+    Direct(BytesMut),
+
+    // unaligned memory; data is read into the bounce buffer, which may be longer, and copied
+    // upon completion
+    Bounced(BytesMut, AlignedBuffer),
+}
+
+#[cfg(feature = "bytes")]
+impl BytesMutHandle {
+    pub(crate) fn new(
+        data: BytesMut,
+        policy: AlignmentPolicy,
+    ) -> Result<BytesMutHandle, (BytesMut, io::Error)> {
+        if let Err(err) = check_alignment(policy.alignment()) {
+            return Err((data, err));
+        }
+
+        if is_aligned(&data, policy.alignment()) {
+            return Ok(BytesMutHandle::Direct(data));
+        }
+
+        match policy {
+            AlignmentPolicy::Require(align) => Err((data, misaligned(align))),
+
+            // the read is rounded up to whole blocks, of which only the requested part is kept
+            AlignmentPolicy::Bounce(align) => match AlignedBuffer::new((data.len() + align - 1) & !(align - 1), align) {
+                Ok(bounce) => Ok(BytesMutHandle::Bounced(data, bounce)),
+                Err(err) => Err((data, err)),
+            },
+        }
+    }
+
+    // Retrieve the buffer; `completed` indicates whether the transfer was successful and hence
+    // whether the content of a bounce buffer needs to be copied back
+    pub(crate) fn into_inner(self, completed: bool) -> BytesMut {
+        match self {
+            BytesMutHandle::Direct(data) => data,
+            BytesMutHandle::Bounced(mut data, bounce) => {
+                if completed {
+                    unsafe {
+                        ptr::copy_nonoverlapping(
+                            bounce.as_ref().as_ptr(),
+                            data.as_mut().as_mut_ptr(),
+                            data.len(),
+                        );
+                    }
+                }
+
+                data
+            }
+        }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl convert::AsMut<[u8]> for BytesMutHandle {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match *self {
+            BytesMutHandle::Direct(ref mut data) => data.as_mut(),
+            BytesMutHandle::Bounced(_, ref mut bounce) => bounce.as_mut(),
+        }
+    }
+}
+
+/// Future returned as result of submitting a write request via `AioContext::write_bytes`.
+#[cfg(feature = "bytes")]
+pub struct AioWriteBytesFuture {
+    // the actual request, or the error determined when validating the buffer
+    state: Result<AioWriteResultFuture<BytesHandle>, Option<AioError<Bytes>>>,
+}
+
+#[cfg(feature = "bytes")]
+impl AioWriteBytesFuture {
+    pub(crate) fn new(
+        state: Result<AioWriteResultFuture<BytesHandle>, AioError<Bytes>>,
+    ) -> AioWriteBytesFuture {
+        AioWriteBytesFuture {
+            state: state.map_err(Some),
+        }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl AioWriteBytesFuture {
+    request_options!(|this| this.state.as_mut().ok().map(|future| &mut future.base));
+}
+
+#[cfg(feature = "bytes")]
+impl futures::Future for AioWriteBytesFuture {
+    type Item = Bytes;
+    type Error = AioError<Bytes>;
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        match self.state {
+            Err(ref mut err) => Err(err.take().unwrap()),
+            Ok(ref mut future) => future
+                .poll()
+                .map(|val| val.map(BytesHandle::into_inner))
+                .map_err(|err| AioError {
+                    buffer: err.buffer.into_inner(),
+                    error: err.error,
+                }),
+        }
+    }
+}
+
+/// Future returned as result of submitting a read request via `AioContext::read_bytes`.
+#[cfg(feature = "bytes")]
+pub struct AioReadBytesFuture {
+    // the actual request, or the error determined when validating the buffer
+    state: Result<AioReadResultFuture<BytesMutHandle>, Option<AioError<BytesMut>>>,
+}
+
+#[cfg(feature = "bytes")]
+impl AioReadBytesFuture {
+    pub(crate) fn new(
+        state: Result<AioReadResultFuture<BytesMutHandle>, AioError<BytesMut>>,
+    ) -> AioReadBytesFuture {
+        AioReadBytesFuture {
+            state: state.map_err(Some),
+        }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl AioReadBytesFuture {
+    request_options!(|this| this.state.as_mut().ok().map(|future| &mut future.base));
+}
+
+#[cfg(feature = "bytes")]
+impl futures::Future for AioReadBytesFuture {
+    type Item = BytesMut;
+    type Error = AioError<BytesMut>;
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        match self.state {
+            Err(ref mut err) => Err(err.take().unwrap()),
+            Ok(ref mut future) => future
+                .poll()
+                .map(|val| val.map(|handle| handle.into_inner(true)))
+                .map_err(|err| AioError {
+                    buffer: err.buffer.into_inner(false),
+                    error: err.error,
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_buffer_alignment() {
+        let mut buffer = AlignedBuffer::new(8192, 4096).unwrap();
+
+        assert!(buffer.len() == 8192);
+        assert!(buffer.as_ref().as_ptr() as usize & 4095 == 0);
+        assert!(buffer.as_mut().iter().all(|byte| *byte == 0));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn aligned_bytes_mut_freeze() {
+        let buffer = aligned_bytes_mut(4096, 512);
+        assert!(buffer.len() == 4096);
+        assert!(is_aligned(&buffer, 512));
+
+        let frozen = buffer.freeze();
+        assert!(is_aligned(&frozen, 512));
+
+        // small buffers stay aligned when moved
+        let buffer = Box::new(aligned_bytes_mut(8, 8));
+        assert!(is_aligned(&buffer, 8));
+        assert!(is_aligned(&buffer.freeze(), 8));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_handle_policy() {
+        let data = Bytes::from(vec![7u8; 1024 + 1]).slice_from(1);
+        assert!(!is_aligned(&data, 512));
+
+        match BytesHandle::new(data.clone(), AlignmentPolicy::Require(512)) {
+            Err((returned, err)) => {
+                assert!(returned == data);
+                assert!(err.kind() == io::ErrorKind::InvalidInput);
+            }
+            Ok(_) => panic!("misaligned buffer accepted"),
+        }
+
+        match BytesHandle::new(data.clone(), AlignmentPolicy::Bounce(512)) {
+            Ok(handle) => {
+                assert!(handle.as_ref() == &data[..]);
+                assert!(handle.into_inner() == data);
+            }
+            Err(_) => panic!("bounce buffer not created"),
+        }
+
+        // a write cannot be padded, and an alignment needs to be a power of two
+        for &policy in [AlignmentPolicy::Bounce(512), AlignmentPolicy::Bounce(0), AlignmentPolicy::Require(0)].iter() {
+            match BytesHandle::new(Bytes::from(vec![7u8; 1000]), policy) {
+                Err((_, err)) => assert!(err.kind() == io::ErrorKind::InvalidInput),
+                Ok(_) => panic!("unusable policy accepted"),
+            }
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_mut_handle_rounds_up() {
+        let mut data = BytesMut::from(vec![0u8; 1000 + 1]);
+        data.advance(1);
+
+        let mut handle = match BytesMutHandle::new(data, AlignmentPolicy::Bounce(512)) {
+            Ok(handle) => handle,
+            Err(_) => panic!("bounce buffer not created"),
+        };
+
+        // the kernel transfers whole blocks, of which the caller gets the requested part
+        assert!(handle.as_mut().len() == 1024);
+
+        for (index, byte) in handle.as_mut().iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        let data = handle.into_inner(true);
+        assert!(data.len() == 1000);
+        assert!(data.iter().enumerate().all(|(index, byte)| *byte == index as u8));
+    }
+}
@@ -0,0 +1,193 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::convert;
+use std::io;
+
+use std::os::unix::io::RawFd;
+
+use futures;
+use libc;
+
+use slab::Callback;
+use {AioBaseFuture, AioContext, AioError, AioReadResultFuture, AioWriteResultFuture, SyncLevel};
+
+/// Future returned as result of issuing a request via `AioContext::read_with` or
+/// `AioContext::write_with`.
+///
+/// The future only takes care of submitting the request: it completes once the request has been
+/// handed to the kernel, or once it has failed before that. The result of the request is passed
+/// to the callback, which runs on the thread retrieving the completion. The callback is called
+/// exactly once; if the future is dropped before the request has secured a submission slot, it
+/// receives an `ECANCELED` error. A request waiting in a batch when the future is dropped is
+/// submitted along with the batch, and its callback receives the result of the kernel.
+pub struct AioCallbackFuture {
+    // common AIO future state, which holds the callback until submission
+    base: AioBaseFuture,
+}
+
+impl AioCallbackFuture {
+    request_options!(|this| Some(&mut this.base));
+}
+
+impl futures::Future for AioCallbackFuture {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        let base = &mut self.base;
+
+        // the executor has run the other ready tasks since our request was queued, so the
+        // batch goes out now
+        if base.queued {
+            base.queued = false;
+            base.context.submitter.flush();
+        }
+
+        match base.submit_request() {
+            Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+
+            // stay around until the batch holding the request has gone out
+            Ok(futures::Async::Ready(())) if base.queued => Ok(futures::Async::NotReady),
+
+            // the slab entry reports the completion to the callback from here on
+            Ok(futures::Async::Ready(())) => {
+                base.ticket = None;
+                Ok(futures::Async::Ready(()))
+            }
+
+            // a request refused by the kernel has been reported to the callback already
+            Err(err) => {
+                if let Some(callback) = base.callback.take() {
+                    callback.call(Err(err));
+                }
+
+                Ok(futures::Async::Ready(()))
+            }
+        }
+    }
+}
+
+impl Drop for AioCallbackFuture {
+    fn drop(&mut self) {
+        // once the request has claimed its slab entry, the callback lives there, and the base
+        // flushes the batch holding the request, if any
+        if let Some(callback) = self.base.callback.take() {
+            callback.call(Err(io::Error::from_raw_os_error(libc::ECANCELED)));
+        }
+    }
+}
+
+impl AioContext {
+    /// Initiate an asynchronous read operation into the buffer, whose result is passed to the
+    /// callback rather than returned by a future. The buffer also determines the number of bytes
+    /// to be read. The returned future needs to be polled for the request to be submitted; the
+    /// callback is run by whoever retrieves the completion, such as the background polling task
+    /// of the context, and should not block.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    /// - callback: Receives the buffer along with the number of bytes read, or the error
+    pub fn read_with<ReadWriteHandle, F>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadWriteHandle,
+        callback: F,
+    ) -> AioCallbackFuture
+        where
+            ReadWriteHandle: convert::AsMut<[u8]> + Send + 'static,
+            F: FnOnce(Result<(ReadWriteHandle, usize), AioError<ReadWriteHandle>>) + Send + 'static,
+    {
+        let AioReadResultFuture { base, buffer } = self.read(fd, offset, buffer);
+        AioCallbackFuture::new(base, buffer.unwrap(), callback)
+    }
+
+    /// Initiate an asynchronous write operation from the buffer, whose result is passed to the
+    /// callback rather than returned by a future. The buffer also determines the number of bytes
+    /// to be written. The returned future needs to be polled for the request to be submitted; the
+    /// callback is run by whoever retrieves the completion, such as the background polling task
+    /// of the context, and should not block.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - callback: Receives the buffer along with the number of bytes written, or the error
+    pub fn write_with<ReadOnlyHandle, F>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+        callback: F,
+    ) -> AioCallbackFuture
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]> + Send + 'static,
+            F: FnOnce(Result<(ReadOnlyHandle, usize), AioError<ReadOnlyHandle>>) + Send + 'static,
+    {
+        self.write_sync_with(fd, offset, buffer, SyncLevel::None, callback)
+    }
+
+    /// Initiate an asynchronous write operation from the buffer, applying the given
+    /// synchronization level, whose result is passed to the callback rather than returned by a
+    /// future. Otherwise, the request behaves like one issued via `write_with`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - sync_level: A synchronization level to apply for this write operation
+    /// - callback: Receives the buffer along with the number of bytes written, or the error
+    pub fn write_sync_with<ReadOnlyHandle, F>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+        sync_level: SyncLevel,
+        callback: F,
+    ) -> AioCallbackFuture
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]> + Send + 'static,
+            F: FnOnce(Result<(ReadOnlyHandle, usize), AioError<ReadOnlyHandle>>) + Send + 'static,
+    {
+        let AioWriteResultFuture { base, buffer } = self.write_sync(fd, offset, buffer, sync_level);
+        AioCallbackFuture::new(base, buffer.unwrap(), callback)
+    }
+}
+
+impl AioCallbackFuture {
+    // Attach the callback to a request, handing the buffer back along with the result
+    fn new<Handle, F>(mut base: AioBaseFuture, buffer: Handle, callback: F) -> AioCallbackFuture
+        where
+            Handle: Send + 'static,
+            F: FnOnce(Result<(Handle, usize), AioError<Handle>>) + Send + 'static,
+    {
+        base.callback = Some(Callback::new(move |result: io::Result<usize>| match result {
+            Ok(len) => callback(Ok((buffer, len))),
+            Err(error) => callback(Err(AioError { buffer, error })),
+        }));
+
+        AioCallbackFuture { base }
+    }
+}
@@ -0,0 +1,239 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::io;
+use std::sync;
+use std::time;
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+use mio;
+
+use eventfd::EventFdInner;
+use raw::{CompletionQueue, RawCompletion, SignalSet};
+use AioContextInner;
+
+/// A completed request that carries a token, as returned by `CompletionDriver::reap`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    /// The token set via the `token` method of the request future
+    pub token: u64,
+
+    /// The result reported by the kernel: the number of bytes transferred, or a negated `errno`
+    /// value
+    pub result: i64,
+}
+
+/// Retrieves the completions of a context built via `AioContextBuilder::build_driven`, for
+/// callers that run an event loop of their own rather than an executor.
+///
+/// The kernel signals completions through an eventfd, which is returned by `fd` and becomes
+/// readable once completions are waiting. The caller adds it to its own `epoll` set and calls
+/// `process_completions` whenever it is readable. Request futures waiting for the completions
+/// are woken up as usual. Alternatively, the driver can be registered with a `mio::Poll`, and
+/// `reap` reports the completed requests by their tokens.
+///
+/// Dropping the driver closes the context to new requests, which fail with `ESHUTDOWN`, and
+/// blocks the calling thread until the kernel has completed the requests still in flight. When
+/// the driver is dropped on the thread running the caller's event loop, that loop stalls for as
+/// long as those requests take.
+pub struct CompletionDriver {
+    // the context whose completions are retrieved
+    inner: sync::Arc<AioContextInner>,
+
+    // the queue for retrieving completions from the kernel
+    completions: CompletionQueue,
+
+    // the eventfd on which the kernel signals completions
+    eventfd: EventFdInner,
+
+    // a buffer to retrieve completion status from the kernel
+    events: Vec<RawCompletion>,
+}
+
+impl fmt::Debug for CompletionDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "CompletionDriver {{ fd: {} }}", self.eventfd.fd)
+    }
+}
+
+impl CompletionDriver {
+    pub(crate) fn new(inner: sync::Arc<AioContextInner>, completions: CompletionQueue, eventfd: EventFdInner) -> CompletionDriver {
+        let events = Vec::with_capacity(inner.nr);
+
+        CompletionDriver {
+            inner,
+            completions,
+            eventfd,
+            events,
+        }
+    }
+
+    /// The eventfd through which the kernel signals completions; it is readable while
+    /// completions are waiting to be processed.
+    pub fn fd(&self) -> RawFd {
+        self.eventfd.fd
+    }
+
+    /// Retrieve up to `max` completions without blocking, and hand them to the requests waiting
+    /// for them. Returns the number of completions processed. If that number equals `max`, more
+    /// completions may be waiting, and the eventfd stays readable.
+    ///
+    /// # Params
+    /// - max: The maximum number of completions to process
+    pub fn process_completions(&mut self, max: usize) -> io::Result<usize> {
+        self.retrieve(0, max, Some(time::Duration::from_secs(0)), None, |_, _| {})
+    }
+
+    /// Wait until at least `min` completions are available or until the timeout has passed, and
+    /// hand up to `max` of them to the requests waiting for them; without a timeout, the call
+    /// waits indefinitely. Returns the number of completions processed.
+    ///
+    /// If a signal mask is given, it replaces the signal mask of the calling thread while
+    /// waiting, as with `pselect`. A signal unblocked by the mask interrupts the wait with
+    /// `io::ErrorKind::Interrupted`, even if it has arrived before the call, so that a signal
+    /// requesting shutdown is not lost in between two waits. This relies on `io_pgetevents`;
+    /// see `CompletionQueue::reap_masked` for the weaker guarantee on older kernels.
+    ///
+    /// # Params
+    /// - min: The number of completions to wait for
+    /// - max: The maximum number of completions to process
+    /// - timeout: The maximum time to wait
+    /// - sigmask: The signal mask to apply while waiting
+    pub fn wait_completions(
+        &mut self,
+        min: usize,
+        max: usize,
+        timeout: Option<time::Duration>,
+        sigmask: Option<&SignalSet>,
+    ) -> io::Result<usize> {
+        self.retrieve(min.min(max), max, timeout, sigmask, |_, _| {})
+    }
+
+    /// Retrieve all completions that are waiting without blocking, and hand them to the
+    /// requests waiting for them. Returns the tokens and results of the completed requests that
+    /// have been given a token.
+    pub fn reap(&mut self) -> io::Result<Vec<Completion>> {
+        let mut completions = Vec::new();
+        let max = self.events.capacity();
+
+        loop {
+            let reaped = |token, result| completions.push(Completion { token, result });
+            let retrieved = self.retrieve(0, max, Some(time::Duration::from_secs(0)), None, reaped)?;
+
+            if retrieved < max {
+                break;
+            }
+        }
+
+        Ok(completions)
+    }
+
+    // Retrieve between `min` and `max` completions, passing those of requests with a token to
+    // `reaped`
+    fn retrieve<F>(
+        &mut self,
+        min: usize,
+        max: usize,
+        timeout: Option<time::Duration>,
+        sigmask: Option<&SignalSet>,
+        reaped: F,
+    ) -> io::Result<usize>
+        where
+            F: FnMut(u64, i64),
+    {
+        // reset the eventfd prior to retrieving the completions, so that completions arriving
+        // in the meantime signal it anew
+        let mut counter: u64 = 0;
+        let buffer = &mut counter as *mut u64 as *mut libc::c_void;
+        let rc = unsafe { libc::read(self.eventfd.fd, buffer, 8) };
+
+        if rc < 0 {
+            let error = io::Error::last_os_error();
+
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error);
+            }
+        }
+
+        let max = max.min(self.events.capacity());
+        let min = min.min(max);
+
+        self.events.clear();
+        self.completions.retrieve(min, max, timeout, sigmask, &mut self.events)?;
+        self.inner.capacity.complete_all(&self.events, reaped);
+
+        if max > 0 && self.events.len() == max {
+            // completions may be left over; keep the eventfd readable for them
+            let increment: u64 = 1;
+            let buffer = &increment as *const u64 as *const libc::c_void;
+            let rc = unsafe { libc::write(self.eventfd.fd, buffer, 8) };
+
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(self.events.len())
+    }
+}
+
+impl AsRawFd for CompletionDriver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd()
+    }
+}
+
+impl mio::Evented for CompletionDriver {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        self.eventfd.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        self.eventfd.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        self.eventfd.deregister(poll)
+    }
+}
+
+impl Drop for CompletionDriver {
+    fn drop(&mut self) {
+        // nobody retrieves completions from here on
+        self.inner.submitter.drain(&mut self.completions);
+    }
+}
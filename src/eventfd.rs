@@ -44,6 +44,25 @@ pub struct EventFdInner {
     pub fd: RawFd,
 }
 
+impl EventFdInner {
+    // Create a non-blocking eventfd that is not associated with any reactor
+    pub fn create(init: usize, semaphore: bool) -> Result<EventFdInner, io::Error> {
+        let flags = if semaphore {
+            O_CLOEXEC | EFD_NONBLOCK as i32 | EFD_SEMAPHORE as i32
+        } else {
+            O_CLOEXEC | EFD_NONBLOCK as i32
+        };
+
+        let fd = unsafe { eventfd(init as c_uint, flags) };
+
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(EventFdInner { fd })
+        }
+    }
+}
+
 impl Drop for EventFdInner {
     fn drop(&mut self) {
         if self.fd >= 0 {
@@ -84,20 +103,18 @@ pub struct EventFd {
 
 impl EventFd {
     pub fn create(init: usize, semaphore: bool) -> Result<EventFd, io::Error> {
-        let flags = if semaphore {
-            O_CLOEXEC | EFD_NONBLOCK as i32 | EFD_SEMAPHORE as i32
-        } else {
-            O_CLOEXEC | EFD_NONBLOCK as i32
-        };
-
-        let fd = unsafe { eventfd(init as c_uint, flags) };
+        EventFd::create_with_reactor(init, semaphore, &reactor::Handle::default())
+    }
 
-        if fd < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            reactor::PollEvented::new(EventFdInner { fd }, &reactor::Handle::default())
-                .map(|evented| EventFd { evented })
-        }
+    // Create an eventfd that is registered with the given reactor; the default handle binds
+    // lazily to the reactor of the task that first polls the eventfd
+    pub fn create_with_reactor(
+        init: usize,
+        semaphore: bool,
+        handle: &reactor::Handle,
+    ) -> Result<EventFd, io::Error> {
+        let inner = EventFdInner::create(init, semaphore)?;
+        reactor::PollEvented::new(inner, handle).map(|evented| EventFd { evented })
     }
 
     pub fn read(&mut self) -> Result<futures::Async<u64>, io::Error> {
@@ -0,0 +1,239 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::io;
+use std::sync;
+use std::time;
+
+use std::os::unix::io::RawFd;
+
+use futures;
+use parking_lot;
+use tokio::reactor;
+
+use eventfd;
+use raw::{CompletionQueue, RawCompletion};
+use {AioContext, AioContextBuilder, AioContextInner};
+
+// the contexts of a group, which are not kept alive by the group
+type Members = sync::Arc<parking_lot::Mutex<Vec<sync::Weak<AioContextInner>>>>;
+
+// The state shared by the handles of a group and its member contexts, which keeps the eventfd
+// and the background task around for as long as any of them exists
+struct GroupInner {
+    // the fd of the eventfd owned by the background task, which the members pass to the kernel
+    fd: RawFd,
+
+    members: Members,
+
+    // handle for the spawned background task; dropping it will cancel the task
+    _poll_task_handle: futures::sync::oneshot::SpawnHandle<(), io::Error>,
+}
+
+/// A completion eventfd and background polling task shared by several contexts, each with a
+/// kernel context of its own. Contexts created via `AioContextBuilder::build_in_group` have the
+/// kernel signal their completions through the eventfd of the group, and the task of the group
+/// retrieves the completions of each member in turn. This saves a reactor registration and a
+/// task per context, for instance with one context per disk on a machine with many disks.
+///
+/// The eventfd and the task stay around for as long as the group or any of its members exists.
+#[derive(Clone)]
+pub struct CompletionGroup {
+    inner: sync::Arc<GroupInner>,
+}
+
+impl fmt::Debug for CompletionGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "CompletionGroup {{ fd: {}, contexts: {} }}", self.inner.fd, self.contexts())
+    }
+}
+
+impl CompletionGroup {
+    /// Create a group whose background polling task is spawned on the given executor.
+    ///
+    /// # Params
+    /// - executor: The executor used to spawn the background polling task
+    pub fn new<E>(executor: &E) -> Result<CompletionGroup, io::Error>
+        where
+            E: futures::future::Executor<futures::sync::oneshot::Execute<GroupPollFuture>>,
+    {
+        CompletionGroup::spawn(eventfd::EventFd::create(0, false)?, executor)
+    }
+
+    /// Create a group whose eventfd is registered with the given reactor, as for contexts built
+    /// via `AioContextBuilder::reactor`.
+    ///
+    /// # Params
+    /// - executor: The executor used to spawn the background polling task
+    /// - handle: The reactor that drives the completion eventfd
+    pub fn with_reactor<E>(executor: &E, handle: &reactor::Handle) -> Result<CompletionGroup, io::Error>
+        where
+            E: futures::future::Executor<futures::sync::oneshot::Execute<GroupPollFuture>>,
+    {
+        CompletionGroup::spawn(eventfd::EventFd::create_with_reactor(0, false, handle)?, executor)
+    }
+
+    fn spawn<E>(eventfd: eventfd::EventFd, executor: &E) -> Result<CompletionGroup, io::Error>
+        where
+            E: futures::future::Executor<futures::sync::oneshot::Execute<GroupPollFuture>>,
+    {
+        let fd = eventfd.evented.get_ref().fd;
+        let members: Members = Default::default();
+
+        let poll_future = GroupPollFuture {
+            eventfd,
+            members: members.clone(),
+            events: Vec::new(),
+        };
+
+        Ok(CompletionGroup {
+            inner: sync::Arc::new(GroupInner {
+                fd,
+                members,
+                _poll_task_handle: futures::sync::oneshot::spawn(poll_future, executor),
+            }),
+        })
+    }
+
+    /// The number of contexts in the group that are still alive. A context dropped while the
+    /// task of the group retrieves its completions stays alive until the task is done with it.
+    pub fn contexts(&self) -> usize {
+        let members = self.inner.members.lock();
+        members.iter().filter(|member| member.upgrade().is_some()).count()
+    }
+}
+
+impl AioContextBuilder {
+    /// Create an AioContext whose completions are signalled through the eventfd of the group,
+    /// and retrieved by the background task of the group along with those of the other members.
+    ///
+    /// # Params
+    /// - group: The group to join
+    pub fn build_in_group(&self, group: &CompletionGroup) -> Result<AioContext, io::Error> {
+        let (mut inner, completions) = AioContextInner::new(Some(group.inner.fd), self)?;
+
+        inner.group = Some(GroupMembership {
+            group: group.inner.clone(),
+            completions: parking_lot::Mutex::new(completions),
+        });
+
+        let inner = sync::Arc::new(inner);
+        let mut members = group.inner.members.lock();
+
+        members.retain(|member| member.upgrade().is_some());
+        members.push(sync::Arc::downgrade(&inner));
+
+        Ok(AioContext { inner })
+    }
+}
+
+// Keeps the group of a context alive, and holds the queue through which the task of the group
+// retrieves the completions of the context
+pub(crate) struct GroupMembership {
+    group: sync::Arc<GroupInner>,
+
+    // only used by the task of the group, and once it ends
+    completions: parking_lot::Mutex<CompletionQueue>,
+}
+
+impl fmt::Debug for GroupMembership {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "GroupMembership {{ fd: {} }}", self.group.fd)
+    }
+}
+
+// A future spawned as background task of a group, which retrieves the completions of the member
+// contexts whenever the shared eventfd signals any of them.
+pub struct GroupPollFuture {
+    // the eventfd on which the kernel will notify I/O completions of every member
+    eventfd: eventfd::EventFd,
+
+    members: Members,
+
+    // a buffer to retrieve completion status from the kernel
+    events: Vec<RawCompletion>,
+}
+
+impl GroupPollFuture {
+    // Retrieve the completions of every member without waiting. The counter of the eventfd
+    // cannot be attributed to the members, so each one is drained until the kernel reports
+    // fewer completions than fit into the buffer.
+    fn drain(&mut self) -> Result<(), io::Error> {
+        // handing out completions may run request callbacks, which may create members; the lock
+        // is not held while doing so
+        let members: Vec<_> = {
+            let mut members = self.members.lock();
+            members.retain(|member| member.upgrade().is_some());
+            members.iter().filter_map(|member| member.upgrade()).collect()
+        };
+
+        for member in members {
+            let mut completions = member.group.as_ref().unwrap().completions.lock();
+            let max = member.nr;
+
+            loop {
+                let zero = Some(time::Duration::from_secs(0));
+                self.events.clear();
+                let count = completions.retrieve(0, max, zero, None, &mut self.events)?;
+
+                // dispatch the retrieved events to the associated futures
+                member.capacity.complete_all(&self.events, |_, _| {});
+
+                if count < max {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl futures::Future for GroupPollFuture {
+    type Item = ();
+    type Error = io::Error;
+
+    // This poll function will never return completion
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        loop {
+            // check the eventfd for completed I/O operations of any member
+            match self.eventfd.read() {
+                Err(err) => return Err(err),
+                Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+                Ok(futures::Async::Ready(_)) => self.drain()?,
+            }
+        }
+    }
+}
+
+impl Drop for GroupPollFuture {
+    fn drop(&mut self) {
+        // nobody retrieves completions of the members from here on; drain without the lock held
+        let members: Vec<_> = self.members.lock().iter().filter_map(|member| member.upgrade()).collect();
+
+        for member in members {
+            let mut completions = member.group.as_ref().unwrap().completions.lock();
+            member.submitter.drain(&mut completions);
+        }
+    }
+}
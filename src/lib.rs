@@ -59,6 +59,8 @@
 //! such a lock once the operation has completed.
 
 extern crate aio_bindings;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 extern crate fnv;
 extern crate futures;
 extern crate futures_cpupool;
@@ -69,172 +71,334 @@ extern crate parking_lot;
 extern crate rand;
 extern crate tokio;
 
+use std::cmp;
 use std::convert;
 use std::error;
 use std::fmt;
 use std::io;
-use std::mem;
 use std::ops;
-use std::ptr;
+use std::time;
 
 use std::os::unix::io::RawFd;
+use std::sync::atomic;
 
-use libc::{c_long, c_void, mlock};
+use libc::{c_void, mlock};
 
 use futures::Future;
 use ops::Deref;
 
-// local modules
-mod aio;
-mod eventfd;
-mod sync;
+use tokio::reactor;
 
-// -----------------------------------------------------------------------------------------------
-// Bindings for Linux AIO start here
-// -----------------------------------------------------------------------------------------------
+// Setters for the scheduling options of a request, shared by the futures returned when issuing
+// requests. The closure-like argument yields the `AioBaseFuture` of the request, if there is one;
+// the `token` form also generates the setter for the completion token.
+macro_rules! request_options {
+    (|$this:ident| $base:expr) => {
+        /// Set the priority used to admit the request when all submission slots are taken.
+        pub fn priority(mut self, priority: $crate::Priority) -> Self {
+            {
+                let $this = &mut self;
+                if let Some(base) = $base {
+                    base.sched_info.priority = priority;
+                }
+            }
+
+            self
+        }
 
-// field values that we need to transfer into a kernel IOCB
-struct IocbInfo {
-    // the I/O opcode
-    opcode: u32,
+        /// Set the tenant on whose behalf the request is issued, for fair queuing between tenants.
+        pub fn tenant(mut self, tenant: u32) -> Self {
+            {
+                let $this = &mut self;
+                if let Some(base) = $base {
+                    base.sched_info.tenant = tenant;
+                }
+            }
 
-    // file fd identifying the file to operate on
-    fd: RawFd,
+            self
+        }
 
-    // an absolute file offset, if applicable for the command
-    offset: u64,
+        /// Set the point in time by which the request needs to be submitted. A request that misses
+        /// its deadline fails with `io::ErrorKind::TimedOut` without being submitted. Under deadline
+        /// scheduling, waiting requests are admitted in order of their deadlines.
+        pub fn deadline(mut self, deadline: ::std::time::Instant) -> Self {
+            {
+                let $this = &mut self;
+                if let Some(base) = $base {
+                    base.sched_info.deadline = Some(deadline);
+                }
+            }
 
-    // the base address of the transfer buffer, if applicable
-    buf: u64,
+            self
+        }
+    };
 
-    // the number of bytes to be transferred, if applicable
-    len: u64,
+    (token, |$this:ident| $base:expr) => {
+        request_options!(|$this| $base);
 
-    // flags to provide additional parameters 
-    flags: u32,
-}
+        /// Set the token under which `CompletionDriver::reap` reports the completion of the
+        /// request, for contexts whose completions are retrieved by the caller.
+        pub fn token(mut self, token: u64) -> Self {
+            {
+                let $this = &mut self;
+                if let Some(base) = $base {
+                    base.token = Some(token);
+                }
+            }
 
-// Data which is passed to AIO request
-#[derive(Debug)]
-struct RequestData {
-    // We have both sides of a oneshot channel here
-    completed_sender: Option<futures::sync::oneshot::Sender<aio_bindings::__s64>>,
+            self
+        }
+    };
 }
 
-// State information that is associated with an I/O request that is currently in flight.
-#[derive(Debug)]
-struct RequestState {
-    // Linux kernal I/O control block which can be submitted to io_submit
-    request: aio::iocb,
+// local modules
+mod adaptive;
+mod aio;
+mod batch;
+mod blocking;
+mod buffer;
+mod callback;
+mod driver;
+mod eventfd;
+mod group;
+mod rate;
+mod raw;
+mod reaper;
+mod sched;
+mod scope;
+mod shard;
+mod slab;
+pub mod sync;
+mod timer;
+
+pub use adaptive::{AdaptiveConcurrency, ConcurrencyStats, Decision};
+pub use batch::SubmitPolicy;
+pub use blocking::{BlockingAioContext, BlockingRequest, PendingRequests};
+pub use buffer::{AlignedBuffer, DEFAULT_ALIGNMENT};
+pub use callback::AioCallbackFuture;
+pub use driver::{Completion, CompletionDriver};
+pub use group::{CompletionGroup, GroupPollFuture};
+pub use rate::RateLimit;
+pub use raw::{
+    opcode, raw_queues, rwf, CompletionQueue, IocbBuilder, RawCompletion, RejectedRequest, SignalSet,
+    SubmissionQueue, IOCB_FLAG_IOPRIO,
+};
+pub use reaper::ReaperConfig;
+pub use sched::{
+    ClassLimit, ClassStats, LaneStats, OpClass, Priority, PriorityPolicy, TenantConfig, TenantStats,
+    DEFAULT_REQUEST_COST,
+};
+pub use scope::{Scope, ScopedReadFuture, ScopedWriteFuture};
+pub use shard::{ShardRouting, ShardedAioContext};
+
+#[cfg(feature = "bytes")]
+pub use buffer::{aligned_bytes_mut, AioReadBytesFuture, AioWriteBytesFuture, AlignmentPolicy};
 
-    // Concurrency primitive to notify completion to the associated future
-    completed_receiver: futures::sync::oneshot::Receiver<aio_bindings::__s64>,
-}
+// -----------------------------------------------------------------------------------------------
+// Bindings for Linux AIO start here
+// -----------------------------------------------------------------------------------------------
 
 // Common data structures for futures returned by `AioContext`.
 struct AioBaseFuture {
     // reference to the `AioContext` that controls the submission queue for asynchronous I/O
     context: std::sync::Arc<AioContextInner>,
 
-    // set of links to in_flight data
-    in_flight: std::sync::Arc<parking_lot::Mutex<fnv::FnvHashSet<usize>>>,
+    // the control block of the kernel request, apart from its ticket and the completion eventfd
+    iocb: IocbBuilder,
+
+    // scheduling information for the request
+    sched_info: sched::RequestInfo,
+
+    // admission future for a submission slot
+    admission: Option<sched::Admission>,
+
+    // wait imposed by the rate limiter, once the request has been charged against it
+    throttle: Option<rate::Throttle>,
+    rate_charged: bool,
+
+    // wakes us up when the deadline passes while we wait for the rate limiter or the byte budget
+    expiry: Option<timer::Delay>,
+
+    // acquire future for the byte budget, followed by the acquired permit
+    bytes_acquire_state: Option<sync::Acquire>,
+    bytes_permit: Option<sync::SemaphorePermit>,
+
+    // identifies the slab entry of the request once it has been submitted
+    ticket: Option<slab::Ticket>,
+
+    // whether the request waits in a batch that goes out once the task is polled again
+    queued: bool,
 
-    // request information captured for the kernel request
-    iocb_info: IocbInfo,
+    // the token under which a completion driver reports the completion, if any
+    token: Option<u64>,
 
-    // the associated request state
-    state: Option<Box<RequestState>>,
+    // the scope to notify about completion, for requests operating on borrowed memory
+    scope: Option<std::sync::Arc<scope::ScopeTracker>>,
 
-    // acquire future
-    acquire_state: Option<sync::SemaphoreHandle>,
+    // receives the result in place of this future, for requests issued with a callback; moves
+    // into the slab entry once the request is submitted
+    callback: Option<slab::Callback>,
 }
 
 impl AioBaseFuture {
+    // Create the future for a request with the given control block, which is not submitted before
+    // the future is polled
+    fn new(context: &std::sync::Arc<AioContextInner>, iocb: IocbBuilder) -> AioBaseFuture {
+        AioBaseFuture {
+            context: context.clone(),
+            iocb,
+            sched_info: sched::RequestInfo::default(),
+            admission: None,
+            throttle: None,
+            rate_charged: false,
+            expiry: None,
+            bytes_acquire_state: None,
+            bytes_permit: None,
+            ticket: None,
+            queued: false,
+            token: None,
+            scope: None,
+            callback: None,
+        }
+    }
+
     // Attempt to submit the I/O request; this may need to wait until a submission slot is
     // available.
     fn submit_request(&mut self) -> Result<futures::Async<()>, io::Error> {
-        if self.state.is_none() {
-            // See if we can secure a submission slot
-            if self.acquire_state.is_none() {
-                self.acquire_state = Some(self.context.have_capacity.acquire());
+        if self.ticket.is_none() {
+            // Give up on a request that can no longer make its deadline
+            if self.sched_info.expired(time::Instant::now()) {
+                return Err(self.deadline_expired());
             }
 
-            match self.acquire_state.as_mut().unwrap().poll() {
-                Err(err) => return Err(err),
-                Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
-                Ok(futures::Async::Ready(_)) => {
-                    // retrieve a state container from the set of available ones and move it into the future
-                    self.state = self.context.capacity.write().state.pop();
+            // Wait for the rate limiter, prior to the byte budget and a submission slot
+            if !self.rate_charged {
+                let direction = self.iocb.direction();
+                self.throttle = rate::RateLimiter::throttle(&self.context.rate_limiter, direction, self.iocb.len);
+                self.rate_charged = true;
+            }
+
+            if let Some(ref mut throttle) = self.throttle {
+                if throttle.poll().is_not_ready() {
+                    return self.wait_for_deadline();
                 }
             }
 
-            assert!(self.state.is_some());
-            let state = self.state.as_mut().unwrap();
+            self.throttle = None;
 
-            let (sender, receiver) = futures::sync::oneshot::channel();
+            if self.sched_info.expired(time::Instant::now()) {
+                return Err(self.deadline_expired());
+            }
 
-            let mut data = Box::new(RequestData {
-                completed_sender: Some(sender),
-            });
-            let data_ptr = Box::into_raw(data);
-            let data_addr = unsafe { mem::transmute::<_, usize>(data_ptr) };
+            // See if we can secure our share of the byte budget, prior to taking a slot
+            if let Some(ref budget) = self.context.byte_budget {
+                if self.bytes_permit.is_none() && self.iocb.len > 0 {
+                    if self.bytes_acquire_state.is_none() {
+                        // a request larger than the budget is admitted once it has it to itself
+                        let permits = cmp::min(self.iocb.len, self.context.max_bytes_in_flight as u64);
+                        self.bytes_acquire_state = Some(budget.acquire_many(permits as usize));
+                    }
 
-            // Fill in the iocb data structure to be submitted to the kernel
-            state.request.aio_data = data_addr as u64;
-            state.request.aio_resfd = self.context.completed_fd as u32;
-            state.request.aio_flags = aio::IOCB_FLAG_RESFD | self.iocb_info.flags;
-            state.request.aio_fildes = self.iocb_info.fd as u32;
-            state.request.aio_offset = self.iocb_info.offset as i64;
-            state.request.aio_buf = self.iocb_info.buf;
-            state.request.aio_nbytes = self.iocb_info.len;
-            state.request.aio_lio_opcode = self.iocb_info.opcode as u16;
+                    match self.bytes_acquire_state.as_mut().unwrap().poll() {
+                        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+                        Ok(futures::Async::NotReady) => return self.wait_for_deadline(),
+                        Ok(futures::Async::Ready(permit)) => {
+                            self.bytes_acquire_state = None;
+                            self.bytes_permit = Some(permit);
+                        }
+                    }
 
-            // attach synchronization primitives that are used to indicate completion of this request
-            state.completed_receiver = receiver;
+                    if self.sched_info.expired(time::Instant::now()) {
+                        return Err(self.deadline_expired());
+                    }
+                }
+            }
 
-            let in_flight = &mut *self.in_flight.lock();
+            // See if we can secure a submission slot
+            if self.admission.is_none() {
+                self.sched_info.len = self.iocb.len;
+                self.sched_info.class = self.iocb.class();
+                let info = self.sched_info.clone();
+                self.admission = Some(sched::Scheduler::admit(&self.context.scheduler, info));
+            }
 
-            in_flight.insert(data_addr);
+            let permit = match self.admission.as_mut().unwrap().poll() {
+                Err(()) => return Err(self.deadline_expired()),
+                Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+                Ok(futures::Async::Ready(permit)) => permit,
+            };
 
+            self.admission = None;
 
-            // submit the request
-            let mut request_ptr_array: [*mut aio::iocb; 1] =
-                [&mut state.request as *mut aio::iocb; 1];
+            // claim the slab entry backing the slot, whose ticket identifies the request
+            let ticket = self.context.capacity.claim(slab::Occupant {
+                permit,
+                info: self.sched_info.clone(),
+                bytes: self.bytes_permit.take(),
+                scope_ticket: self.scope.as_ref().map(scope::ScopeTracker::ticket),
+                token: self.token,
+                callback: self.callback.take(),
+            });
 
-            let result = unsafe {
-                aio::io_submit(
-                    self.context.context,
-                    1,
-                    &mut request_ptr_array[0] as *mut *mut aio::iocb,
-                )
+            // the kernel signals the completion through the eventfd, if we use one
+            let iocb = match self.context.completed_fd {
+                Some(fd) => self.iocb.resfd(fd),
+                None => self.iocb,
             };
 
-            // if we have submission error, capture it as future result
-            if result != 1 {
-                return Err(io::Error::last_os_error());
+            // submit the request, or queue it for the next batch; if we have submission error,
+            // capture it as future result
+            batch::Submitter::submit(&self.context.submitter, ticket, &iocb)?;
+            self.ticket = Some(ticket);
+
+            // give the executor the chance to run the other ready tasks, whose requests may
+            // join the batch, before we get polled again and submit it
+            if self.context.submitter.flushes_on_poll() {
+                self.queued = true;
+                futures::task::current().notify();
             }
         }
 
         Ok(futures::Async::Ready(()))
     }
 
-    // Attempt to retrieve the result of a previously submitted I/O request; this may need to
-    // wait until the I/O operation has been completed
-    fn retrieve_result(&mut self) -> Result<futures::Async<()>, io::Error> {
-        // Check if we have received a notification indicating completion of the I/O request
-        let result_code = match self.state.as_mut().unwrap().completed_receiver.poll() {
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
-            Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
-            Ok(futures::Async::Ready(n)) => n,
+    // Keep waiting for the rate limiter or the byte budget, unless the deadline has passed in the
+    // meantime; the admission to a slot watches the deadline on its own
+    fn wait_for_deadline(&mut self) -> Result<futures::Async<()>, io::Error> {
+        let deadline = match self.sched_info.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(futures::Async::NotReady),
         };
 
-        // Release the kernel queue slot and the state variable that we just processed
-        let lock = &mut *self.context.capacity.write();
+        let timer = self.context.scheduler.timer();
+
+        match self.expiry.get_or_insert_with(|| timer::Delay::new(deadline)).poll(timer) {
+            futures::Async::Ready(()) => Err(self.deadline_expired()),
+            futures::Async::NotReady => Ok(futures::Async::NotReady),
+        }
+    }
+
+    // Fail the request because its deadline has passed prior to submission
+    fn deadline_expired(&mut self) -> io::Error {
+        self.admission = None;
+        self.expiry = None;
+        self.throttle = None;
+        self.bytes_acquire_state = None;
+        self.bytes_permit = None;
+        self.context.requests_expired.fetch_add(1, atomic::Ordering::Relaxed);
 
-        lock.state.push(self.state.take().unwrap());
+        io::Error::new(io::ErrorKind::TimedOut, "deadline passed before submission")
+    }
 
-        // notify others that we release a state slot
-        self.context.have_capacity.release();
+    // Attempt to retrieve the result of a previously submitted I/O request; this may need to
+    // wait until the I/O operation has been completed
+    fn retrieve_result(&mut self) -> Result<futures::Async<()>, io::Error> {
+        // Check if the completion of the I/O request has been reported; retrieving the result
+        // releases the submission slot
+        let result_code = match self.context.capacity.poll_result(self.ticket.unwrap()) {
+            futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            futures::Async::Ready(n) => n,
+        };
 
         if result_code < 0 {
             Err(io::Error::from_raw_os_error(result_code as i32))
@@ -244,12 +408,33 @@ impl AioBaseFuture {
     }
 }
 
+impl Drop for AioBaseFuture {
+    fn drop(&mut self) {
+        // the batch holding our request waits for us to be polled again, which will not happen
+        if self.queued {
+            self.context.submitter.flush();
+        }
+
+        // a request in flight keeps its slot until the kernel reports completion
+        if let Some(ticket) = self.ticket {
+            self.context.capacity.abandon(ticket);
+        }
+    }
+}
+
 // Common future base type for all asynchronous operations supperted by this API
 impl futures::Future for AioBaseFuture {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<futures::Async<()>, io::Error> {
+        // the executor has run the other ready tasks since our request was queued, so the
+        // batch goes out now
+        if self.queued {
+            self.queued = false;
+            self.context.submitter.flush();
+        }
+
         let result = self.submit_request();
 
         match result {
@@ -304,6 +489,13 @@ pub struct AioReadResultFuture<ReadWriteHandle>
     buffer: Option<ReadWriteHandle>,
 }
 
+impl<ReadWriteHandle> AioReadResultFuture<ReadWriteHandle>
+    where
+        ReadWriteHandle: convert::AsMut<[u8]>,
+{
+    request_options!(token, |this| Some(&mut this.base));
+}
+
 impl<ReadWriteHandle> futures::Future for AioReadResultFuture<ReadWriteHandle>
     where
         ReadWriteHandle: convert::AsMut<[u8]>,
@@ -335,6 +527,13 @@ pub struct AioWriteResultFuture<ReadOnlyHandle>
     buffer: Option<ReadOnlyHandle>,
 }
 
+impl<ReadOnlyHandle> AioWriteResultFuture<ReadOnlyHandle>
+    where
+        ReadOnlyHandle: convert::AsRef<[u8]>,
+{
+    request_options!(token, |this| Some(&mut this.base));
+}
+
 impl<ReadOnlyHandle> futures::Future for AioWriteResultFuture<ReadOnlyHandle>
     where
         ReadOnlyHandle: convert::AsRef<[u8]>,
@@ -361,6 +560,10 @@ pub struct AioSyncResultFuture
     base: AioBaseFuture,
 }
 
+impl AioSyncResultFuture {
+    request_options!(token, |this| Some(&mut this.base));
+}
+
 impl futures::Future for AioSyncResultFuture
 {
     type Item = ();
@@ -374,17 +577,20 @@ impl futures::Future for AioSyncResultFuture
 // A future spawned as background task to retrieve I/O completion events from the kernel
 // and distributing the results to the current futures in flight.
 pub struct AioPollFuture {
-    // the context handle for retrieving AIO completions from the kernel
-    context: aio::aio_context_t,
+    // the queue for retrieving AIO completions from the kernel
+    completions: raw::CompletionQueue,
+
+    // the requests in flight
+    capacity: std::sync::Arc<slab::Capacity>,
 
-    // set of links to in_flight data
-    in_flight: std::sync::Arc<parking_lot::Mutex<fnv::FnvHashSet<usize>>>,
+    // closes and drains the context once the task ends
+    submitter: std::sync::Arc<batch::Submitter>,
 
     // the eventfd on which the kernel will notify I/O completions
     eventfd: eventfd::EventFd,
 
     // a buffer to retrieve completion status from the kernel
-    events: Vec<aio::io_event>,
+    events: Vec<raw::RawCompletion>,
 }
 
 impl futures::Future for AioPollFuture {
@@ -402,131 +608,173 @@ impl futures::Future for AioPollFuture {
             };
 
             assert!(available > 0);
-            self.events.clear();
-
-            unsafe {
-                let result = aio::io_getevents(
-                    self.context,
-                    available as c_long,
-                    available as c_long,
-                    self.events.as_mut_ptr(),
-                    ptr::null_mut::<aio::timespec>(),
-                );
-
-                // adjust the vector size to the actual number of items returned
-                if result < 0 {
-                    return Err(io::Error::last_os_error());
-                }
 
-                assert!(result as usize == available);
-                self.events.set_len(available);
-            };
+            self.events.clear();
+            let count = self.completions.retrieve(available, available, None, None, &mut self.events)?;
+            assert!(count == available);
 
             // dispatch the retrieved events to the associated futures
-            let in_flight = &mut *self.in_flight.lock();
-            for ref event in &self.events {
-                let addr = event.data as usize;
-                if in_flight.remove(&addr) {
-                    let mut request_state: Box<RequestData> = unsafe { Box::from_raw(mem::transmute(addr)) };
-
-                    let _ = request_state
-                        .completed_sender
-                        .take()
-                        .unwrap()
-                        .send(event.res);
-                } else {
-                    println!("WARN: received event with data which is not in in_flights");
-                }
-            }
+            self.capacity.complete_all(&self.events, |_, _| {});
         }
     }
 }
 
 impl Drop for AioPollFuture {
     fn drop(&mut self) {
-        let in_flight = &mut *self.in_flight.lock();
-        for addr in in_flight.drain() {
-            // delete all in_flight data which will never arrive from AIO (after termination of AioPollFuture)
-            let _: Box<RequestData> = unsafe { Box::from_raw(mem::transmute(addr)) };
-        };
+        // nobody retrieves completions from here on
+        self.submitter.drain(&mut self.completions);
     }
 }
 
 
-// Shared state within AioContext that is backing I/O requests as represented by the individual futures.
-#[derive(Debug)]
-struct Capacity {
-    // pre-allocated eventfds and iocbs that are associated with scheduled I/O requests
-    state: Vec<Box<RequestState>>,
-}
-
-impl Capacity {
-    fn new(nr: usize) -> Result<Capacity, io::Error> {
-        let mut state = Vec::with_capacity(nr);
-
-        // using a for loop to properly handle the error case
-        // range map collect would only allow for using unwrap(), thereby turning an error into a panic
-        for _ in 0..nr {
-            let (_, receiver) = futures::sync::oneshot::channel();
-
-            state.push(Box::new(RequestState {
-                request: unsafe { mem::zeroed() },
-                completed_receiver: receiver,
-            }));
-        }
-
-        Ok(Capacity { state })
-    }
-}
-
 // The inner state, which is shared between the AioContext object returned to clients and
 // used internally by futures in flight.
 #[derive(Debug)]
 struct AioContextInner {
-    // the context handle for submitting AIO requests to the kernel
-    context: aio::aio_context_t,
-
     // the fd embedded in the completed eventfd, which can be passed to kernel functions;
     // the handle is managed by the Eventfd object that is owned by the AioPollFuture
-    // that we spawn when creating an AioContext.
-    completed_fd: RawFd,
+    // that we spawn when creating an AioContext, by the driver of a context driven by the
+    // caller, or by the task of the completion group the context belongs to. There is none for
+    // contexts whose completions are retrieved by a reaper thread.
+    completed_fd: Option<RawFd>,
+
+    // admission of waiting requests to the submission slots
+    scheduler: std::sync::Arc<sched::Scheduler>,
+
+    // the requests in flight, backed by pre-allocated slab entries
+    capacity: std::sync::Arc<slab::Capacity>,
+
+    // hands requests to the kernel, possibly in batches
+    submitter: std::sync::Arc<batch::Submitter>,
 
-    // do we have capacity?
-    have_capacity: sync::Semaphore,
+    // limits on the rate of reads and writes
+    rate_limiter: std::sync::Arc<rate::RateLimiter>,
 
-    // pre-allocated eventfds and a capacity semaphore
-    capacity: parking_lot::RwLock<Capacity>,
+    // the number of submission slots
+    nr: usize,
+
+    // optional limit on the number of bytes transferred by requests in flight, expressed as
+    // a semaphore with one permit per byte
+    byte_budget: Option<sync::Semaphore>,
+    max_bytes_in_flight: usize,
+
+    // the number of requests that failed because their deadline passed prior to submission
+    requests_expired: atomic::AtomicUsize,
 
     // handle for the spawned background task; dropping it will cancel the task
     // we are using an Option value with delayed initialization to keep the generic
     // executor type parameter out of AioContextInner
     poll_task_handle: Option<futures::sync::oneshot::SpawnHandle<(), io::Error>>,
+
+    // the thread retrieving completions in place of the background task, if any
+    reaper: Option<reaper::Reaper>,
+
+    // the completion group retrieving completions in place of the background task, if any
+    group: Option<group::GroupMembership>,
 }
 
 impl AioContextInner {
-    fn new(fd: RawFd, nr: usize) -> Result<AioContextInner, io::Error> {
-        let mut context: aio::aio_context_t = 0;
+    // Create the state of a context, along with the queue through which the party retrieving
+    // its completions does so
+    fn new(fd: Option<RawFd>, builder: &AioContextBuilder) -> Result<(AioContextInner, raw::CompletionQueue), io::Error> {
+        let nr = builder.nr;
+
+        let reserved: usize = builder.class_limits.iter().map(|limit| limit.reserved).sum();
+
+        if reserved > nr {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "more slots reserved for operation classes than available",
+            ));
+        }
+
+        if let (&QueueDiscipline::Deadline, false) = (&builder.discipline, builder.tenants.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tenants are configured, but deadline scheduling does not queue by tenant",
+            ));
+        }
+
+        match builder.max_bytes_in_flight {
+            Some(0) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "byte budget must not be empty"));
+            }
+            Some(bytes) if bytes > sync::MAX_PERMITS => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "byte budget too large"));
+            }
+            _ => {}
+        }
+
+        if let Some(ref config) = builder.adaptive {
+            config
+                .validate(nr)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        }
+
+        builder
+            .submit_policy
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
 
-        unsafe {
-            if aio::io_setup(nr as c_long, &mut context) != 0 {
-                return Err(io::Error::last_os_error());
+        let queue: Box<dyn sched::Discipline> = match builder.discipline {
+            QueueDiscipline::Priority => Box::new(sched::PriorityLanes::new(builder.priority_policy)),
+            QueueDiscipline::FairQueuing(request_cost) => {
+                Box::new(sched::FairQueue::new(request_cost, builder.tenants.clone()))
             }
+            QueueDiscipline::Deadline => Box::new(sched::DeadlineQueue::new()),
+        };
+
+        let timer = std::sync::Arc::new(timer::Timer::new());
+        let rate_limiter = rate::RateLimiter::new(builder.read_rate_limit, builder.write_rate_limit, timer.clone());
+
+        let scheduler = std::sync::Arc::new(sched::Scheduler::new(
+            sync::Semaphore::new(nr),
+            queue,
+            builder.class_limits,
+            builder.adaptive.map(|config| adaptive::Controller::new(config, nr)),
+            timer.clone(),
+        ));
+
+        let capacity = slab::Capacity::new(nr, scheduler.clone(), builder.adaptive.is_some());
+        let capacity = std::sync::Arc::new(capacity);
+
+        // requests still in flight fail once the kernel is done with them
+        let teardown = {
+            let capacity = capacity.clone();
+            move || capacity.shutdown()
         };
 
-        Ok(AioContextInner {
-            context,
-            capacity: parking_lot::RwLock::new(Capacity::new(nr)?),
-            have_capacity: sync::Semaphore::new(nr),
+        let kernel = raw::KernelContext::setup(nr, Some(Box::new(teardown)))?;
+        let completions = raw::CompletionQueue::new(&kernel, nr);
+        let submitter = batch::Submitter::new(kernel, builder.submit_policy, nr, capacity.clone(), timer);
+
+        let inner = AioContextInner {
+            capacity,
+            scheduler,
+            submitter: std::sync::Arc::new(submitter),
+            rate_limiter: std::sync::Arc::new(rate_limiter),
+            nr,
+            byte_budget: builder.max_bytes_in_flight.map(sync::Semaphore::new),
+            max_bytes_in_flight: builder.max_bytes_in_flight.unwrap_or(0),
+            requests_expired: atomic::AtomicUsize::new(0),
             completed_fd: fd,
             poll_task_handle: None,
-        })
+            reaper: None,
+            group: None,
+        };
+
+        Ok((inner, completions))
     }
 }
 
 impl Drop for AioContextInner {
     fn drop(&mut self) {
-        let result = unsafe { aio::io_destroy(self.context) };
-        assert!(result == 0);
+        if let Some(ref mut reaper) = self.reaper {
+            reaper.stop();
+        }
+
+        // the kernel context is destroyed along with the submitter, once the party retrieving
+        // completions has let go of its queue as well
     }
 }
 
@@ -535,9 +783,6 @@ impl Drop for AioContextInner {
 #[derive(Clone, Debug)]
 pub struct AioContext {
     inner: std::sync::Arc<AioContextInner>,
-
-    // set of links to in_flight data
-    in_flight: std::sync::Arc<parking_lot::Mutex<fnv::FnvHashSet<usize>>>,
 }
 
 /// Synchronization levels associated with I/O operations
@@ -553,92 +798,469 @@ pub enum SyncLevel {
     Full = aio::RWF_SYNC as isize,
 }
 
-impl AioContext {
-    /// Create a new AioContext that is driven by the provided event loop.
+// The order in which requests waiting for a submission slot are admitted; the discipline selected
+// last on the builder applies
+#[derive(Clone, Debug)]
+enum QueueDiscipline {
+    // priority lanes
+    Priority,
+
+    // weighted fair queuing between tenants, with the cost charged per request
+    FairQueuing(u64),
+
+    // earliest deadline first
+    Deadline,
+}
+
+/// Builder for an `AioContext` with configuration options beyond the number of submission slots.
+#[derive(Clone, Debug)]
+pub struct AioContextBuilder {
+    // number of submission slots
+    nr: usize,
+
+    // limit on the bytes transferred by requests in flight
+    max_bytes_in_flight: Option<usize>,
+
+    // how waiting requests of different priorities are admitted
+    priority_policy: PriorityPolicy,
+
+    // the order in which waiting requests are admitted
+    discipline: QueueDiscipline,
+
+    // explicitly configured tenants for fair queuing
+    tenants: fnv::FnvHashMap<u32, TenantConfig>,
+
+    // initial rate limits
+    read_rate_limit: RateLimit,
+    write_rate_limit: RateLimit,
+
+    // queue-depth limits per operation class
+    class_limits: [ClassLimit; 3],
+
+    // adaptive concurrency control, if enabled
+    adaptive: Option<AdaptiveConcurrency>,
+
+    // how requests are handed to the kernel
+    submit_policy: SubmitPolicy,
+
+    // how requests are routed to the shards of a sharded context
+    shard_routing: ShardRouting,
+
+    // the reactor to register the completion eventfd with, if given explicitly
+    reactor: Option<reactor::Handle>,
+}
+
+impl AioContextBuilder {
+    /// Create a new builder for a context with the given number of submission slots.
     ///
     /// # Params
-    /// - executor: The executor used to spawn the background polling task
     /// - nr: Number of submission slots for IO requests
-    pub fn new<E>(executor: &E, nr: usize) -> Result<AioContext, io::Error>
+    pub fn new(nr: usize) -> AioContextBuilder {
+        AioContextBuilder {
+            nr,
+            max_bytes_in_flight: None,
+            priority_policy: PriorityPolicy::default(),
+            discipline: QueueDiscipline::Priority,
+            tenants: fnv::FnvHashMap::default(),
+            read_rate_limit: RateLimit::default(),
+            write_rate_limit: RateLimit::default(),
+            class_limits: Default::default(),
+            adaptive: None,
+            submit_policy: SubmitPolicy::default(),
+            shard_routing: ShardRouting::default(),
+            reactor: None,
+        }
+    }
+
+    /// Limit the total number of bytes transferred by the requests in flight. Requests wait for
+    /// their share of the budget before taking a submission slot. A request that is larger than
+    /// the budget is submitted once no other request is using the budget. The budget must not be
+    /// zero.
+    ///
+    /// # Params
+    /// - bytes: The maximum number of bytes in flight
+    pub fn max_bytes_in_flight(&mut self, bytes: usize) -> &mut Self {
+        self.max_bytes_in_flight = Some(bytes);
+        self
+    }
+
+    /// Determine how requests of different priorities that wait for a submission slot are
+    /// admitted. The default is strict priority.
+    ///
+    /// # Params
+    /// - policy: The policy for serving the waiting lanes
+    pub fn priority_policy(&mut self, policy: PriorityPolicy) -> &mut Self {
+        self.priority_policy = policy;
+        self
+    }
+
+    /// Admit requests waiting for a submission slot by weighted fair queuing between tenants
+    /// rather than by priority alone. Each request is charged `request_cost` bytes on top of its
+    /// transfer size, so that tenants are accounted for both the number of requests and the bytes
+    /// they transfer. Within a tenant, waiting requests are admitted by strict priority.
+    ///
+    /// # Params
+    /// - request_cost: The cost of a request in bytes, in addition to its transfer size
+    pub fn fair_queuing(&mut self, request_cost: u64) -> &mut Self {
+        self.discipline = QueueDiscipline::FairQueuing(request_cost);
+        self
+    }
+
+    /// Configure the weight and rate caps of a tenant, enabling fair queuing with a request
+    /// cost of `DEFAULT_REQUEST_COST` unless configured otherwise. Tenants that are not configured
+    /// explicitly have a weight of 1 and no caps. Building the context fails if deadline
+    /// scheduling is selected as well.
+    ///
+    /// # Params
+    /// - tenant: The tenant identifier, as passed to the `tenant` method of request futures
+    /// - config: The configuration of the tenant
+    pub fn tenant(&mut self, tenant: u32, config: TenantConfig) -> &mut Self {
+        if let QueueDiscipline::Priority = self.discipline {
+            self.discipline = QueueDiscipline::FairQueuing(DEFAULT_REQUEST_COST);
+        }
+
+        self.tenants.insert(tenant, config);
+        self
+    }
+
+    /// Admit requests waiting for a submission slot in order of their deadlines, as set via the
+    /// `deadline` method of request futures. Requests without a deadline are admitted after all
+    /// requests with one, in order of arrival. Building the context fails if tenants are
+    /// configured as well.
+    pub fn deadline_scheduling(&mut self) -> &mut Self {
+        self.discipline = QueueDiscipline::Deadline;
+        self
+    }
+
+    /// Limit the rate of read requests. Requests over the limit wait before they queue up for
+    /// a submission slot. The limit can be changed later via `AioContext::set_read_rate_limit`.
+    ///
+    /// # Params
+    /// - limit: The limit on bandwidth and request rate of reads
+    pub fn read_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.read_rate_limit = limit;
+        self
+    }
+
+    /// Limit the rate of write and sync requests. Requests over the limit wait before they queue
+    /// up for a submission slot. The limit can be changed later via
+    /// `AioContext::set_write_rate_limit`.
+    ///
+    /// # Params
+    /// - limit: The limit on bandwidth and request rate of writes
+    pub fn write_rate_limit(&mut self, limit: RateLimit) -> &mut Self {
+        self.write_rate_limit = limit;
+        self
+    }
+
+    /// Limit the queue depth of an operation class on top of the shared submission slots. Slots
+    /// reserved for a class are not handed to requests of other classes, and a class with a
+    /// maximum never has more requests in flight. Building the context fails if more slots are
+    /// reserved than available.
+    ///
+    /// # Params
+    /// - class: The operation class
+    /// - limit: The reservation and maximum for the class
+    pub fn class_limit(&mut self, class: OpClass, limit: ClassLimit) -> &mut Self {
+        self.class_limits[class as usize] = limit;
+        self
+    }
+
+    /// Adjust the number of requests in flight to the observed completion latency, within the
+    /// submission slots of the context. Requests beyond the current limit wait for a slot as if
+    /// the context had fewer slots. Building the context fails if the configuration is invalid.
+    ///
+    /// # Params
+    /// - config: The latency target and tuning of the controller
+    pub fn adaptive_concurrency(&mut self, config: AdaptiveConcurrency) -> &mut Self {
+        self.adaptive = Some(config);
+        self
+    }
+
+    /// Determine how requests that have secured a submission slot are handed to the kernel:
+    /// one at a time for the lowest latency, which is the default, or in batches for fewer
+    /// system calls. Building the context fails if the policy is invalid.
+    ///
+    /// # Params
+    /// - policy: The submission policy
+    pub fn submit_policy(&mut self, policy: SubmitPolicy) -> &mut Self {
+        self.submit_policy = policy;
+        self
+    }
+
+    /// Register the eventfd through which the kernel signals completions with the given reactor.
+    /// By default, the eventfd binds to the reactor of the task that polls it first, which is
+    /// not necessarily the reactor of the executor passed to `build` when a process runs several
+    /// runtimes. A reactor created via `tokio::reactor::Reactor::new` can be run on a thread of
+    /// its own via `background`.
+    ///
+    /// # Params
+    /// - handle: The reactor that drives the completion eventfd
+    pub fn reactor(&mut self, handle: &reactor::Handle) -> &mut Self {
+        self.reactor = Some(handle.clone());
+        self
+    }
+
+    /// Create the AioContext that is driven by the provided event loop.
+    ///
+    /// # Params
+    /// - executor: The executor used to spawn the background polling task
+    pub fn build<E>(&self, executor: &E) -> Result<AioContext, io::Error>
         where
             E: futures::future::Executor<futures::sync::oneshot::Execute<AioPollFuture>>,
     {
         // An eventfd that we use for I/O completion notifications from the kernel
-        let eventfd = eventfd::EventFd::create(0, false)?;
+        let eventfd = match self.reactor {
+            Some(ref handle) => eventfd::EventFd::create_with_reactor(0, false, handle)?,
+            None => eventfd::EventFd::create(0, false)?,
+        };
         let fd = eventfd.evented.get_ref().fd;
 
-        let in_flight = std::sync::Arc::new(parking_lot::Mutex::new(fnv::FnvHashSet::<usize>::default()));
-
-        let mut inner = AioContextInner::new(fd, nr)?;
-        let context = inner.context;
+        let (mut inner, completions) = AioContextInner::new(Some(fd), self)?;
 
         let poll_future = AioPollFuture {
-            context,
-            in_flight: in_flight.clone(),
+            completions,
+            capacity: inner.capacity.clone(),
+            submitter: inner.submitter.clone(),
             eventfd,
-            events: Vec::with_capacity(nr),
+            events: Vec::with_capacity(self.nr),
         };
 
         inner.poll_task_handle = Some(futures::sync::oneshot::spawn(poll_future, executor));
 
         Ok(AioContext {
             inner: std::sync::Arc::new(inner),
-            in_flight,
         })
     }
 
-    /// Initiate an asynchronous read operation on the given file descriptor for reading
-    /// data from the provided absolute file offset into the buffer. The buffer also determines
-    /// the number of bytes to be read, which should be a multiple of the underlying device block
-    /// size.
+    /// Create an AioContext whose completions are retrieved by a dedicated thread blocking in
+    /// `io_getevents`, rather than by a task driven through a reactor. This suits callers without
+    /// an event loop on their storage path; request futures can simply be waited on. The thread
+    /// exits once the context has been dropped.
     ///
-    /// # Params:
-    /// - fd: The file descriptor of the file from which to read
-    /// - offset: The file offset where we want to read from
-    /// - buffer: A buffer to receive the read results
-    pub fn read<ReadWriteHandle>(
-        &self,
-        fd: RawFd,
-        offset: u64,
-        mut buffer_obj: ReadWriteHandle,
-    ) -> AioReadResultFuture<ReadWriteHandle>
-        where
-            ReadWriteHandle: convert::AsMut<[u8]>,
-    {
-        let (ptr, len) = {
-            let buffer = buffer_obj.as_mut();
-            let len = buffer.len() as u64;
-            let ptr = unsafe { mem::transmute::<_, usize>(buffer.as_ptr()) } as u64;
-            (ptr, len)
-        };
+    /// # Params
+    /// - config: The name and CPU affinity of the thread
+    pub fn build_with_reaper(&self, config: &ReaperConfig) -> Result<AioContext, io::Error> {
+        let (mut inner, completions) = AioContextInner::new(None, self)?;
+        let wake_up = inner.submitter.submission_queue(1);
+        inner.reaper = Some(reaper::Reaper::spawn(completions, wake_up, inner.capacity.clone(), self.nr, config)?);
 
-        // nothing really happens here until someone calls poll
-        AioReadResultFuture {
-            base: AioBaseFuture {
-                context: self.inner.clone(),
-                in_flight: self.in_flight.clone(),
-                iocb_info: IocbInfo {
-                    opcode: aio::IOCB_CMD_PREAD,
-                    fd,
-                    offset,
-                    len,
-                    buf: ptr,
-                    flags: 0,
-                },
-                state: None,
-                acquire_state: None,
-            },
-            buffer: Some(buffer_obj),
-        }
+        Ok(AioContext {
+            inner: std::sync::Arc::new(inner),
+        })
     }
 
-    /// Initiate an asynchronous write operation on the given file descriptor for writing
-    /// data to the provided absolute file offset from the buffer. The buffer also determines
-    /// the number of bytes to be written, which should be a multiple of the underlying device block
-    /// size.
+    /// Create an AioContext whose completions are retrieved by the caller via the returned
+    /// driver, rather than by a task spawned on an executor. This allows to fold completions into
+    /// an event loop of the caller's own.
+    pub fn build_driven(&self) -> Result<(AioContext, CompletionDriver), io::Error> {
+        let eventfd = eventfd::EventFdInner::create(0, false)?;
+        let (inner, completions) = AioContextInner::new(Some(eventfd.fd), self)?;
+        let inner = std::sync::Arc::new(inner);
+        let driver = CompletionDriver::new(inner.clone(), completions, eventfd);
+
+        Ok((AioContext { inner }, driver))
+    }
+
+    /// Create a context for callers without an event loop, whose requests block the calling
+    /// thread until they have completed. Completions are retrieved by a dedicated thread, as for
+    /// `build_with_reaper`.
     ///
-    /// # Params:
-    /// - fd: The file descriptor of the file to which to write
+    /// # Params
+    /// - config: The name and CPU affinity of the thread
+    pub fn build_blocking(&self, config: &ReaperConfig) -> Result<BlockingAioContext, io::Error> {
+        self.build_with_reaper(config).map(BlockingAioContext::from_context)
+    }
+
+    /// Determine how a sharded context routes requests to its shards. By default, each thread
+    /// sticks to one shard.
+    ///
+    /// # Params
+    /// - routing: The routing of requests to shards
+    pub fn shard_routing(&mut self, routing: ShardRouting) -> &mut Self {
+        self.shard_routing = routing;
+        self
+    }
+
+    /// Create a sharded context with one shard per executor, each with its own kernel context
+    /// and completion eventfd that are driven by that executor. Every shard is configured by
+    /// this builder, including the number of submission slots.
+    ///
+    /// # Params
+    /// - executors: The executors of the shards, such as the event loops of the worker threads
+    pub fn build_sharded<E>(&self, executors: &[E]) -> Result<ShardedAioContext, io::Error>
+        where
+            E: futures::future::Executor<futures::sync::oneshot::Execute<AioPollFuture>>,
+    {
+        let shards = executors
+            .iter()
+            .map(|executor| self.build(executor))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        ShardedAioContext::from_shards(shards, self.shard_routing)
+    }
+}
+
+/// A snapshot of the activity of an `AioContext`, as returned by `AioContext::stats`.
+#[derive(Clone, Debug, Default)]
+pub struct AioStats {
+    /// The number of submission slots of the context
+    pub slots: usize,
+
+    /// The number of requests that have been submitted to the kernel and not completed yet
+    pub requests_in_flight: usize,
+
+    /// The number of bytes transferred by the requests in flight
+    pub bytes_in_flight: usize,
+
+    /// The configured limit on the bytes in flight, if any
+    pub max_bytes_in_flight: Option<usize>,
+
+    /// The number of requests waiting for a submission slot or for their share of the byte budget
+    pub requests_waiting: usize,
+
+    /// The number of requests held back by the rate limits of the context
+    pub requests_throttled: usize,
+
+    /// The number of requests that failed because their deadline passed before they could be
+    /// submitted
+    pub requests_expired: usize,
+
+    /// The current limit on reads
+    pub read_rate_limit: RateLimit,
+
+    /// The current limit on writes and syncs
+    pub write_rate_limit: RateLimit,
+
+    /// Statistics on the waiting lanes, ordered from `Priority::High` to `Priority::Low`
+    pub lanes: Vec<LaneStats>,
+
+    /// Statistics on the tenants that have issued requests, ordered by tenant identifier; only
+    /// maintained when fair queuing is enabled
+    pub tenants: Vec<TenantStats>,
+
+    /// Statistics on the requests of each operation class
+    pub classes: Vec<ClassStats>,
+
+    /// Statistics on adaptive concurrency control, if enabled
+    pub concurrency: Option<ConcurrencyStats>,
+
+    /// The number of `io_submit` calls made
+    pub submit_calls: usize,
+
+    /// The number of `io_submit` calls saved by submitting requests in batches, i.e., the number
+    /// of requests submitted beyond one per call
+    pub submit_calls_saved: usize,
+}
+
+impl AioContext {
+    /// Create a new AioContext that is driven by the provided event loop.
+    ///
+    /// # Params
+    /// - executor: The executor used to spawn the background polling task
+    /// - nr: Number of submission slots for IO requests
+    pub fn new<E>(executor: &E, nr: usize) -> Result<AioContext, io::Error>
+        where
+            E: futures::future::Executor<futures::sync::oneshot::Execute<AioPollFuture>>,
+    {
+        AioContextBuilder::new(nr).build(executor)
+    }
+
+    /// Create a builder for a context with the given number of submission slots.
+    ///
+    /// # Params
+    /// - nr: Number of submission slots for IO requests
+    pub fn builder(nr: usize) -> AioContextBuilder {
+        AioContextBuilder::new(nr)
+    }
+
+    /// Retrieve a snapshot of the current activity of this context.
+    pub fn stats(&self) -> AioStats {
+        let inner = &self.inner;
+
+        let mut stats = AioStats {
+            slots: inner.nr,
+            requests_in_flight: inner.capacity.requests_in_flight(),
+            bytes_in_flight: inner.capacity.bytes_in_flight(),
+            max_bytes_in_flight: inner.byte_budget.as_ref().map(|_| inner.max_bytes_in_flight),
+            requests_waiting: inner.scheduler.waiting()
+                + inner.byte_budget.as_ref().map_or(0, sync::Semaphore::waiting),
+            requests_throttled: inner.rate_limiter.throttled(),
+            requests_expired: inner.requests_expired.load(atomic::Ordering::Relaxed),
+            read_rate_limit: inner.rate_limiter.limit(rate::Direction::Read),
+            write_rate_limit: inner.rate_limiter.limit(rate::Direction::Write),
+            lanes: Vec::new(),
+            tenants: Vec::new(),
+            classes: Vec::new(),
+            concurrency: None,
+            submit_calls: inner.submitter.calls(),
+            submit_calls_saved: inner.submitter.calls_saved(),
+        };
+
+        inner.scheduler.report(&mut stats);
+        stats
+    }
+
+    /// Change the rate limit of read requests. Requests that are already held back keep their
+    /// place; the new limit applies to subsequent requests.
+    ///
+    /// # Params
+    /// - limit: The new limit on bandwidth and request rate of reads
+    pub fn set_read_rate_limit(&self, limit: RateLimit) {
+        self.inner.rate_limiter.configure(rate::Direction::Read, limit);
+    }
+
+    /// Change the rate limit of write and sync requests. Requests that are already held back keep
+    /// their place; the new limit applies to subsequent requests.
+    ///
+    /// # Params
+    /// - limit: The new limit on bandwidth and request rate of writes
+    pub fn set_write_rate_limit(&self, limit: RateLimit) {
+        self.inner.rate_limiter.configure(rate::Direction::Write, limit);
+    }
+
+    /// The number of bytes transferred by requests that are currently in flight.
+    pub fn bytes_in_flight(&self) -> usize {
+        self.inner.capacity.bytes_in_flight()
+    }
+
+    /// Initiate an asynchronous read operation on the given file descriptor for reading
+    /// data from the provided absolute file offset into the buffer. The buffer also determines
+    /// the number of bytes to be read, which should be a multiple of the underlying device block
+    /// size.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    pub fn read<ReadWriteHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        mut buffer_obj: ReadWriteHandle,
+    ) -> AioReadResultFuture<ReadWriteHandle>
+        where
+            ReadWriteHandle: convert::AsMut<[u8]>,
+    {
+        let (ptr, len) = {
+            let buffer = buffer_obj.as_mut();
+            (buffer.as_mut_ptr(), buffer.len())
+        };
+
+        // nothing really happens here until someone calls poll
+        AioReadResultFuture {
+            base: AioBaseFuture::new(&self.inner, IocbBuilder::pread(fd, offset, ptr, len)),
+            buffer: Some(buffer_obj),
+        }
+    }
+
+    /// Initiate an asynchronous write operation on the given file descriptor for writing
+    /// data to the provided absolute file offset from the buffer. The buffer also determines
+    /// the number of bytes to be written, which should be a multiple of the underlying device block
+    /// size.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
     /// - offset: The file offset where we want to write to
     /// - buffer: A buffer holding the data to be written
     pub fn write<ReadOnlyHandle>(
@@ -675,31 +1297,73 @@ impl AioContext {
     {
         let (ptr, len) = {
             let buffer = buffer_obj.as_ref();
-            let len = buffer.len() as u64;
-            let ptr = unsafe { mem::transmute::<_, usize>(buffer.as_ptr()) } as c_long;
-            (ptr, len)
+            (buffer.as_ptr(), buffer.len())
         };
 
+        let iocb = IocbBuilder::pwrite(fd, offset, ptr, len).rw_flags(sync_level as i32);
+
         // nothing really happens here until someone calls poll
         AioWriteResultFuture {
-            base: AioBaseFuture {
-                context: self.inner.clone(),
-                in_flight: self.in_flight.clone(),
-                iocb_info: IocbInfo {
-                    opcode: aio::IOCB_CMD_PWRITE,
-                    fd,
-                    offset,
-                    len,
-                    buf: ptr as u64,
-                    flags: sync_level as u32,
-                },
-                state: None,
-                acquire_state: None,
-            },
+            base: AioBaseFuture::new(&self.inner, iocb),
             buffer: Some(buffer_obj),
         }
     }
 
+    /// Initiate an asynchronous read operation into a `BytesMut` buffer. The length of the
+    /// buffer determines the number of bytes to be read. Once completed, the buffer can be frozen
+    /// and passed on without copying the data.
+    ///
+    /// Memory allocated via `aligned_bytes_mut` is transferred without copying. For other memory
+    /// the alignment policy determines whether the request fails or whether the data is read
+    /// through a temporary aligned buffer.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    /// - policy: How to deal with memory that is not suitably aligned
+    #[cfg(feature = "bytes")]
+    pub fn read_bytes(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: bytes::BytesMut,
+        policy: AlignmentPolicy,
+    ) -> AioReadBytesFuture {
+        AioReadBytesFuture::new(
+            buffer::BytesMutHandle::new(buffer, policy)
+                .map(|handle| self.read(fd, offset, handle))
+                .map_err(|(buffer, error)| AioError { buffer, error }),
+        )
+    }
+
+    /// Initiate an asynchronous write operation from a `Bytes` buffer. The length of the
+    /// buffer determines the number of bytes to be written.
+    ///
+    /// Aligned memory is transferred without copying. For other memory the alignment policy
+    /// determines whether the request fails or whether the data is written through a temporary
+    /// aligned buffer.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - policy: How to deal with memory that is not suitably aligned
+    #[cfg(feature = "bytes")]
+    pub fn write_bytes(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: bytes::Bytes,
+        policy: AlignmentPolicy,
+    ) -> AioWriteBytesFuture {
+        AioWriteBytesFuture::new(
+            buffer::BytesHandle::new(buffer, policy)
+                .map(|handle| self.write(fd, offset, handle))
+                .map_err(|(buffer, error)| AioError { buffer, error }),
+        )
+    }
+
     /// Initiate an asynchronous sync operation on the given file descriptor.
     /// 
     /// __Caveat:__ While this operation is defined in the ABI, this command is known to
@@ -715,20 +1379,7 @@ impl AioContext {
     {
         // nothing really happens here until someone calls poll
         AioSyncResultFuture {
-            base: AioBaseFuture {
-                context: self.inner.clone(),
-                in_flight: self.in_flight.clone(),
-                iocb_info: IocbInfo {
-                    opcode: aio::IOCB_CMD_FSYNC,
-                    fd,
-                    buf: 0,
-                    len: 0,
-                    offset: 0,
-                    flags: 0,
-                },
-                state: None,
-                acquire_state: None,
-            },
+            base: AioBaseFuture::new(&self.inner, IocbBuilder::fsync(fd)),
         }
     }
 
@@ -748,20 +1399,7 @@ impl AioContext {
     {
         // nothing really happens here until someone calls poll
         AioSyncResultFuture {
-            base: AioBaseFuture {
-                context: self.inner.clone(),
-                in_flight: self.in_flight.clone(),
-                iocb_info: IocbInfo {
-                    opcode: aio::IOCB_CMD_FDSYNC,
-                    fd,
-                    buf: 0,
-                    len: 0,
-                    offset: 0,
-                    flags: 0,
-                },
-                state: None,
-                acquire_state: None,
-            },
+            base: AioBaseFuture::new(&self.inner, IocbBuilder::fdsync(fd)),
         }
     }
 }
@@ -774,12 +1412,18 @@ impl AioContext {
 mod tests {
     use std::borrow::{Borrow, BorrowMut};
     use std::env;
+    use std::ffi;
     use std::fs;
     use std::io::Write;
+    use std::mem;
     use std::os::unix::ffi::OsStrExt;
     use std::path;
+    use std::ptr;
     use std::sync;
+    use std::thread;
+    use std::time;
 
+    use futures::future;
     use futures_cpupool;
     use libc::{close, O_DIRECT, O_RDWR, open};
     use memmap;
@@ -800,6 +1444,11 @@ mod tests {
         result
     }
 
+    // NUL-terminated copy of a path, for passing to libc
+    fn c_path(path: &path::Path) -> ffi::CString {
+        ffi::CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
     // Create a temporary file with some content
     fn create_temp_file(path: &path::Path) {
         let mut file = fs::File::create(path).unwrap();
@@ -881,7 +1530,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -921,7 +1570,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -966,7 +1615,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -1065,7 +1714,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -1106,7 +1755,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -1144,7 +1793,7 @@ mod tests {
         {
             let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
                 open(
-                    mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
+                    c_path(&file_name).as_ptr(),
                     O_DIRECT | O_RDWR,
                 )
             });
@@ -1183,7 +1832,7 @@ mod tests {
                     assert!(result.is_ok());
 
                     // all slots have been returned
-                    assert!(context.inner.have_capacity.current_capacity() == num_slots);
+                    assert!(context.inner.scheduler.available_slots() == num_slots);
                 }
             }
         }
@@ -1191,116 +1840,1383 @@ mod tests {
         remove_file(&file_name);
     }
 
-    // A test with a mixed read/write workload
+    // Wraps a future and requests a limited number of additional polls while it is not ready.
+    // The number needs to be bounded, as the executor polls a task that notifies itself in a loop.
+    struct SpuriousPolls<F>(F, usize);
+
+    impl<F: Future> Future for SpuriousPolls<F> {
+        type Item = F::Item;
+        type Error = F::Error;
+
+        fn poll(&mut self) -> Result<futures::Async<F::Item>, F::Error> {
+            let result = self.0.poll();
+
+            if let Ok(futures::Async::NotReady) = result {
+                if self.1 > 0 {
+                    self.1 -= 1;
+                    futures::task::current().notify();
+                }
+            }
+
+            result
+        }
+    }
+
+    // Polls a future once and then drops it, whatever its state
+    struct PollOnce<F>(Option<F>);
+
+    impl<F: Future> Future for PollOnce<F> {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Result<futures::Async<()>, ()> {
+            let _ = self.0.take().unwrap().poll();
+            Ok(futures::Async::Ready(()))
+        }
+    }
+
+    // Hammer a small context with requests that are polled spuriously or abandoned while waiting
+    // or in flight; the context must neither exceed its slots nor lose any of them.
     #[test]
-    fn mixed_read_write() {
+    fn slot_handoff_stress() {
         let file_name = temp_file_name();
         create_temp_file(&file_name);
 
-        let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
-            open(
-                mem::transmute(file_name.as_os_str().as_bytes().as_ptr()),
-                O_DIRECT | O_RDWR,
-            )
-        });
-        let fd = owned_fd.fd;
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
 
-        let mut futures = Vec::new();
+            let num_slots = 3;
+            let pool = futures_cpupool::CpuPool::new(8);
+            let context = AioContext::new(&pool, num_slots).unwrap();
 
-        let pool = futures_cpupool::CpuPool::new(5);
-        let context = AioContext::new(&pool, 7).unwrap();
+            let (sender, receiver) = std::sync::mpsc::channel();
 
-        // First access sequence
-        let buffer1 = MemoryHandle::new();
+            {
+                let context = context.clone();
+                let pool = pool.clone();
+
+                std::thread::spawn(move || {
+                    for _wave in 0..20 {
+                        let mut futures = Vec::new();
+
+                        for index in 0..60 {
+                            let read_future = context
+                                .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                                .map(move |result_buffer| {
+                                    assert!(validate_block(result_buffer.as_ref()));
+                                })
+                                .map_err(|err| {
+                                    panic!("{:?}", err);
+                                });
+
+                            let future: Box<dyn Future<Item = (), Error = ()> + Send> = match index % 3 {
+                                0 => Box::new(read_future),
+                                1 => Box::new(SpuriousPolls(read_future, 5)),
+                                _ => Box::new(PollOnce(Some(read_future))),
+                            };
+
+                            futures.push(pool.spawn(future));
+                        }
+
+                        futures::future::join_all(futures).wait().unwrap();
+                    }
 
-        let sequence1 = {
-            let context1 = context.clone();
-            let context2 = context.clone();
-            let context3 = context.clone();
-            let context4 = context.clone();
-            let context5 = context.clone();
-            let context6 = context.clone();
+                    sender.send(()).unwrap();
+                });
+            }
 
-            context1
-                .read(fd, 8192, buffer1)
-                .map(|mut buffer| -> MemoryHandle {
-                    assert!(validate_block(buffer.as_ref()));
-                    fill_pattern(0u8, buffer.as_mut());
-                    buffer
-                })
-                .and_then(move |buffer| context2.write(fd, 8192, buffer))
-                .and_then(move |buffer| context3.read(fd, 0, buffer))
-                .map(|mut buffer| -> MemoryHandle {
-                    assert!(validate_block(buffer.as_ref()));
-                    fill_pattern(1u8, buffer.as_mut());
-                    buffer
-                })
-                .and_then(move |buffer| context4.write(fd, 0, buffer))
-                .and_then(move |buffer| context5.read(fd, 8192, buffer))
-                .map(|buffer| -> MemoryHandle {
-                    assert!(validate_pattern(0u8, buffer.as_ref()));
-                    buffer
-                })
-                .and_then(move |buffer| context6.read(fd, 0, buffer))
-                .map(|buffer| -> MemoryHandle {
-                    assert!(validate_pattern(1u8, buffer.as_ref()));
-                    buffer
-                })
-                .map_err(|err| {
-                    panic!("{:?}", err);
-                })
-        };
+            receiver
+                .recv_timeout(std::time::Duration::from_secs(60))
+                .expect("requests did not complete");
 
-        // Second access sequence
+            // abandoned requests may still be in flight; they release their slots upon completion
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
 
-        let buffer2 = MemoryHandle::new();
+            while context.inner.scheduler.available_slots() != num_slots {
+                assert!(std::time::Instant::now() < deadline, "submission slots lost");
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
 
-        let sequence2 = {
-            let context1 = context.clone();
-            let context2 = context.clone();
-            let context3 = context.clone();
-            let context4 = context.clone();
-            let context5 = context.clone();
-            let context6 = context.clone();
+            assert!(context.inner.capacity.free_entries() == num_slots);
+        }
 
-            context1
-                .read(fd, 16384, buffer2)
-                .map(|mut buffer| -> MemoryHandle {
-                    assert!(validate_block(buffer.as_ref()));
-                    fill_pattern(2u8, buffer.as_mut());
-                    buffer
-                })
-                .and_then(move |buffer| context2.write(fd, 16384, buffer))
-                .and_then(move |buffer| context3.read(fd, 24576, buffer))
-                .map(|mut buffer| -> MemoryHandle {
-                    assert!(validate_block(buffer.as_ref()));
-                    fill_pattern(3u8, buffer.as_mut());
-                    buffer
-                })
-                .and_then(move |buffer| context4.write(fd, 24576, buffer))
-                .and_then(move |buffer| context5.read(fd, 16384, buffer))
-                .map(|buffer| -> MemoryHandle {
-                    assert!(validate_pattern(2u8, buffer.as_ref()));
-                    buffer
-                })
-                .and_then(move |buffer| context6.read(fd, 24576, buffer))
-                .map(|buffer| -> MemoryHandle {
-                    assert!(validate_pattern(3u8, buffer.as_ref()));
-                    buffer
-                })
-                .map_err(|err| {
-                    panic!("{:?}", err);
-                })
-        };
+        remove_file(&file_name);
+    }
 
-        // Third access sequence
+    #[test]
+    fn byte_budget() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
 
-        let buffer3 = MemoryHandle::new();
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
 
-        let sequence3 = {
-            let context1 = context.clone();
-            let context2 = context.clone();
-            let context3 = context.clone();
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::builder(8).max_bytes_in_flight(16384).build(&pool).unwrap();
+
+            let futures: Vec<_> = (0..40)
+                .map(|index| {
+                    let monitor = context.clone();
+
+                    pool.spawn(
+                        context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .map(move |result_buffer| {
+                                assert!(validate_block(result_buffer.as_ref()));
+
+                                // at most two 8k requests fit into the budget
+                                let stats = monitor.stats();
+                                assert!(stats.bytes_in_flight <= 16384);
+                                assert!(stats.requests_in_flight <= 2);
+                            })
+                            .map_err(|err| {
+                                panic!("{:?}", err);
+                            }),
+                    )
+                })
+                .collect();
+
+            assert!(futures::future::join_all(futures).wait().is_ok());
+
+            let stats = context.stats();
+            assert!(stats.bytes_in_flight == 0);
+            assert!(stats.requests_in_flight == 0);
+            assert!(stats.max_bytes_in_flight == Some(16384));
+
+            // a request exceeding the budget is still admitted
+            let context = AioContext::builder(8).max_bytes_in_flight(4096).build(&pool).unwrap();
+            let result = pool.spawn(context.read(fd, 0, MemoryHandle::new())).wait();
+            assert!(result.is_ok());
+
+            // an empty budget would admit requests without limit, and is refused
+            let error = AioContext::builder(8).max_bytes_in_flight(0).build(&pool).unwrap_err();
+            assert!(error.kind() == io::ErrorKind::InvalidInput);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn tenant_throttling() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::builder(4)
+                .tenant(1, TenantConfig { max_iops: Some(20), ..Default::default() })
+                .tenant(2, TenantConfig { weight: 4, ..Default::default() })
+                .build(&pool)
+                .unwrap();
+
+            let start = time::Instant::now();
+
+            // tenant 1 can burst 20 requests, and needs another half second for the rest
+            let futures: Vec<_> = (0..60)
+                .map(|index| {
+                    let tenant = if index % 2 == 0 { 1 } else { 2 };
+
+                    pool.spawn(
+                        context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .tenant(tenant)
+                            .map(move |result_buffer| {
+                                assert!(validate_block(result_buffer.as_ref()));
+                                (tenant, time::Instant::now())
+                            })
+                            .map_err(|err| {
+                                panic!("{:?}", err);
+                            }),
+                    )
+                })
+                .collect();
+
+            let results = futures::future::join_all(futures).wait().unwrap();
+
+            // the capped tenant finishes last, and no earlier than its cap allows
+            let last = |tenant| results.iter().filter(|r| r.0 == tenant).map(|r| r.1).max().unwrap();
+            assert!(last(1) >= start + time::Duration::from_millis(250));
+            assert!(last(2) < last(1));
+
+            // only the 10 requests beyond the burst can have been held back, each counted once
+            let stats = context.stats();
+            assert!(stats.tenants.len() == 2);
+            assert!(stats.tenants.iter().all(|tenant| tenant.admitted == 30 && tenant.in_flight == 0));
+            assert!(stats.tenants[0].throttled > 0 && stats.tenants[0].throttled <= 10);
+            assert!(stats.tenants[1].throttled == 0);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let limit = RateLimit {
+                ops_per_sec: Some(50),
+                burst: time::Duration::from_millis(100),
+                ..Default::default()
+            };
+            let context = AioContext::builder(8).read_rate_limit(limit).build(&pool).unwrap();
+
+            let reads = |count| -> Vec<_> {
+                (0..count)
+                    .map(|index| {
+                        pool.spawn(
+                            context
+                                .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                                .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                                .map_err(|err| {
+                                    panic!("{:?}", err);
+                                }),
+                        )
+                    })
+                    .collect()
+            };
+
+            // five reads pass as burst, the others are spread out at 20ms intervals
+            let start = time::Instant::now();
+            let throttled = reads(20);
+
+            // writes and syncs are not affected by the read limit
+            assert!(pool.spawn(context.sync(fd)).wait().is_ok());
+
+            assert!(futures::future::join_all(throttled).wait().is_ok());
+            assert!(start.elapsed() >= time::Duration::from_millis(200));
+            assert!(context.stats().requests_throttled == 0);
+
+            // a request is charged against the limit when first polled; complete a read and
+            // report the number of requests held back right after its first poll
+            let probe = |context: &AioContext| {
+                let monitor = context.clone();
+                let mut future = context.read(fd, 0, MemoryHandle::new());
+                let mut held = None;
+
+                pool.spawn(futures::future::poll_fn(move || {
+                    let result = future.poll().map_err(|err| err.error);
+                    let held = *held.get_or_insert_with(|| monitor.stats().requests_throttled);
+                    result.map(|ready| ready.map(|_| held))
+                }))
+                .wait()
+                .unwrap()
+            };
+
+            // with one read per second, the second read is held back
+            let limit = RateLimit {
+                ops_per_sec: Some(1),
+                ..Default::default()
+            };
+            let context = AioContext::builder(8).read_rate_limit(limit).build(&pool).unwrap();
+            assert!(probe(&context) == 0);
+            assert!(probe(&context) == 1);
+
+            // lifting the limit at runtime
+            context.set_read_rate_limit(RateLimit::default());
+            assert!(context.stats().read_rate_limit.ops_per_sec.is_none());
+            assert!(probe(&context) == 0);
+            assert!(context.stats().requests_throttled == 0);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn deadline_scheduling() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::builder(2).deadline_scheduling().build(&pool).unwrap();
+
+            // a request that has missed its deadline fails without being submitted
+            let result = pool
+                .spawn(context.read(fd, 0, MemoryHandle::new()).deadline(time::Instant::now()))
+                .wait();
+
+            match result {
+                Err(err) => {
+                    assert!(err.error.kind() == io::ErrorKind::TimedOut);
+                    assert!(err.buffer.as_ref().len() == 8192);
+                }
+                Ok(_) => panic!("expired request has been submitted"),
+            }
+
+            // requests with achievable deadlines go through
+            let deadline = time::Instant::now() + time::Duration::from_secs(60);
+            let futures: Vec<_> = (0..20)
+                .map(|index| {
+                    pool.spawn(
+                        context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .deadline(deadline + time::Duration::from_millis(index))
+                            .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                            .map_err(|err| {
+                                panic!("{:?}", err);
+                            }),
+                    )
+                })
+                .collect();
+
+            assert!(futures::future::join_all(futures).wait().is_ok());
+
+            let stats = context.stats();
+            assert!(stats.requests_expired == 1);
+            assert!(stats.requests_in_flight == 0);
+
+            // a request held back by the rate limiter fails once its deadline passes
+            let limit = RateLimit {
+                ops_per_sec: Some(1),
+                ..Default::default()
+            };
+            let context = AioContext::builder(2).read_rate_limit(limit).build(&pool).unwrap();
+            assert!(pool.spawn(context.read(fd, 0, MemoryHandle::new())).wait().is_ok());
+
+            let deadline = time::Instant::now() + time::Duration::from_millis(50);
+            let result = pool.spawn(context.read(fd, 0, MemoryHandle::new()).deadline(deadline)).wait();
+            assert!(result.err().unwrap().error.kind() == io::ErrorKind::TimedOut);
+
+            let stats = context.stats();
+            assert!(stats.requests_expired == 1);
+            assert!(stats.requests_throttled == 0);
+
+            // tenants cannot be configured along with deadline scheduling, in either order
+            let tenant = TenantConfig { weight: 2, ..Default::default() };
+            let mut builders = vec![AioContext::builder(2), AioContext::builder(2)];
+            builders[0].deadline_scheduling().tenant(1, tenant.clone());
+            builders[1].tenant(1, tenant).deadline_scheduling();
+
+            for builder in builders {
+                match builder.build(&pool) {
+                    Err(err) => assert!(err.kind() == io::ErrorKind::InvalidInput),
+                    Ok(_) => panic!("conflicting disciplines accepted"),
+                }
+            }
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn class_limits() {
+        let pool = futures_cpupool::CpuPool::new(5);
+
+        // reservations cannot exceed the available slots
+        let result = AioContext::builder(2)
+            .class_limit(OpClass::Read, ClassLimit { reserved: 2, max: None })
+            .class_limit(OpClass::Sync, ClassLimit { reserved: 1, max: None })
+            .build(&pool);
+
+        match result {
+            Err(err) => assert!(err.kind() == io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("over-reserved context has been built"),
+        }
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let context = AioContext::builder(4)
+                .class_limit(OpClass::Read, ClassLimit { reserved: 0, max: Some(2) })
+                .class_limit(OpClass::Sync, ClassLimit { reserved: 1, max: Some(1) })
+                .build(&pool)
+                .unwrap();
+
+            let reads: Vec<_> = (0..20)
+                .map(|index| {
+                    pool.spawn(
+                        context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                            .map_err(|err| {
+                                panic!("{:?}", err);
+                            }),
+                    )
+                })
+                .collect();
+
+            let syncs: Vec<_> = (0..5)
+                .map(|_| pool.spawn(context.sync(fd).map_err(|err| panic!("{:?}", err))))
+                .collect();
+
+            assert!(futures::future::join_all(reads).wait().is_ok());
+            assert!(futures::future::join_all(syncs).wait().is_ok());
+
+            let stats = context.stats();
+            assert!(stats.classes.len() == 3);
+            assert!(stats.classes[0].class == OpClass::Read && stats.classes[0].limit.max == Some(2));
+            assert!(stats.classes[2].class == OpClass::Sync && stats.classes[2].limit.reserved == 1);
+            assert!(stats
+                .classes
+                .iter()
+                .all(|class| class.in_flight == 0 && class.waiting == 0));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn adaptive_concurrency() {
+        let pool = futures_cpupool::CpuPool::new(5);
+
+        let mut config = AdaptiveConcurrency::new(time::Duration::from_secs(10));
+        config.initial_limit = Some(9);
+        assert!(AioContext::builder(8).adaptive_concurrency(config).build(&pool).is_err());
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            config.initial_limit = Some(1);
+            config.window = 4;
+            let context = AioContext::builder(8).adaptive_concurrency(config).build(&pool).unwrap();
+            assert!(context.stats().concurrency.unwrap().limit == 1);
+
+            // with a generous latency target, a backlog of requests raises the limit
+            let futures: Vec<_> = (0..40)
+                .map(|index| {
+                    pool.spawn(
+                        context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                            .map_err(|err| {
+                                panic!("{:?}", err);
+                            }),
+                    )
+                })
+                .collect();
+
+            assert!(futures::future::join_all(futures).wait().is_ok());
+
+            let concurrency = context.stats().concurrency.unwrap();
+            assert!(concurrency.limit > 1 && concurrency.increases > 0);
+            assert!(concurrency.decreases == 0);
+            assert!(concurrency.window_latency.unwrap() < concurrency.target_latency);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn batched_submission() {
+        let pool = futures_cpupool::CpuPool::new(5);
+
+        let policy = SubmitPolicy::Batched {
+            window: time::Duration::from_millis(0),
+            max_batch: 0,
+        };
+        assert!(AioContext::builder(8).submit_policy(policy).build(&pool).is_err());
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let read_all = |context: &AioContext, count: u64, invalid: bool| {
+                let mut futures: Vec<Box<dyn Future<Item = (), Error = Option<i32>> + Send>> = (0..count)
+                    .map(|index| {
+                        let future = context
+                            .read(fd, (index * 8192) % FILE_SIZE, MemoryHandle::new())
+                            .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                            .map_err(|err| err.error.raw_os_error());
+                        Box::new(future) as Box<dyn Future<Item = _, Error = _> + Send>
+                    })
+                    .collect();
+
+                if invalid {
+                    let future = context
+                        .read(2431, 0, MemoryHandle::new())
+                        .map(|_| panic!("read from an invalid fd succeeded"))
+                        .map_err(|err| err.error.raw_os_error());
+                    futures.push(Box::new(future));
+                }
+
+                // a single task polls all requests, so that they are queued together
+                let results = futures.into_iter().map(|future| future.then(Ok::<_, ()>));
+                pool.spawn(futures::future::join_all(results)).wait().unwrap()
+            };
+
+            // by default, every request is submitted on its own
+            let context = AioContext::new(&pool, 32).unwrap();
+            assert!(read_all(&context, 4, false).iter().all(Result::is_ok));
+            assert!(context.stats().submit_calls == 4);
+            assert!(context.stats().submit_calls_saved == 0);
+
+            // requests queued within the window go out together; a refused request fails on its
+            // own, after the requests ahead of it have been accepted
+            let context = AioContext::builder(32)
+                .submit_policy(SubmitPolicy::Batched {
+                    window: time::Duration::from_millis(100),
+                    max_batch: 64,
+                })
+                .build(&pool)
+                .unwrap();
+
+            let results = read_all(&context, 16, true);
+            assert!(results[..16].iter().all(Result::is_ok));
+            assert!(results[16].is_err());
+
+            let stats = context.stats();
+            assert!(stats.submit_calls == 2 && stats.submit_calls_saved == 15);
+            assert!(stats.requests_in_flight == 0);
+            assert!(context.inner.capacity.free_entries() == 32);
+
+            // without a window, full batches go out right away and the rest once the task is
+            // polled again
+            let context = AioContext::builder(32)
+                .submit_policy(SubmitPolicy::Batched {
+                    window: time::Duration::from_millis(0),
+                    max_batch: 8,
+                })
+                .build(&pool)
+                .unwrap();
+
+            assert!(read_all(&context, 20, false).iter().all(Result::is_ok));
+            assert!(context.stats().submit_calls == 3);
+            assert!(context.stats().submit_calls_saved == 17);
+
+            // a request abandoned while queued goes out all the same, and releases its slot
+            let mut read = futures::executor::spawn(context.read(fd, 0, MemoryHandle::new()));
+            let notify = sync::Arc::new(NoopNotify);
+            assert!(read.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+            drop(read);
+            assert!(context.stats().submit_calls == 4);
+
+            for _ in 0..100 {
+                if context.inner.capacity.free_entries() == 32 {
+                    break;
+                }
+
+                thread::sleep(time::Duration::from_millis(10));
+            }
+
+            assert!(context.inner.capacity.free_entries() == 32);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn sharded_context() {
+        let pools: Vec<_> = (0..3).map(|_| futures_cpupool::CpuPool::new(1)).collect();
+        assert!(AioContext::builder(4).build_sharded::<futures_cpupool::CpuPool>(&[]).is_err());
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let context = AioContext::builder(4).build_sharded(&pools).unwrap();
+            assert!(context.shards().len() == 3);
+
+            // requests of a bound thread stay on its shard
+            ShardedAioContext::bind_current_thread(4);
+            assert!(context.local_index() == 1);
+
+            for index in 0..6 {
+                let result_buffer = context.read(fd, index * 8192, MemoryHandle::new()).wait().unwrap();
+                assert!(validate_block(result_buffer.as_ref()));
+            }
+
+            let shards: Vec<_> = context.shard_stats().iter().map(|stats| stats.submit_calls).collect();
+            assert!(shards == vec![0, 6, 0]);
+
+            // the combined statistics cover all shards
+            let stats = context.stats();
+            assert!(stats.slots == 12 && stats.submit_calls == 6);
+            assert!(stats.requests_in_flight == 0);
+            assert!(stats.classes.len() == 3 && stats.lanes.len() == 3);
+
+            // routing by CPU picks one of the shards
+            let context = AioContext::builder(4)
+                .shard_routing(ShardRouting::Cpu)
+                .build_sharded(&pools)
+                .unwrap();
+            assert!(context.local_index() < 3);
+
+            let result_buffer = context.read(fd, 0, MemoryHandle::new()).wait().unwrap();
+            assert!(validate_block(result_buffer.as_ref()));
+            assert!(context.stats().submit_calls == 1);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn explicit_reactor() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            // two independent runtimes, each driving a context of its own on its own thread,
+            // with completions signalled through a reactor of its own
+            let threads: Vec<_> = (0..2u64)
+                .map(|runtime| {
+                    thread::spawn(move || {
+                        let mut runtime_obj = tokio::runtime::current_thread::Runtime::new().unwrap();
+                        let background = reactor::Reactor::new().unwrap().background().unwrap();
+
+                        let context = AioContext::builder(8)
+                            .reactor(background.handle())
+                            .build(&runtime_obj.handle())
+                            .unwrap();
+
+                        let reads = (0..16).map(|index| {
+                            context
+                                .read(fd, (runtime * 16 + index) * 8192 % FILE_SIZE, MemoryHandle::new())
+                                .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                        });
+
+                        assert!(runtime_obj.block_on(future::join_all(reads)).is_ok());
+                        context.stats().submit_calls
+                    })
+                })
+                .collect();
+
+            for thread in threads {
+                assert!(thread.join().unwrap() == 16);
+            }
+
+            // completions reach requests awaited outside of any runtime
+            let background = reactor::Reactor::new().unwrap().background().unwrap();
+            let pool = futures_cpupool::CpuPool::new(1);
+            let context = AioContext::builder(8)
+                .reactor(background.handle())
+                .build(&pool)
+                .unwrap();
+
+            let result_buffer = context.read(fd, 0, MemoryHandle::new()).wait().unwrap();
+            assert!(validate_block(result_buffer.as_ref()));
+        }
+
+        remove_file(&file_name);
+    }
+
+    // whether a thread of the given name is running in this process
+    fn thread_running(name: &str) -> bool {
+        fs::read_dir("/proc/self/task").unwrap().any(|task| {
+            let comm = fs::read_to_string(task.unwrap().path().join("comm")).unwrap_or_default();
+            comm.trim_end() == name
+        })
+    }
+
+    #[test]
+    fn reaper_thread() {
+        let config = ReaperConfig {
+            name: Some("aio-test-reaper".to_string()),
+            cpu_affinity: vec![100_000],
+            ..ReaperConfig::default()
+        };
+        assert!(AioContext::builder(8).build_with_reaper(&config).is_err());
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let config = ReaperConfig {
+                cpu_affinity: vec![0],
+                ..config
+            };
+            let context = AioContext::builder(8).build_with_reaper(&config).unwrap();
+            assert!(thread_running("aio-test-reaper"));
+
+            // no executor is involved in submitting and completing the requests
+            let reads = (0..16).map(|index| {
+                context
+                    .read(fd, index * 8192, MemoryHandle::new())
+                    .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+            });
+
+            assert!(future::join_all(reads).wait().is_ok());
+            assert!(context.stats().requests_in_flight == 0);
+
+            // a request abandoned while in flight does not hold up the shutdown
+            drop(context.read(fd, 0, MemoryHandle::new()).select2(future::ok::<(), ()>(())).wait());
+
+            // the thread exits along with the context
+            drop(context);
+            assert!(!thread_running("aio-test-reaper"));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn blocking_context() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let context = BlockingAioContext::new(4).unwrap();
+
+            let mut source = AlignedBuffer::new(8192, 4096).unwrap();
+            fill_pattern(37u8, source.as_mut());
+            context.write(fd, 8192, source).unwrap();
+
+            let target = context.read(fd, 8192, AlignedBuffer::new(8192, 4096).unwrap()).unwrap();
+            assert!(validate_pattern(37u8, target.as_ref()));
+
+            // more requests than slots, and a failing one in between; results come back in order
+            let requests = (0..12u64).map(|index| {
+                let buffer = AlignedBuffer::new(8192, 4096).unwrap();
+
+                match index {
+                    5 => BlockingRequest::Read { fd: -1, offset: 0, buffer },
+                    9 => {
+                        let mut buffer = buffer;
+                        fill_pattern(38u8, buffer.as_mut());
+                        BlockingRequest::Write { fd, offset: 16384, buffer, sync_level: SyncLevel::Data }
+                    }
+                    _ => BlockingRequest::Read { fd, offset: (index % 2) * 24576, buffer },
+                }
+            });
+
+            let results = context.submit_many(requests).wait_all();
+            assert!(results.len() == 12);
+
+            for (index, result) in results.into_iter().enumerate() {
+                match index {
+                    5 => assert!(result.err().unwrap().error.raw_os_error() == Some(libc::EBADF)),
+                    9 => assert!(validate_pattern(38u8, result.unwrap().as_ref())),
+                    _ => assert!(validate_block(result.unwrap().as_ref())),
+                }
+            }
+
+            let target = context.read(fd, 16384, AlignedBuffer::new(8192, 4096).unwrap()).unwrap();
+            assert!(validate_pattern(38u8, target.as_ref()));
+            assert!(context.stats().requests_in_flight == 0);
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn raw_queues_read_write() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let (mut submission, mut completion) = raw_queues(4).unwrap();
+
+            let mut source = AlignedBuffer::new(8192, 4096).unwrap();
+            fill_pattern(41u8, source.as_mut());
+
+            let write = IocbBuilder::pwrite(fd, 8192, source.as_ref().as_ptr(), 8192).rw_flags(rwf::DSYNC);
+            assert!(unsafe { submission.push(1, &write) });
+            assert!(submission.submit().unwrap() == 1);
+
+            let mut completions = Vec::new();
+
+            while completions.is_empty() {
+                completion.reap(1, None, &mut completions).unwrap();
+            }
+
+            assert!(completions == vec![RawCompletion { tag: 1, res: 8192, res2: 0 }]);
+
+            // the request with a bad file descriptor is refused, the ones behind it stay queued
+            let mut targets: Vec<_> = (0..3).map(|_| AlignedBuffer::new(8192, 4096).unwrap()).collect();
+            let reads: Vec<_> = targets
+                .iter_mut()
+                .enumerate()
+                .map(|(index, target)| {
+                    let fd = if index == 0 { -1 } else { fd };
+                    IocbBuilder::pread(fd, 8192 * index as u64, target.as_mut().as_mut_ptr(), 8192)
+                })
+                .collect();
+
+            for (index, read) in reads.iter().enumerate() {
+                assert!(unsafe { submission.push(10 + index as u64, read) });
+            }
+
+            let rejected = submission.submit().err().unwrap();
+            assert!(rejected.tag == 10);
+            assert!(rejected.error.raw_os_error() == Some(libc::EBADF));
+            assert!(submission.len() == 2);
+            assert!(submission.submit().unwrap() == 2);
+            assert!(submission.is_empty());
+
+            completions.clear();
+
+            while completions.len() < 2 {
+                completion.reap(2 - completions.len(), None, &mut completions).unwrap();
+            }
+
+            completions.sort_by_key(|completion| completion.tag);
+            assert!(completions.iter().map(|completion| completion.tag).eq(vec![11, 12]));
+            assert!(completions.iter().all(|completion| completion.res == 8192));
+
+            assert!(validate_pattern(41u8, targets[1].as_ref()));
+            assert!(validate_block(targets[2].as_ref()));
+        }
+
+        remove_file(&file_name);
+    }
+
+    extern "C" fn ignore_signal(_signal: libc::c_int) {}
+
+    #[test]
+    fn masked_waits() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            // a bounded wait without completions returns empty-handed
+            let (_submission, mut completion) = raw_queues(4).unwrap();
+            let mut completions = Vec::new();
+            let timeout = time::Duration::from_millis(20);
+            let started = time::Instant::now();
+
+            assert!(completion.reap(1, Some(timeout), &mut completions).unwrap() == 0);
+            assert!(started.elapsed() >= timeout);
+
+            // a signal that has arrived before the wait still interrupts it
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as usize;
+                assert!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()) == 0);
+            }
+
+            let mut usr1 = SignalSet::empty();
+            usr1.add(libc::SIGUSR1);
+            assert!(format!("{:?}", usr1) == format!("{{{}}}", libc::SIGUSR1));
+
+            usr1.block().unwrap();
+            assert!(unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR1) } == 0);
+
+            let mut sigmask = SignalSet::current().unwrap();
+            assert!(sigmask.contains(libc::SIGUSR1));
+            sigmask.remove(libc::SIGUSR1);
+
+            let started = time::Instant::now();
+            let result = completion.reap_masked(1, Some(time::Duration::from_secs(5)), &sigmask, &mut completions);
+            assert!(result.err().unwrap().kind() == io::ErrorKind::Interrupted);
+            assert!(started.elapsed() < time::Duration::from_secs(5));
+
+            // a driver waits for completions
+            let (context, mut driver) = AioContext::builder(8).build_driven().unwrap();
+
+            let request = thread::spawn(move || {
+                let read = context
+                    .read(fd, 8192, MemoryHandle::new())
+                    .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())));
+                assert!(read.wait().is_ok());
+                context
+            });
+
+            let mut completed = 0;
+
+            while completed == 0 {
+                completed += driver.wait_completions(1, 8, Some(time::Duration::from_secs(5)), Some(&sigmask)).unwrap();
+            }
+
+            drop(request.join().unwrap());
+
+            // a reaper thread that accepts signals while waiting, with bounded waits
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as usize;
+                assert!(libc::sigaction(libc::SIGUSR2, &action, ptr::null_mut()) == 0);
+            }
+
+            let interrupts = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+            let counter = interrupts.clone();
+
+            let mut config = ReaperConfig {
+                name: Some("aio-test-masked".to_string()),
+                wait_timeout: Some(time::Duration::from_millis(10)),
+                on_interrupt: Some(sync::Arc::new(move || {
+                    counter.fetch_add(1, sync::atomic::Ordering::SeqCst);
+                })),
+                ..ReaperConfig::default()
+            };
+            config.wait_signals.add(libc::SIGUSR2);
+            let context = AioContext::builder(8).build_with_reaper(&config).unwrap();
+
+            // an interrupt is reported, and the thread keeps retrieving completions
+            let tid = fs::read_dir("/proc/self/task")
+                .unwrap()
+                .map(|task| task.unwrap().path())
+                .find(|task| fs::read_to_string(task.join("comm")).unwrap_or_default().trim_end() == "aio-test-masked")
+                .and_then(|task| task.file_name().unwrap().to_str().unwrap().parse::<libc::pid_t>().ok())
+                .unwrap();
+
+            while interrupts.load(sync::atomic::Ordering::SeqCst) == 0 {
+                assert!(unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, libc::SIGUSR2) } == 0);
+                thread::sleep(time::Duration::from_millis(5));
+            }
+
+            let reads = (0..16).map(|index| {
+                context
+                    .read(fd, index * 8192, MemoryHandle::new())
+                    .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+            });
+
+            assert!(future::join_all(reads).wait().is_ok());
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn executor_shutdown() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+            let context = AioContext::new(&runtime.handle(), 4).unwrap();
+
+            let mut read = futures::executor::spawn(context.read(fd, 8192, MemoryHandle::new()));
+            let notify = sync::Arc::new(NoopNotify);
+            assert!(read.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+            // the polling task goes away along with the runtime, but the request in flight still
+            // completes with the result of the kernel
+            drop(runtime);
+
+            let result_buffer = read.wait_future().unwrap();
+            assert!(validate_block(result_buffer.as_ref()));
+            assert!(context.stats().requests_in_flight == 0);
+
+            // later requests fail rather than waiting for completions that nobody retrieves
+            let result = context.read(fd, 0, MemoryHandle::new()).wait();
+            assert!(result.err().unwrap().error.raw_os_error() == Some(libc::ESHUTDOWN));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn driven_completions() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let (context, mut driver) = AioContext::builder(32).build_driven().unwrap();
+
+            // the requests are awaited on a thread of their own, while this thread plays the
+            // event loop of the caller
+            let requests = thread::spawn(move || {
+                let reads = (0..16).map(|index| {
+                    context
+                        .read(fd, index * 8192, MemoryHandle::new())
+                        .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                });
+
+                assert!(future::join_all(reads).wait().is_ok());
+                context
+            });
+
+            let mut completed = 0;
+
+            while completed < 16 {
+                let mut poll_fd = libc::pollfd {
+                    fd: driver.fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+
+                // completions left over from the previous round keep the eventfd readable
+                assert!(unsafe { libc::poll(&mut poll_fd, 1, 5000) } == 1);
+                completed += driver.process_completions(4).unwrap();
+            }
+
+            let context = requests.join().unwrap();
+            assert!(completed == 16);
+            assert!(context.stats().requests_in_flight == 0);
+            assert!(driver.process_completions(4).unwrap() == 0);
+
+            // a request in flight when the driver goes away still completes with the result of
+            // the kernel, and later requests fail
+            let mut read = futures::executor::spawn(context.read(fd, 8192, MemoryHandle::new()));
+            let notify = sync::Arc::new(NoopNotify);
+            assert!(read.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+            drop(driver);
+
+            let result_buffer = read.wait_future().unwrap();
+            assert!(validate_block(result_buffer.as_ref()));
+
+            let result = context.read(fd, 0, MemoryHandle::new()).wait();
+            assert!(result.err().unwrap().error.raw_os_error() == Some(libc::ESHUTDOWN));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn completion_group() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(3);
+            let group = CompletionGroup::new(&pool).unwrap();
+
+            // members of different sizes share the eventfd and the task of the group
+            let first = AioContext::builder(4).build_in_group(&group).unwrap();
+            let second = AioContext::builder(16).build_in_group(&group).unwrap();
+            assert!(group.contexts() == 2);
+
+            let reads = (0..32).map(|index| {
+                let context = if index % 2 == 0 { &first } else { &second };
+
+                context
+                    .read(fd, (index % 16) * 8192, MemoryHandle::new())
+                    .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+            });
+
+            let reads: Vec<_> = reads.map(|read| pool.spawn(read)).collect();
+            assert!(future::join_all(reads).wait().is_ok());
+
+            // the task of the group may still be retrieving completions of the dropped member
+            drop(first);
+
+            for _ in 0..100 {
+                if group.contexts() == 1 {
+                    break;
+                }
+
+                thread::sleep(time::Duration::from_millis(10));
+            }
+
+            assert!(group.contexts() == 1);
+
+            // the remaining member keeps the group alive
+            drop(group);
+
+            let read = second
+                .read(fd, 8192, MemoryHandle::new())
+                .map(|result_buffer| assert!(validate_block(result_buffer.as_ref())));
+            assert!(pool.spawn(read).wait().is_ok());
+            assert!(second.stats().requests_in_flight == 0);
+
+            // a request in flight when the task of the group goes away still completes with the
+            // result of the kernel
+            let runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+            let group = CompletionGroup::new(&runtime.handle()).unwrap();
+            let member = AioContext::builder(4).build_in_group(&group).unwrap();
+
+            let mut read = futures::executor::spawn(member.read(fd, 8192, MemoryHandle::new()));
+            let notify = sync::Arc::new(NoopNotify);
+            assert!(read.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+            drop(runtime);
+
+            let result_buffer = read.wait_future().unwrap();
+            assert!(validate_block(result_buffer.as_ref()));
+
+            let result = member.read(fd, 0, MemoryHandle::new()).wait();
+            assert!(result.err().unwrap().error.raw_os_error() == Some(libc::ESHUTDOWN));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn mio_completions() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let (context, mut driver) = AioContext::builder(4).build_driven().unwrap();
+
+            let poll = mio::Poll::new().unwrap();
+            poll.register(&driver, mio::Token(7), mio::Ready::readable(), mio::PollOpt::edge())
+                .unwrap();
+
+            // requests with a token are reported by it, those without are not
+            let done = sync::Arc::new(atomic::AtomicBool::new(false));
+            let requests_done = done.clone();
+
+            let requests = thread::spawn(move || {
+                let reads = (0..12).map(|index| {
+                    let read = context.read(fd, index * 8192, MemoryHandle::new());
+                    let read = if index % 3 == 0 { read } else { read.token(index) };
+                    read.map(|result_buffer| assert!(validate_block(result_buffer.as_ref())))
+                });
+
+                assert!(future::join_all(reads).wait().is_ok());
+                requests_done.store(true, atomic::Ordering::Release);
+            });
+
+            let mut events = mio::Events::with_capacity(4);
+            let mut completions = Vec::new();
+            let deadline = time::Instant::now() + time::Duration::from_secs(10);
+
+            while !done.load(atomic::Ordering::Acquire) {
+                assert!(time::Instant::now() < deadline, "completions not signalled");
+                poll.poll(&mut events, Some(time::Duration::from_millis(100))).unwrap();
+
+                if events.is_empty() {
+                    continue;
+                }
+
+                assert!(events.iter().all(|event| event.token() == mio::Token(7)));
+                completions.extend(driver.reap().unwrap());
+            }
+
+            requests.join().unwrap();
+
+            let mut tokens: Vec<_> = completions.iter().map(|completion| completion.token).collect();
+            tokens.sort();
+            assert!(tokens == vec![1, 2, 4, 5, 7, 8, 10, 11]);
+            assert!(completions.iter().all(|completion| completion.result > 0));
+        }
+
+        remove_file(&file_name);
+    }
+
+    // A test with a mixed read/write workload
+    #[test]
+    fn mixed_read_write() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+            open(
+                c_path(&file_name).as_ptr(),
+                O_DIRECT | O_RDWR,
+            )
+        });
+        let fd = owned_fd.fd;
+
+        let mut futures = Vec::new();
+
+        let pool = futures_cpupool::CpuPool::new(5);
+        let context = AioContext::new(&pool, 7).unwrap();
+
+        // First access sequence
+        let buffer1 = MemoryHandle::new();
+
+        let sequence1 = {
+            let context1 = context.clone();
+            let context2 = context.clone();
+            let context3 = context.clone();
+            let context4 = context.clone();
+            let context5 = context.clone();
+            let context6 = context.clone();
+
+            context1
+                .read(fd, 8192, buffer1)
+                .map(|mut buffer| -> MemoryHandle {
+                    assert!(validate_block(buffer.as_ref()));
+                    fill_pattern(0u8, buffer.as_mut());
+                    buffer
+                })
+                .and_then(move |buffer| context2.write(fd, 8192, buffer))
+                .and_then(move |buffer| context3.read(fd, 0, buffer))
+                .map(|mut buffer| -> MemoryHandle {
+                    assert!(validate_block(buffer.as_ref()));
+                    fill_pattern(1u8, buffer.as_mut());
+                    buffer
+                })
+                .and_then(move |buffer| context4.write(fd, 0, buffer))
+                .and_then(move |buffer| context5.read(fd, 8192, buffer))
+                .map(|buffer| -> MemoryHandle {
+                    assert!(validate_pattern(0u8, buffer.as_ref()));
+                    buffer
+                })
+                .and_then(move |buffer| context6.read(fd, 0, buffer))
+                .map(|buffer| -> MemoryHandle {
+                    assert!(validate_pattern(1u8, buffer.as_ref()));
+                    buffer
+                })
+                .map_err(|err| {
+                    panic!("{:?}", err);
+                })
+        };
+
+        // Second access sequence
+
+        let buffer2 = MemoryHandle::new();
+
+        let sequence2 = {
+            let context1 = context.clone();
+            let context2 = context.clone();
+            let context3 = context.clone();
+            let context4 = context.clone();
+            let context5 = context.clone();
+            let context6 = context.clone();
+
+            context1
+                .read(fd, 16384, buffer2)
+                .map(|mut buffer| -> MemoryHandle {
+                    assert!(validate_block(buffer.as_ref()));
+                    fill_pattern(2u8, buffer.as_mut());
+                    buffer
+                })
+                .and_then(move |buffer| context2.write(fd, 16384, buffer))
+                .and_then(move |buffer| context3.read(fd, 24576, buffer))
+                .map(|mut buffer| -> MemoryHandle {
+                    assert!(validate_block(buffer.as_ref()));
+                    fill_pattern(3u8, buffer.as_mut());
+                    buffer
+                })
+                .and_then(move |buffer| context4.write(fd, 24576, buffer))
+                .and_then(move |buffer| context5.read(fd, 16384, buffer))
+                .map(|buffer| -> MemoryHandle {
+                    assert!(validate_pattern(2u8, buffer.as_ref()));
+                    buffer
+                })
+                .and_then(move |buffer| context6.read(fd, 24576, buffer))
+                .map(|buffer| -> MemoryHandle {
+                    assert!(validate_pattern(3u8, buffer.as_ref()));
+                    buffer
+                })
+                .map_err(|err| {
+                    panic!("{:?}", err);
+                })
+        };
+
+        // Third access sequence
+
+        let buffer3 = MemoryHandle::new();
+
+        let sequence3 = {
+            let context1 = context.clone();
+            let context2 = context.clone();
+            let context3 = context.clone();
             let context4 = context.clone();
             let context5 = context.clone();
             let context6 = context.clone();
@@ -1346,6 +3262,221 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    // Notification sink for polling futures outside of an executor
+    struct NoopNotify;
+
+    impl futures::executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    #[test]
+    fn scoped_read_write() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::new(&pool, 4).unwrap();
+
+            let mut source = AlignedBuffer::new(8192, 4096).unwrap();
+            let mut target = AlignedBuffer::new(8192, 4096).unwrap();
+            fill_pattern(71u8, source.as_mut());
+
+            context.scope(|scope| {
+                scope.write(fd, 8192, source.as_ref()).wait().unwrap();
+
+                let data = scope.read(fd, 8192, target.as_mut()).wait().unwrap();
+                assert!(validate_pattern(71u8, data));
+            });
+
+            assert!(validate_pattern(71u8, target.as_ref()));
+
+            // dropping a future after submission, or panicking, still waits for the kernel
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                context.scope(|scope| {
+                    let mut future =
+                        futures::executor::spawn(scope.read(fd, 0, target.as_mut()));
+                    let notify = sync::Arc::new(NoopNotify);
+                    let _ = future.poll_future_notify(&notify, 0);
+                    drop(future);
+
+                    panic!("leaving the scope early");
+                })
+            }));
+
+            assert!(result.is_err());
+            assert!(validate_block(target.as_ref()));
+        }
+
+        remove_file(&file_name);
+    }
+
+    #[test]
+    fn callback_read_write() {
+        use std::sync::mpsc;
+
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::new(&pool, 4).unwrap();
+            let (sender, receiver) = mpsc::channel();
+
+            let mut source = AlignedBuffer::new(8192, 4096).unwrap();
+            fill_pattern(93u8, source.as_mut());
+
+            let written = sender.clone();
+            let write = context.write_with(fd, 16384, source, move |result| {
+                written.send(result.map(|(_, len)| len).map_err(|err| err.error)).unwrap();
+            });
+
+            pool.spawn(write).wait().unwrap();
+            assert!(receiver.recv().unwrap().unwrap() == 8192);
+
+            // the results of several requests arrive through their callbacks
+            let reads: Vec<_> = (0..8u64)
+                .map(|index| {
+                    let sender = sender.clone();
+                    let buffer = AlignedBuffer::new(8192, 4096).unwrap();
+
+                    context.read_with(fd, (index % 3) * 8192, buffer, move |result| {
+                        let (buffer, len) = result.unwrap();
+                        let valid = if index % 3 == 2 {
+                            validate_pattern(93u8, buffer.as_ref())
+                        } else {
+                            validate_block(buffer.as_ref())
+                        };
+
+                        sender.send(if valid { Ok(len) } else { Err(io::ErrorKind::InvalidData.into()) })
+                            .unwrap();
+                    })
+                })
+                .collect();
+
+            pool.spawn(future::join_all(reads)).wait().unwrap();
+
+            for _ in 0..8 {
+                assert!(receiver.recv().unwrap().unwrap() == 8192);
+            }
+
+            assert!(context.stats().requests_in_flight == 0);
+
+            // a request that fails before submission hands the buffer back all the same
+            let expired = context
+                .read_with(fd, 0, MemoryHandle::new(), move |result| {
+                    sender.send(result.map(|(_, len)| len).map_err(|err| err.error)).unwrap();
+                })
+                .deadline(time::Instant::now() - time::Duration::from_millis(1));
+
+            pool.spawn(expired).wait().unwrap();
+            assert!(receiver.recv().unwrap().unwrap_err().kind() == io::ErrorKind::TimedOut);
+
+            // a request dropped while waiting in a batch reports the result of the kernel
+            let context = AioContext::builder(4)
+                .submit_policy(SubmitPolicy::Batched {
+                    window: time::Duration::from_millis(0),
+                    max_batch: 4,
+                })
+                .build(&pool)
+                .unwrap();
+
+            let (sender, receiver) = mpsc::channel();
+            let queued = context.read_with(fd, 0, MemoryHandle::new(), move |result| {
+                sender.send(result.map(|(_, len)| len).map_err(|err| err.error)).unwrap();
+            });
+
+            let mut queued = futures::executor::spawn(queued);
+            let notify = sync::Arc::new(NoopNotify);
+            assert!(queued.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+            drop(queued);
+
+            assert!(receiver.recv().unwrap().unwrap() == 8192);
+        }
+
+        remove_file(&file_name);
+    }
+
+    // Zero-copy and bounce transfers of `bytes` buffers
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn read_write_bytes() {
+        let file_name = temp_file_name();
+        create_temp_file(&file_name);
+
+        {
+            let owned_fd = OwnedFd::new_from_raw_fd(unsafe {
+                open(
+                    c_path(&file_name).as_ptr(),
+                    O_DIRECT | O_RDWR,
+                )
+            });
+            let fd = owned_fd.fd;
+
+            let pool = futures_cpupool::CpuPool::new(5);
+            let context = AioContext::new(&pool, 4).unwrap();
+
+            // aligned memory is written as is
+            let mut data = aligned_bytes_mut(8192, 4096);
+            fill_pattern(65u8, data.as_mut());
+            let data = data.freeze();
+            let address = data.as_ptr();
+
+            let written = pool
+                .spawn(context.write_bytes(fd, 8192, data, AlignmentPolicy::Require(512)))
+                .wait()
+                .unwrap();
+            assert!(written.as_ptr() == address);
+
+            // unaligned memory is rejected unless we permit a bounce buffer
+            let mut unaligned = bytes::BytesMut::from(vec![0u8; 8192 + 1]);
+            unaligned.advance(1);
+
+            let unaligned = match pool
+                .spawn(context.read_bytes(fd, 8192, unaligned, AlignmentPolicy::Require(512)))
+                .wait()
+            {
+                Err(err) => {
+                    assert!(err.error.kind() == io::ErrorKind::InvalidInput);
+                    err.buffer
+                }
+                Ok(_) => panic!("misaligned buffer accepted"),
+            };
+
+            let result = pool
+                .spawn(context.read_bytes(fd, 8192, unaligned, AlignmentPolicy::Bounce(512)))
+                .wait()
+                .unwrap();
+            assert!(validate_pattern(65u8, result.as_ref()));
+
+            // the frozen read buffer can be passed on
+            let result = pool
+                .spawn(context.read_bytes(fd, 0, aligned_bytes_mut(8192, 512), AlignmentPolicy::default()))
+                .wait()
+                .unwrap()
+                .freeze();
+            assert!(validate_block(result.as_ref()));
+        }
+
+        remove_file(&file_name);
+    }
+
     // Fille the buffer with a pattern that has a dependency on the provided key.
     fn fill_pattern(key: u8, buffer: &mut [u8]) {
         // The pattern we generate is an alternation of the key value and an index value
@@ -0,0 +1,360 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::sync;
+use std::time;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures;
+use parking_lot;
+
+use timer::{Delay, Timer};
+
+// -----------------------------------------------------------------------------------------------
+// Token buckets
+// -----------------------------------------------------------------------------------------------
+
+// A token bucket that refills continuously at a fixed rate up to a burst size. A request that
+// costs more than the burst size is let through once the bucket is full, and puts the bucket
+// into debt; this keeps the long-term rate while never blocking large requests forever.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    // tokens added per second
+    rate: f64,
+
+    // maximum number of tokens
+    burst: f64,
+
+    // current number of tokens; negative while in debt
+    tokens: f64,
+
+    // time of the last refill
+    updated: time::Instant,
+}
+
+impl TokenBucket {
+    // Create a full bucket refilling at `rate` tokens per second up to `burst` tokens
+    pub(crate) fn new(rate: u64, burst: u64, now: time::Instant) -> TokenBucket {
+        let burst = burst.max(1) as f64;
+
+        TokenBucket {
+            rate: rate.max(1) as f64,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: time::Instant) {
+        if now > self.updated {
+            let elapsed = now.duration_since(self.updated);
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.updated = now;
+        }
+    }
+
+    // Determine when a request of the given cost may pass; None if it may pass right away
+    pub(crate) fn ready_at(&mut self, cost: u64, now: time::Instant) -> Option<time::Instant> {
+        self.refill(now);
+
+        let needed = (cost as f64).min(self.burst);
+
+        if self.tokens >= needed {
+            None
+        } else {
+            let nanos = ((needed - self.tokens) / self.rate * 1e9).ceil() as u64;
+            Some(now + time::Duration::from_nanos(nanos.max(1)))
+        }
+    }
+
+    // Charge a request that has been let through
+    pub(crate) fn take(&mut self, cost: u64) {
+        self.tokens -= cost as f64;
+    }
+
+    // Charge a request up front and determine when it may pass; None if right away. Successive
+    // reservations queue up behind each other as the bucket goes into debt.
+    pub(crate) fn reserve(&mut self, cost: u64, now: time::Instant) -> Option<time::Instant> {
+        let at = self.ready_at(cost, now);
+        self.take(cost);
+        at
+    }
+
+    // Return the tokens of a reservation that has been abandoned
+    pub(crate) fn refund(&mut self, cost: u64) {
+        self.tokens = (self.tokens + cost as f64).min(self.burst);
+    }
+
+    // Change the rate and burst size, keeping the tokens accumulated so far
+    pub(crate) fn reconfigure(&mut self, rate: u64, burst: u64, now: time::Instant) {
+        self.refill(now);
+        self.rate = rate.max(1) as f64;
+        self.burst = burst.max(1) as f64;
+        self.tokens = self.tokens.min(self.burst);
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Rate limits
+// -----------------------------------------------------------------------------------------------
+
+/// Limits on the rate at which requests of one direction are submitted to the kernel. Either
+/// limit can be left unset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained limit on the number of bytes transferred per second
+    pub bytes_per_sec: Option<u64>,
+
+    /// Sustained limit on the number of requests per second
+    pub ops_per_sec: Option<u64>,
+
+    /// The burst allowance, as the time worth of the sustained rates that can be used up at
+    /// once after a period of inactivity; one second by default
+    pub burst: time::Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            bytes_per_sec: None,
+            ops_per_sec: None,
+            burst: time::Duration::from_secs(1),
+        }
+    }
+}
+
+// The direction of a request for the purpose of rate limiting; syncs count as writes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Read,
+    Write,
+}
+
+// the number of tokens a bucket holds for a given burst allowance
+fn burst_size(rate: u64, burst: time::Duration) -> u64 {
+    let seconds = burst.as_secs() as f64 + f64::from(burst.subsec_nanos()) * 1e-9;
+    (rate as f64 * seconds).ceil() as u64
+}
+
+// The buckets enforcing the rate limit of one direction
+struct Buckets {
+    limit: RateLimit,
+    bytes: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit, now: time::Instant) -> Buckets {
+        let bucket = |rate| TokenBucket::new(rate, burst_size(rate, limit.burst), now);
+
+        Buckets {
+            limit,
+            bytes: limit.bytes_per_sec.map(bucket),
+            ops: limit.ops_per_sec.map(bucket),
+        }
+    }
+
+    fn reconfigure(&mut self, limit: RateLimit, now: time::Instant) {
+        let update = |bucket: &mut Option<TokenBucket>, rate: Option<u64>| match (bucket.take(), rate) {
+            (Some(mut existing), Some(rate)) => {
+                existing.reconfigure(rate, burst_size(rate, limit.burst), now);
+                *bucket = Some(existing);
+            }
+            (None, Some(rate)) => *bucket = Some(TokenBucket::new(rate, burst_size(rate, limit.burst), now)),
+            (_, None) => {}
+        };
+
+        update(&mut self.bytes, limit.bytes_per_sec);
+        update(&mut self.ops, limit.ops_per_sec);
+        self.limit = limit;
+    }
+
+    fn reserve(&mut self, len: u64, now: time::Instant) -> Option<time::Instant> {
+        let bytes = self.bytes.as_mut().and_then(|bucket| bucket.reserve(len, now));
+        let ops = self.ops.as_mut().and_then(|bucket| bucket.reserve(1, now));
+        bytes.max(ops)
+    }
+
+    fn refund(&mut self, len: u64) {
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.refund(len);
+        }
+
+        if let Some(ref mut bucket) = self.ops {
+            bucket.refund(1);
+        }
+    }
+}
+
+// Rate limiter of a context with separate limits for reads and writes. Requests reserve their
+// share of the rate up front and wait until the reservation is due before they queue up for a
+// submission slot.
+pub(crate) struct RateLimiter {
+    // buckets for reads and writes
+    buckets: parking_lot::Mutex<[Buckets; 2]>,
+
+    // wakes up throttled requests
+    timer: sync::Arc<Timer>,
+
+    // the number of requests currently held back
+    throttled: AtomicUsize,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "RateLimiter {{ throttled: {} }}", self.throttled())
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(read: RateLimit, write: RateLimit, timer: sync::Arc<Timer>) -> RateLimiter {
+        let now = time::Instant::now();
+
+        RateLimiter {
+            buckets: parking_lot::Mutex::new([Buckets::new(read, now), Buckets::new(write, now)]),
+            timer,
+            throttled: AtomicUsize::new(0),
+        }
+    }
+
+    // Replace the limit of one direction; requests that are already waiting keep their
+    // reservations
+    pub(crate) fn configure(&self, direction: Direction, limit: RateLimit) {
+        self.buckets.lock()[direction as usize].reconfigure(limit, time::Instant::now());
+    }
+
+    // The current limit of one direction
+    pub(crate) fn limit(&self, direction: Direction) -> RateLimit {
+        self.buckets.lock()[direction as usize].limit
+    }
+
+    // The number of requests currently held back
+    pub(crate) fn throttled(&self) -> usize {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Charge a request against the limit of its direction; the returned throttle needs to
+    // complete before the request may proceed
+    pub(crate) fn throttle(limiter: &sync::Arc<RateLimiter>, direction: Direction, len: u64) -> Option<Throttle> {
+        let at = limiter.buckets.lock()[direction as usize].reserve(len, time::Instant::now())?;
+        limiter.throttled.fetch_add(1, Ordering::Relaxed);
+
+        Some(Throttle {
+            limiter: limiter.clone(),
+            direction,
+            len,
+            delay: Delay::new(at),
+            done: false,
+        })
+    }
+}
+
+// A request held back by the rate limiter
+pub(crate) struct Throttle {
+    limiter: sync::Arc<RateLimiter>,
+    direction: Direction,
+    len: u64,
+    delay: Delay,
+    done: bool,
+}
+
+impl Throttle {
+    pub(crate) fn poll(&mut self) -> futures::Async<()> {
+        if self.done {
+            return futures::Async::Ready(());
+        }
+
+        let result = self.delay.poll(&self.limiter.timer);
+
+        if result.is_ready() {
+            self.done = true;
+            self.limiter.throttled.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        if !self.done {
+            // the request has been abandoned while waiting
+            self.limiter.buckets.lock()[self.direction as usize].refund(self.len);
+            self.limiter.throttled.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn refill_and_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 100, start);
+
+        assert!(bucket.ready_at(100, start).is_none());
+        bucket.take(100);
+
+        // 50 tokens take 50ms to come back
+        let ready = bucket.ready_at(50, start).unwrap();
+        assert!(ready >= start + Duration::from_millis(49) && ready <= start + Duration::from_millis(51));
+        assert!(bucket.ready_at(50, start + Duration::from_millis(50)).is_none());
+
+        // an oversized request needs a full bucket and leaves debt behind
+        let later = start + Duration::from_secs(1);
+        assert!(bucket.ready_at(1000, later).is_none());
+        bucket.take(1000);
+        assert!(bucket.ready_at(1, later + Duration::from_millis(500)).is_some());
+        assert!(bucket.ready_at(1, later + Duration::from_millis(1000)).is_none());
+    }
+
+    #[test]
+    fn reservations_queue_up() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 10, start);
+
+        // the burst passes, then each request waits for its predecessors
+        for _ in 0..10 {
+            assert!(bucket.reserve(1, start).is_none());
+        }
+
+        let first = bucket.reserve(1, start).unwrap();
+        let second = bucket.reserve(1, start).unwrap();
+        assert!(second > first);
+        assert!(second <= start + Duration::from_millis(21));
+
+        // abandoning a reservation makes room for the next one
+        bucket.refund(1);
+        assert!(bucket.reserve(1, start).unwrap() <= second);
+
+        // a lower rate takes effect for new reservations
+        bucket.reconfigure(10, 1, start);
+        assert!(bucket.reserve(1, start).unwrap() >= start + Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,736 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync;
+use std::time;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use aio;
+use rate;
+use OpClass;
+
+/// Opcodes of kernel AIO requests, for use with `IocbBuilder::new`; see `linux/aio_abi.h`.
+pub mod opcode {
+    use aio;
+
+    /// Read into a buffer
+    pub const PREAD: u16 = aio::IOCB_CMD_PREAD as u16;
+
+    /// Write from a buffer
+    pub const PWRITE: u16 = aio::IOCB_CMD_PWRITE as u16;
+
+    /// Sync data and meta data of a file
+    pub const FSYNC: u16 = aio::IOCB_CMD_FSYNC as u16;
+
+    /// Sync the data of a file
+    pub const FDSYNC: u16 = aio::IOCB_CMD_FDSYNC as u16;
+
+    /// Wait for poll events on a file descriptor, which are passed in the buffer field
+    pub const POLL: u16 = 5;
+
+    /// Do nothing
+    pub const NOOP: u16 = 6;
+
+    /// Read into an array of `struct iovec`
+    pub const PREADV: u16 = 7;
+
+    /// Write from an array of `struct iovec`
+    pub const PWRITEV: u16 = 8;
+}
+
+/// Per-request flags of reads and writes, for use with `IocbBuilder::rw_flags`; see
+/// `linux/fs.h`.
+pub mod rwf {
+    /// High priority request, polling for its completion where supported
+    pub const HIPRI: i32 = 1;
+
+    /// Per-request `O_DSYNC`
+    pub const DSYNC: i32 = 2;
+
+    /// Per-request `O_SYNC`
+    pub const SYNC: i32 = 4;
+
+    /// Fail with `EAGAIN` rather than block
+    pub const NOWAIT: i32 = 8;
+
+    /// Per-request `O_APPEND`
+    pub const APPEND: i32 = 16;
+}
+
+/// Flag of `IocbBuilder::flags` that makes the request priority set via `reqprio` take effect.
+pub const IOCB_FLAG_IOPRIO: u32 = 1 << 1;
+
+// -----------------------------------------------------------------------------------------------
+// Control blocks
+// -----------------------------------------------------------------------------------------------
+
+/// Describes a kernel I/O control block (`struct iocb`) field by field, including those that the
+/// requests of an `AioContext` do not expose. The `aio_data` field is reserved for the tag or
+/// ticket that identifies the request upon completion.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IocbBuilder {
+    pub(crate) opcode: u16,
+    pub(crate) fd: RawFd,
+    pub(crate) offset: u64,
+    pub(crate) buf: u64,
+    pub(crate) len: u64,
+    pub(crate) flags: u32,
+    pub(crate) rw_flags: i32,
+    pub(crate) reqprio: i16,
+    pub(crate) resfd: Option<RawFd>,
+}
+
+impl IocbBuilder {
+    /// Start a control block for the given opcode, with all other fields cleared.
+    ///
+    /// # Params
+    /// - opcode: The operation, such as one of the constants in `opcode`
+    pub fn new(opcode: u16) -> IocbBuilder {
+        IocbBuilder {
+            opcode,
+            fd: -1,
+            offset: 0,
+            buf: 0,
+            len: 0,
+            flags: 0,
+            rw_flags: 0,
+            reqprio: 0,
+            resfd: None,
+        }
+    }
+
+    /// A read of `len` bytes at `buf` from the file offset.
+    pub fn pread(fd: RawFd, offset: u64, buf: *mut u8, len: usize) -> IocbBuilder {
+        IocbBuilder::new(opcode::PREAD).fd(fd).offset(offset).buffer(buf, len)
+    }
+
+    /// A write of `len` bytes at `buf` to the file offset.
+    pub fn pwrite(fd: RawFd, offset: u64, buf: *const u8, len: usize) -> IocbBuilder {
+        IocbBuilder::new(opcode::PWRITE).fd(fd).offset(offset).buffer(buf, len)
+    }
+
+    /// A sync of data and meta data of the file.
+    pub fn fsync(fd: RawFd) -> IocbBuilder {
+        IocbBuilder::new(opcode::FSYNC).fd(fd)
+    }
+
+    /// A sync of the data of the file.
+    pub fn fdsync(fd: RawFd) -> IocbBuilder {
+        IocbBuilder::new(opcode::FDSYNC).fd(fd)
+    }
+
+    /// Set the file descriptor to operate on (`aio_fildes`).
+    pub fn fd(mut self, fd: RawFd) -> Self {
+        self.fd = fd;
+        self
+    }
+
+    /// Set the file offset (`aio_offset`).
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Set the address and length of the transfer (`aio_buf` and `aio_nbytes`); for vectored
+    /// opcodes, these describe the array of `struct iovec` and its number of elements.
+    pub fn buffer<T>(mut self, buf: *const T, len: usize) -> Self {
+        self.buf = buf as usize as u64;
+        self.len = len as u64;
+        self
+    }
+
+    /// Set the flags of the control block (`aio_flags`), such as `IOCB_FLAG_IOPRIO`. The flag for
+    /// signalling an eventfd is set along with `resfd`.
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the per-request flags of reads and writes (`aio_rw_flags`), such as `rwf::DSYNC` or
+    /// `rwf::NOWAIT`.
+    pub fn rw_flags(mut self, rw_flags: i32) -> Self {
+        self.rw_flags = rw_flags;
+        self
+    }
+
+    /// Set the I/O priority of the request (`aio_reqprio`), which takes effect along with the
+    /// `IOCB_FLAG_IOPRIO` flag.
+    pub fn reqprio(mut self, reqprio: i16) -> Self {
+        self.reqprio = reqprio;
+        self
+    }
+
+    /// Have the kernel signal the completion of the request through the given eventfd
+    /// (`aio_resfd`).
+    pub fn resfd(mut self, fd: RawFd) -> Self {
+        self.resfd = Some(fd);
+        self
+    }
+
+    // Fill in a control block, which is expected to be cleared apart from `aio_data`
+    pub(crate) fn fill(&self, iocb: &mut aio::iocb) {
+        iocb.aio_lio_opcode = self.opcode;
+        iocb.aio_fildes = self.fd as u32;
+        iocb.aio_offset = self.offset as i64;
+        iocb.aio_buf = self.buf;
+        iocb.aio_nbytes = self.len;
+        iocb.aio_flags = self.flags;
+        iocb.aio_rw_flags = self.rw_flags;
+        iocb.aio_reqprio = self.reqprio;
+
+        if let Some(fd) = self.resfd {
+            iocb.aio_resfd = fd as u32;
+            iocb.aio_flags |= aio::IOCB_FLAG_RESFD;
+        }
+    }
+
+    // the class of the request for the purpose of queue-depth limits
+    pub(crate) fn class(&self) -> OpClass {
+        let sync = rwf::DSYNC | rwf::SYNC;
+
+        if self.opcode == opcode::PREAD || self.opcode == opcode::PREADV {
+            OpClass::Read
+        } else if (self.opcode == opcode::PWRITE || self.opcode == opcode::PWRITEV) && self.rw_flags & sync == 0 {
+            OpClass::Write
+        } else {
+            OpClass::Sync
+        }
+    }
+
+    // the direction of the request for the purpose of rate limiting
+    pub(crate) fn direction(&self) -> rate::Direction {
+        match self.class() {
+            OpClass::Read => rate::Direction::Read,
+            OpClass::Write | OpClass::Sync => rate::Direction::Write,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Signal sets
+// -----------------------------------------------------------------------------------------------
+
+/// A set of signals, used as the signal mask to apply while waiting for completions.
+#[derive(Copy, Clone)]
+pub struct SignalSet {
+    set: libc::sigset_t,
+}
+
+impl SignalSet {
+    /// A set without any signals.
+    pub fn empty() -> SignalSet {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        SignalSet { set }
+    }
+
+    /// The signal mask of the calling thread.
+    pub fn current() -> io::Result<SignalSet> {
+        let mut signals = SignalSet::empty();
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut signals.set) };
+
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+
+        Ok(signals)
+    }
+
+    /// Add a signal to the set.
+    ///
+    /// # Params
+    /// - signal: The signal number, such as `libc::SIGUSR1`
+    pub fn add(&mut self, signal: libc::c_int) -> &mut Self {
+        let result = unsafe { libc::sigaddset(&mut self.set, signal) };
+        assert!(result == 0, "invalid signal number {}", signal);
+        self
+    }
+
+    /// Remove a signal from the set.
+    ///
+    /// # Params
+    /// - signal: The signal number, such as `libc::SIGUSR1`
+    pub fn remove(&mut self, signal: libc::c_int) -> &mut Self {
+        let result = unsafe { libc::sigdelset(&mut self.set, signal) };
+        assert!(result == 0, "invalid signal number {}", signal);
+        self
+    }
+
+    /// Whether the set contains the signal.
+    pub fn contains(&self, signal: libc::c_int) -> bool {
+        unsafe { libc::sigismember(&self.set, signal) == 1 }
+    }
+
+    /// Whether the set does not contain any signals.
+    pub fn is_empty(&self) -> bool {
+        self.signals().next().is_none()
+    }
+
+    // Block the signals of the set in the calling thread, returning the previous signal mask
+    pub(crate) fn block(&self) -> io::Result<SignalSet> {
+        let mut previous = SignalSet::empty();
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &self.set, &mut previous.set) };
+
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+
+        Ok(previous)
+    }
+
+    // the signals in the set, in ascending order
+    pub(crate) fn signals<'a>(&'a self) -> impl Iterator<Item = libc::c_int> + 'a {
+        (1..libc::SIGRTMAX() + 1).filter(move |&signal| self.contains(signal))
+    }
+}
+
+impl Default for SignalSet {
+    fn default() -> SignalSet {
+        SignalSet::empty()
+    }
+}
+
+impl fmt::Debug for SignalSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_set().entries(self.signals()).finish()
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// System calls
+// -----------------------------------------------------------------------------------------------
+
+// Hand control blocks to the kernel. Returns the number of requests accepted, which is a prefix
+// of the ones passed; an error means that the first one has not been accepted.
+unsafe fn submit(context: aio::aio_context_t, requests: &mut [*mut aio::iocb]) -> io::Result<usize> {
+    let result = aio::io_submit(context, requests.len() as libc::c_long, requests.as_mut_ptr());
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+// Set once `io_pgetevents` has turned out to be missing, so that the emulation is used right away
+static PGETEVENTS_MISSING: AtomicBool = AtomicBool::new(false);
+
+// Retrieve between `min` and `max` completion events into the buffer, replacing its contents,
+// waiting at most for the timeout if given. `max` is limited to the capacity of the buffer.
+//
+// If a signal mask is given, it replaces the signal mask of the calling thread while waiting, so
+// that signals can interrupt the wait without being lost in between two waits. On kernels
+// without `io_pgetevents`, the mask is set with `pthread_sigmask` before calling `io_getevents`
+// and restored afterwards, which is not atomic: a signal that is pending when the mask is set, or
+// that arrives before the wait begins, is handled right away and does not interrupt the wait.
+fn get_events(
+    context: aio::aio_context_t,
+    min: usize,
+    max: usize,
+    events: &mut Vec<aio::io_event>,
+    timeout: Option<time::Duration>,
+    sigmask: Option<&SignalSet>,
+) -> io::Result<usize> {
+    let max = max.min(events.capacity());
+    let mut timespec: aio::timespec = unsafe { mem::zeroed() };
+
+    let timeout = match timeout {
+        None => ptr::null_mut(),
+        Some(timeout) => {
+            timespec.tv_sec = timeout.as_secs() as _;
+            timespec.tv_nsec = timeout.subsec_nanos() as _;
+            &mut timespec as *mut aio::timespec
+        }
+    };
+
+    events.clear();
+
+    let (min, max, buffer) = (min as libc::c_long, max as libc::c_long, events.as_mut_ptr());
+
+    let result = match sigmask {
+        None => unsafe { aio::io_getevents(context, min, max, buffer, timeout) },
+        Some(sigmask) => {
+            let mut result = -1;
+
+            if !PGETEVENTS_MISSING.load(Ordering::Relaxed) {
+                let usig = aio::aio_sigset {
+                    sigmask: &sigmask.set,
+                    sigsetsize: aio::KERNEL_SIGSET_SIZE,
+                };
+
+                result = unsafe { aio::io_pgetevents(context, min, max, buffer, timeout, &usig) };
+
+                if result < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS) {
+                    PGETEVENTS_MISSING.store(true, Ordering::Relaxed);
+                }
+            }
+
+            if PGETEVENTS_MISSING.load(Ordering::Relaxed) {
+                let mut previous = SignalSet::empty();
+                let error = unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &sigmask.set, &mut previous.set) };
+
+                if error != 0 {
+                    return Err(io::Error::from_raw_os_error(error));
+                }
+
+                result = unsafe { aio::io_getevents(context, min, max, buffer, timeout) };
+                let error = io::Error::last_os_error();
+
+                // signals unblocked by the mask have been handled as they arrived, either before
+                // the wait or by interrupting it
+                unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &previous.set, ptr::null_mut()) };
+
+                if result < 0 {
+                    return Err(error);
+                }
+            }
+
+            result
+        }
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe { events.set_len(result as usize) };
+    Ok(result as usize)
+}
+
+// -----------------------------------------------------------------------------------------------
+// Submission and completion queues
+// -----------------------------------------------------------------------------------------------
+
+/// A completed request, as retrieved via `CompletionQueue::reap`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RawCompletion {
+    /// The tag passed when pushing the request
+    pub tag: u64,
+
+    /// The primary result reported by the kernel (`res`): typically the number of bytes
+    /// transferred, or a negated `errno` value
+    pub res: i64,
+
+    /// The secondary result reported by the kernel (`res2`)
+    pub res2: i64,
+}
+
+/// A request the kernel refused to accept, as returned by `SubmissionQueue::submit`.
+#[derive(Debug)]
+pub struct RejectedRequest {
+    /// The tag passed when pushing the request
+    pub tag: u64,
+
+    /// The reason given by the kernel
+    pub error: io::Error,
+}
+
+// A kernel context shared by the queues created on it, which is destroyed along with the last of
+// them
+pub(crate) struct KernelContext {
+    context: aio::aio_context_t,
+
+    // called once the context has been destroyed, when the kernel is done with every request
+    teardown: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+pub(crate) type Kernel = sync::Arc<KernelContext>;
+
+impl KernelContext {
+    // Create a kernel context with room for `nr` requests in flight
+    pub(crate) fn setup(nr: usize, teardown: Option<Box<dyn FnOnce() + Send + Sync>>) -> io::Result<Kernel> {
+        let mut context: aio::aio_context_t = 0;
+
+        if unsafe { aio::io_setup(nr as libc::c_long, &mut context) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(sync::Arc::new(KernelContext { context, teardown }))
+    }
+}
+
+impl fmt::Debug for KernelContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "KernelContext {{ context: {} }}", self.context)
+    }
+}
+
+impl Drop for KernelContext {
+    fn drop(&mut self) {
+        // destroying the context waits for the requests in flight to complete
+        let result = unsafe { aio::io_destroy(self.context) };
+        assert!(result == 0);
+
+        if let Some(teardown) = self.teardown.take() {
+            teardown();
+        }
+    }
+}
+
+/// Create a kernel context with room for `nr` requests in flight, along with the queue for
+/// submitting requests to it and the queue for retrieving their completions. The two queues can
+/// be used from different threads.
+///
+/// `AioContext` submits its requests and retrieves their completions through the same queues,
+/// but on a kernel context of its own. Unlike with `AioContext`, there is no admission control:
+/// the caller keeps track of the number of requests in flight, and requests beyond the capacity
+/// of the kernel context stay in the submission queue.
+///
+/// # Params
+/// - nr: The number of requests the kernel context can hold
+pub fn raw_queues(nr: usize) -> io::Result<(SubmissionQueue, CompletionQueue)> {
+    let kernel = KernelContext::setup(nr, None)?;
+    Ok((SubmissionQueue::new(&kernel, nr), CompletionQueue::new(&kernel, nr)))
+}
+
+/// Collects control blocks and hands them to the kernel with as few `io_submit` calls as
+/// possible, as created via `raw_queues`.
+pub struct SubmissionQueue {
+    kernel: Kernel,
+
+    // the maximum number of requests waiting to be submitted
+    capacity: usize,
+
+    // control blocks waiting to be submitted, in order of arrival; the kernel copies them upon
+    // submission, so they need not stay around while the requests are in flight
+    pending: Vec<aio::iocb>,
+
+    // scratch space for passing the control blocks to the kernel
+    pointers: Vec<*mut aio::iocb>,
+
+    // the number of `io_submit` calls made
+    calls: usize,
+}
+
+// the scratch pointers only refer to control blocks owned by the queue, and are only used by
+// methods taking the queue mutably
+unsafe impl Send for SubmissionQueue {}
+unsafe impl Sync for SubmissionQueue {}
+
+impl fmt::Debug for SubmissionQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "SubmissionQueue {{ pending: {} }}", self.pending.len())
+    }
+}
+
+impl SubmissionQueue {
+    // Create a queue on the kernel context that holds up to `capacity` requests
+    pub(crate) fn new(kernel: &Kernel, capacity: usize) -> SubmissionQueue {
+        SubmissionQueue {
+            kernel: kernel.clone(),
+            capacity,
+            pending: Vec::with_capacity(capacity),
+            pointers: Vec::with_capacity(capacity),
+            calls: 0,
+        }
+    }
+
+    /// The number of requests waiting to be submitted.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether no requests are waiting to be submitted.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue a request for submission. Returns false without queueing it if the queue already
+    /// holds as many requests as the kernel context.
+    ///
+    /// # Safety
+    /// Memory referenced by the control block, such as the buffer of a transfer, needs to stay
+    /// valid until the completion of the request has been retrieved.
+    ///
+    /// # Params
+    /// - tag: Identifies the request in its completion
+    /// - iocb: The control block of the request
+    pub unsafe fn push(&mut self, tag: u64, iocb: &IocbBuilder) -> bool {
+        if self.pending.len() >= self.capacity {
+            return false;
+        }
+
+        let mut request: aio::iocb = mem::zeroed();
+        request.aio_data = tag;
+        iocb.fill(&mut request);
+
+        self.pending.push(request);
+        true
+    }
+
+    /// Hand the queued requests to the kernel. Returns the number of requests submitted; requests
+    /// that do not fit into the kernel context stay queued. If the kernel refuses a request, it
+    /// is removed from the queue and returned, and the requests behind it stay queued.
+    pub fn submit(&mut self) -> Result<usize, RejectedRequest> {
+        let mut submitted = 0;
+
+        while !self.pending.is_empty() {
+            self.pointers.clear();
+            self.pointers.extend(self.pending.iter_mut().map(|request| request as *mut aio::iocb));
+
+            self.calls += 1;
+
+            match unsafe { submit(self.kernel.context, &mut self.pointers) } {
+                Ok(0) => break,
+                Ok(count) => {
+                    self.pending.drain(..count);
+                    submitted += count;
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    let request = self.pending.remove(0);
+                    self.pointers.clear();
+
+                    return Err(RejectedRequest {
+                        tag: request.aio_data,
+                        error,
+                    });
+                }
+            }
+        }
+
+        self.pointers.clear();
+        Ok(submitted)
+    }
+
+    // Remove the request at the head of the queue without submitting it, returning its tag
+    pub(crate) fn pop(&mut self) -> Option<u64> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(self.pending.remove(0).aio_data)
+        }
+    }
+
+    // The number of `io_submit` calls made so far
+    pub(crate) fn calls(&self) -> usize {
+        self.calls
+    }
+}
+
+/// Retrieves the completions of the requests submitted via the matching `SubmissionQueue`, as
+/// created via `raw_queues`.
+pub struct CompletionQueue {
+    kernel: Kernel,
+
+    // a buffer to retrieve completion events from the kernel
+    events: Vec<aio::io_event>,
+}
+
+impl fmt::Debug for CompletionQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "CompletionQueue {{ context: {} }}", self.kernel.context)
+    }
+}
+
+impl CompletionQueue {
+    // Create a queue on the kernel context that retrieves up to `capacity` completions at once
+    pub(crate) fn new(kernel: &Kernel, capacity: usize) -> CompletionQueue {
+        CompletionQueue {
+            kernel: kernel.clone(),
+            events: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Retrieve the completions that are available, waiting until there are at least `min` of
+    /// them or until the timeout has passed; without a timeout, the call waits indefinitely.
+    /// The completions are appended to `out`. Returns the number of completions retrieved.
+    ///
+    /// # Params
+    /// - min: The number of completions to wait for
+    /// - timeout: The maximum time to wait
+    /// - out: Receives the completions
+    pub fn reap(
+        &mut self,
+        min: usize,
+        timeout: Option<time::Duration>,
+        out: &mut Vec<RawCompletion>,
+    ) -> io::Result<usize> {
+        let max = self.events.capacity();
+        self.retrieve(min, max, timeout, None, out)
+    }
+
+    /// Retrieve completions like `reap`, while the signal mask of the calling thread is replaced
+    /// by the given one, as with `pselect`. A signal unblocked by the mask interrupts the wait
+    /// with `io::ErrorKind::Interrupted`, even if it has arrived before the call.
+    ///
+    /// Kernels before Linux 4.18 lack `io_pgetevents`. There, the mask is set before waiting and
+    /// restored afterwards, which is not atomic: a signal that is pending or arrives before the
+    /// wait begins is handled without interrupting the wait, which then lasts until completions
+    /// arrive or the timeout passes. Use a timeout to bound the delay in that case.
+    ///
+    /// # Params
+    /// - min: The number of completions to wait for
+    /// - timeout: The maximum time to wait
+    /// - sigmask: The signal mask to apply while waiting
+    /// - out: Receives the completions
+    pub fn reap_masked(
+        &mut self,
+        min: usize,
+        timeout: Option<time::Duration>,
+        sigmask: &SignalSet,
+        out: &mut Vec<RawCompletion>,
+    ) -> io::Result<usize> {
+        let max = self.events.capacity();
+        self.retrieve(min, max, timeout, Some(sigmask), out)
+    }
+
+    /// Retrieve the completions that are available without waiting, appending them to `out`.
+    /// Returns the number of completions retrieved.
+    ///
+    /// # Params
+    /// - out: Receives the completions
+    pub fn poll(&mut self, out: &mut Vec<RawCompletion>) -> io::Result<usize> {
+        self.reap(0, Some(time::Duration::from_secs(0)), out)
+    }
+
+    // Retrieve between `min` and `max` completions, appending them to `out`; `max` is limited to
+    // the capacity of the queue
+    pub(crate) fn retrieve(
+        &mut self,
+        min: usize,
+        max: usize,
+        timeout: Option<time::Duration>,
+        sigmask: Option<&SignalSet>,
+        out: &mut Vec<RawCompletion>,
+    ) -> io::Result<usize> {
+        let max = max.min(self.events.capacity());
+        let count = get_events(self.kernel.context, min.min(max), max, &mut self.events, timeout, sigmask)?;
+
+        out.extend(self.events.iter().map(|event| RawCompletion {
+            tag: event.data,
+            res: event.res,
+            res2: event.res2,
+        }));
+
+        Ok(count)
+    }
+}
@@ -0,0 +1,252 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync;
+use std::thread;
+use std::time;
+
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+use raw::{CompletionQueue, IocbBuilder, SignalSet, SubmissionQueue};
+use slab::Capacity;
+
+// the `aio_data` of the request that wakes up the reaper thread; it never matches a slab entry
+const WAKE_UP: u64 = u64::MAX;
+
+// how long the thread pauses before it retries after failing to retrieve completions
+const RETRY_WAIT: time::Duration = time::Duration::from_millis(10);
+
+/// Configuration of the thread that retrieves the completions of a context built via
+/// `AioContextBuilder::build_with_reaper`.
+#[derive(Clone, Default)]
+pub struct ReaperConfig {
+    /// The name of the thread; `aio-reaper` if not set
+    pub name: Option<String>,
+
+    /// The CPUs the thread may run on; any CPU if empty
+    pub cpu_affinity: Vec<usize>,
+
+    /// Signals the thread accepts only while it waits for completions, and blocks otherwise, so
+    /// that a signal arriving while completions are processed interrupts the next wait rather
+    /// than getting lost. On kernels without `io_pgetevents`, such a signal is handled without
+    /// interrupting the wait; see `CompletionQueue::reap_masked`
+    pub wait_signals: SignalSet,
+
+    /// Called on the thread whenever a signal has interrupted its wait, before the thread
+    /// resumes waiting; the thread keeps retrieving completions until the context is dropped
+    pub on_interrupt: Option<sync::Arc<dyn Fn() + Send + Sync>>,
+
+    /// The maximum time the thread waits for completions at once; without it, the thread waits
+    /// until a completion arrives
+    pub wait_timeout: Option<time::Duration>,
+}
+
+impl fmt::Debug for ReaperConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ReaperConfig")
+            .field("name", &self.name)
+            .field("cpu_affinity", &self.cpu_affinity)
+            .field("wait_signals", &self.wait_signals)
+            .field("on_interrupt", &self.on_interrupt.is_some())
+            .field("wait_timeout", &self.wait_timeout)
+            .finish()
+    }
+}
+
+// How the reaper thread waits for completions
+struct Wait {
+    // the signal mask while waiting, if the thread blocks signals otherwise
+    sigmask: Option<SignalSet>,
+    on_interrupt: Option<sync::Arc<dyn Fn() + Send + Sync>>,
+    timeout: Option<time::Duration>,
+}
+
+// Pin the current thread to the given CPUs
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index out of range"));
+            }
+
+            libc::CPU_SET(cpu, &mut set);
+        }
+
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+// A thread that blocks in `io_getevents`, or `io_pgetevents` if it accepts signals while
+// waiting, and hands completions to the requests in flight, for
+// contexts that are not driven by a reactor. Stopping the thread submits a request that
+// completes right away, so that the thread wakes up without the context being torn down
+// underneath it.
+pub(crate) struct Reaper {
+    // the queue for submitting the wake-up request
+    wake_up: SubmissionQueue,
+
+    // set once the thread is to exit
+    stop: sync::Arc<AtomicBool>,
+
+    // the file read by the wake-up request
+    null: fs::File,
+
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl fmt::Debug for Reaper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = self.thread.as_ref().and_then(|thread| thread.thread().name());
+        write!(f, "Reaper {{ thread: {:?} }}", name)
+    }
+}
+
+impl Reaper {
+    // Start the thread for the given context, once it has been pinned to its CPUs
+    pub(crate) fn spawn(
+        mut completions: CompletionQueue,
+        wake_up: SubmissionQueue,
+        capacity: sync::Arc<Capacity>,
+        nr: usize,
+        config: &ReaperConfig,
+    ) -> io::Result<Reaper> {
+        let null = fs::File::open("/dev/null")?;
+        let stop = sync::Arc::new(AtomicBool::new(false));
+        let (started, outcome) = sync::mpsc::channel();
+
+        let name = config.name.clone().unwrap_or_else(|| "aio-reaper".to_string());
+        let cpus = config.cpu_affinity.clone();
+        let wait_signals = config.wait_signals;
+        let (on_interrupt, timeout) = (config.on_interrupt.clone(), config.wait_timeout);
+        let thread_stop = stop.clone();
+
+        let thread = thread::Builder::new().name(name).spawn(move || {
+            let setup = if cpus.is_empty() { Ok(()) } else { set_affinity(&cpus) };
+
+            // block the signals accepted while waiting, and unblock them only for the wait
+            let setup = setup.and_then(|_| {
+                if wait_signals.is_empty() {
+                    return Ok(None);
+                }
+
+                let mut sigmask = wait_signals.block()?;
+
+                for signal in wait_signals.signals() {
+                    sigmask.remove(signal);
+                }
+
+                Ok(Some(sigmask))
+            });
+
+            let wait = match setup {
+                Ok(sigmask) => {
+                    let _ = started.send(Ok(()));
+                    Wait { sigmask, on_interrupt, timeout }
+                }
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                    return;
+                }
+            };
+
+            Reaper::run(&mut completions, &capacity, nr, &thread_stop, &wait);
+        })?;
+
+        if let Err(err) = outcome.recv().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "reaper thread failed"))) {
+            let _ = thread.join();
+            return Err(err);
+        }
+
+        Ok(Reaper {
+            wake_up,
+            stop,
+            null,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(completions: &mut CompletionQueue, capacity: &Capacity, nr: usize, stop: &AtomicBool, wait: &Wait) {
+        let mut events = Vec::with_capacity(nr);
+
+        // the thread exits only once stopped; the requests in flight until then complete through
+        // the kernel, and whatever remains is released once the kernel context is destroyed
+        while !stop.load(Ordering::Acquire) {
+            events.clear();
+
+            if let Err(error) = completions.retrieve(1, nr, wait.timeout, wait.sigmask.as_ref(), &mut events) {
+                if error.kind() == io::ErrorKind::Interrupted {
+                    if let Some(ref on_interrupt) = wait.on_interrupt {
+                        on_interrupt();
+                    }
+                } else {
+                    println!("WARN: failed to retrieve AIO completions: {}", error);
+                    thread::sleep(RETRY_WAIT);
+                }
+
+                continue;
+            }
+
+            events.retain(|event| event.tag != WAKE_UP);
+            capacity.complete_all(&events, |_, _| {});
+        }
+    }
+
+    // Stop the thread and wait for it to exit; the context is still alive at this point
+    pub(crate) fn stop(&mut self) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
+
+        self.stop.store(true, Ordering::Release);
+
+        // an empty read, which references no memory
+        let request = IocbBuilder::pread(self.null.as_raw_fd(), 0, ptr::null_mut(), 0);
+        let queued = unsafe { self.wake_up.push(WAKE_UP, &request) };
+        debug_assert!(queued);
+
+        match self.wake_up.submit() {
+            Ok(1) => {
+                let _ = thread.join();
+            }
+
+            // without the wake-up request, the thread only exits once a completion or the timeout
+            // ends its wait; until then, its queue keeps the kernel context alive
+            Ok(_) => println!("WARN: failed to wake up the AIO reaper thread"),
+            Err(rejected) => println!("WARN: failed to wake up the AIO reaper thread: {}", rejected.error),
+        }
+    }
+}
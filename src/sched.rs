@@ -0,0 +1,1571 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::cmp;
+use std::collections;
+use std::fmt;
+use std::sync;
+use std::time;
+
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use fnv;
+use futures;
+use parking_lot;
+
+use adaptive::Controller;
+use rate::TokenBucket;
+use sync::{Semaphore, SemaphorePermit};
+use timer::{Delay, Timer};
+use AioStats;
+
+// -----------------------------------------------------------------------------------------------
+// Request classification
+// -----------------------------------------------------------------------------------------------
+
+/// Scheduling priority of a request. When the submission slots of a context are exhausted,
+/// waiting requests are admitted according to their priority and the `PriorityPolicy` of the
+/// context.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Latency-sensitive foreground requests
+    High,
+
+    /// Regular requests; this is the default
+    Normal,
+
+    /// Background requests such as scans or compactions
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    // index of the waiting lane for this priority
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+// Number of priority lanes
+const NUM_LANES: usize = 3;
+
+/// How the waiting lanes for the different priorities are served.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PriorityPolicy {
+    /// Always admit a waiting request of the highest priority first. Lower priorities may starve
+    /// as long as higher priority requests keep arriving. This is the default.
+    Strict,
+
+    /// Admit requests in weighted round-robin fashion; the weights of `High`, `Normal` and `Low`
+    /// priority requests determine how many requests of each lane are admitted per round.
+    Weighted([u32; NUM_LANES]),
+}
+
+impl Default for PriorityPolicy {
+    fn default() -> PriorityPolicy {
+        PriorityPolicy::Strict
+    }
+}
+
+/// Statistics on the waiting lane of one priority, as part of `AioStats`.
+#[derive(Clone, Debug, Default)]
+pub struct LaneStats {
+    /// The number of requests currently waiting in this lane
+    pub waiting: usize,
+
+    /// The number of requests admitted from this lane
+    pub admitted: u64,
+
+    /// The accumulated time requests of this lane have spent waiting for admission
+    pub total_wait: time::Duration,
+
+    /// The longest time a request of this lane has spent waiting for admission
+    pub max_wait: time::Duration,
+
+    /// The number of times a request of higher priority has been admitted while requests were
+    /// waiting in this lane
+    pub passed_over: u64,
+}
+
+/// Classes of operations, which can be given separate queue-depth limits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    /// Reads
+    Read,
+
+    /// Writes without synchronization requirements
+    Write,
+
+    /// Syncs, and writes with a synchronization level other than `SyncLevel::None`
+    Sync,
+}
+
+impl Default for OpClass {
+    fn default() -> OpClass {
+        OpClass::Read
+    }
+}
+
+impl OpClass {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Number of operation classes
+const NUM_CLASSES: usize = 3;
+
+const CLASSES: [OpClass; NUM_CLASSES] = [OpClass::Read, OpClass::Write, OpClass::Sync];
+
+/// Queue-depth limits of an operation class, on top of the submission slots of the context.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassLimit {
+    /// The number of submission slots reserved for the class, which requests of other classes
+    /// cannot take
+    pub reserved: usize,
+
+    /// The maximum number of requests of the class in flight, if limited
+    pub max: Option<usize>,
+}
+
+/// Statistics on an operation class, as part of `AioStats`.
+#[derive(Clone, Debug)]
+pub struct ClassStats {
+    /// The operation class
+    pub class: OpClass,
+
+    /// The configured limits of the class
+    pub limit: ClassLimit,
+
+    /// The number of admitted requests of the class that have not completed yet
+    pub in_flight: usize,
+
+    /// The number of requests of the class waiting for a submission slot
+    pub waiting: usize,
+}
+
+// Information on a request that is relevant to scheduling decisions
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestInfo {
+    // the priority of the request
+    pub(crate) priority: Priority,
+
+    // the tenant issuing the request
+    pub(crate) tenant: u32,
+
+    // the class of the operation
+    pub(crate) class: OpClass,
+
+    // the number of bytes transferred by the request
+    pub(crate) len: u64,
+
+    // the point in time by which the request needs to be submitted, if any
+    pub(crate) deadline: Option<time::Instant>,
+}
+
+impl RequestInfo {
+    // has the deadline of the request passed?
+    pub(crate) fn expired(&self, now: time::Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Waiting requests
+// -----------------------------------------------------------------------------------------------
+
+// A request waiting for a submission slot. Slots are handed over by the scheduler while holding
+// its lock; the waiter merely picks them up.
+pub(crate) struct Waiter {
+    // identifies the waiter within the queue
+    id: u64,
+
+    // scheduling information
+    info: RequestInfo,
+
+    // time of arrival
+    enqueued: time::Instant,
+
+    // the slot permit handed over to the waiter
+    permit: parking_lot::Mutex<Option<SemaphorePermit>>,
+
+    // set once a permit has been handed over
+    granted: AtomicBool,
+
+    // set if the deadline of the request passed before it could be admitted
+    expired: AtomicBool,
+
+    // set once the caps of the tenant have held back the request
+    throttled: AtomicBool,
+
+    // the task to notify upon handoff
+    task: futures::task::AtomicTask,
+}
+
+// Predicate determining which waiting requests may be admitted at this point
+pub(crate) type Eligible<'a> = &'a dyn Fn(&RequestInfo) -> bool;
+
+// Remove the first eligible request of a queue
+fn take_first(queue: &mut collections::VecDeque<sync::Arc<Waiter>>, eligible: Eligible) -> Option<sync::Arc<Waiter>> {
+    let index = queue.iter().position(|waiter| eligible(&waiter.info))?;
+    queue.remove(index)
+}
+
+// A queueing discipline determines the order in which waiting requests are admitted.
+pub(crate) trait Discipline: Send {
+    // Add a waiting request
+    fn push(&mut self, waiter: sync::Arc<Waiter>);
+
+    // Remove the request to be admitted next among the eligible ones
+    fn pop(&mut self, now: time::Instant, eligible: Eligible) -> Option<sync::Arc<Waiter>>;
+
+    // Whether a request may be admitted without queueing while nobody is waiting, in which case
+    // the discipline does not see it at all
+    fn admits_directly(&self) -> bool {
+        false
+    }
+
+    // Remove a request that is no longer interested in admission; returns false if the request
+    // is not waiting
+    fn remove(&mut self, id: u64) -> bool;
+
+    // The number of waiting requests
+    fn len(&self) -> usize;
+
+    // Add discipline-specific information to the statistics of the context
+    fn report(&self, stats: &mut AioStats);
+
+    // The earliest time at which a request that is currently held back may become eligible for
+    // admission
+    fn next_eligible(&mut self, _now: time::Instant) -> Option<time::Instant> {
+        None
+    }
+
+    // Notification that a request admitted by this discipline has completed
+    fn completed(&mut self, _info: &RequestInfo) {}
+}
+
+// -----------------------------------------------------------------------------------------------
+// Priority lanes
+// -----------------------------------------------------------------------------------------------
+
+// One FIFO lane per priority, served by strict priority or weighted round-robin
+pub(crate) struct PriorityLanes {
+    policy: PriorityPolicy,
+    lanes: [collections::VecDeque<sync::Arc<Waiter>>; NUM_LANES],
+    credits: [u32; NUM_LANES],
+    stats: [LaneStats; NUM_LANES],
+}
+
+impl PriorityLanes {
+    pub(crate) fn new(policy: PriorityPolicy) -> PriorityLanes {
+        PriorityLanes {
+            policy,
+            lanes: Default::default(),
+            credits: [0; NUM_LANES],
+            stats: Default::default(),
+        }
+    }
+
+    // Determine the lane to admit the next request from
+    fn select(&mut self, eligible: Eligible) -> Option<usize> {
+        let lanes = &self.lanes;
+        let mut waiting =
+            (0..NUM_LANES).filter(|&lane| lanes[lane].iter().any(|waiter| eligible(&waiter.info)));
+
+        match self.policy {
+            PriorityPolicy::Strict => waiting.next(),
+            PriorityPolicy::Weighted(weights) => {
+                let waiting: Vec<usize> = waiting.collect();
+
+                if waiting.is_empty() {
+                    return None;
+                }
+
+                // start a new round once the lanes with waiters have used up their credits
+                if waiting.iter().all(|&lane| self.credits[lane] == 0) {
+                    for (credit, weight) in self.credits.iter_mut().zip(weights.iter()) {
+                        *credit = (*weight).max(1);
+                    }
+                }
+
+                let lane = *waiting.iter().find(|&&lane| self.credits[lane] > 0).unwrap();
+                self.credits[lane] -= 1;
+                Some(lane)
+            }
+        }
+    }
+}
+
+impl Discipline for PriorityLanes {
+    fn push(&mut self, waiter: sync::Arc<Waiter>) {
+        self.lanes[waiter.info.priority.lane()].push_back(waiter);
+    }
+
+    fn pop(&mut self, now: time::Instant, eligible: Eligible) -> Option<sync::Arc<Waiter>> {
+        let lane = self.select(eligible)?;
+        let waiter = take_first(&mut self.lanes[lane], eligible).unwrap();
+
+        for lower in lane + 1..NUM_LANES {
+            if !self.lanes[lower].is_empty() {
+                self.stats[lower].passed_over += 1;
+            }
+        }
+
+        let wait = now.duration_since(waiter.enqueued);
+        let stats = &mut self.stats[lane];
+        stats.admitted += 1;
+        stats.total_wait += wait;
+        stats.max_wait = stats.max_wait.max(wait);
+
+        Some(waiter)
+    }
+
+    fn admits_directly(&self) -> bool {
+        true
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        for lane in self.lanes.iter_mut() {
+            if let Some(index) = lane.iter().position(|waiter| waiter.id == id) {
+                lane.remove(index);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn len(&self) -> usize {
+        self.lanes.iter().map(collections::VecDeque::len).sum()
+    }
+
+    fn report(&self, stats: &mut AioStats) {
+        stats.lanes = (0..NUM_LANES)
+            .map(|lane| LaneStats {
+                waiting: self.lanes[lane].len(),
+                ..self.stats[lane].clone()
+            })
+            .collect();
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Weighted fair queuing
+// -----------------------------------------------------------------------------------------------
+
+/// Cost in bytes charged per request under fair queuing unless configured otherwise via
+/// `AioContextBuilder::fair_queuing`.
+pub const DEFAULT_REQUEST_COST: u64 = 64 * 1024;
+
+/// Configuration of a tenant sharing a context under weighted fair queuing.
+#[derive(Clone, Debug)]
+pub struct TenantConfig {
+    /// The share of the context the tenant is entitled to, relative to the other tenants
+    pub weight: u32,
+
+    /// Optional cap on the number of requests per second admitted for the tenant
+    pub max_iops: Option<u64>,
+
+    /// Optional cap on the number of bytes per second admitted for the tenant
+    pub max_bandwidth: Option<u64>,
+}
+
+impl Default for TenantConfig {
+    fn default() -> TenantConfig {
+        TenantConfig {
+            weight: 1,
+            max_iops: None,
+            max_bandwidth: None,
+        }
+    }
+}
+
+/// Statistics on a tenant under weighted fair queuing, as part of `AioStats`.
+#[derive(Clone, Debug, Default)]
+pub struct TenantStats {
+    /// The tenant identifier
+    pub tenant: u32,
+
+    /// The number of requests of the tenant currently waiting for admission
+    pub waiting: usize,
+
+    /// The number of admitted requests of the tenant that have not completed yet
+    pub in_flight: usize,
+
+    /// The number of requests admitted for the tenant
+    pub admitted: u64,
+
+    /// The number of bytes transferred by the requests admitted for the tenant
+    pub bytes_admitted: u64,
+
+    /// The accumulated time requests of the tenant have spent waiting for admission
+    pub total_wait: time::Duration,
+
+    /// The longest time a request of the tenant has spent waiting for admission
+    pub max_wait: time::Duration,
+
+    /// The number of requests of the tenant that have been held back because it exceeded its caps
+    pub throttled: u64,
+}
+
+// fixed-point scale of the virtual time, so that large weights still make progress
+const VIRTUAL_TIME_SCALE: u64 = 1 << 16;
+
+// The waiting requests and accounting of a single tenant
+struct Tenant {
+    // the weight of the tenant
+    weight: u64,
+
+    // waiting requests by priority
+    lanes: [collections::VecDeque<sync::Arc<Waiter>>; NUM_LANES],
+
+    // virtual finish time of the request admitted last
+    finish: u64,
+
+    // rate caps
+    iops: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+
+    stats: TenantStats,
+}
+
+impl Tenant {
+    fn new(tenant: u32, config: &TenantConfig, now: time::Instant) -> Tenant {
+        // the caps allow bursts of up to one second worth of requests
+        Tenant {
+            weight: u64::from(config.weight.max(1)),
+            lanes: Default::default(),
+            finish: 0,
+            iops: config.max_iops.map(|rate| TokenBucket::new(rate, rate, now)),
+            bandwidth: config.max_bandwidth.map(|rate| TokenBucket::new(rate, rate, now)),
+            stats: TenantStats {
+                tenant,
+                ..Default::default()
+            },
+        }
+    }
+
+    // the request of the tenant to be admitted next
+    fn head(&self, eligible: Eligible) -> Option<&sync::Arc<Waiter>> {
+        self.lanes
+            .iter()
+            .filter_map(|lane| lane.iter().find(|waiter| eligible(&waiter.info)))
+            .next()
+    }
+
+    // when the head request may be admitted under the caps of the tenant; None if right away
+    fn ready_at(&mut self, now: time::Instant, eligible: Eligible) -> Option<time::Instant> {
+        let len = self.head(eligible)?.info.len;
+        let iops = self.iops.as_mut().and_then(|bucket| bucket.ready_at(1, now));
+        let bandwidth = self.bandwidth.as_mut().and_then(|bucket| bucket.ready_at(len, now));
+        cmp::max(iops, bandwidth)
+    }
+}
+
+// Start-time fair queuing between tenants: each tenant advances a virtual clock by the cost of
+// its admitted requests divided by its weight, and the tenant with the earliest virtual start
+// time is served next. Tenants exceeding their rate caps are skipped until they are eligible.
+pub(crate) struct FairQueue {
+    // cost charged per request on top of the transfer size
+    request_cost: u64,
+
+    // explicitly configured tenants
+    configs: fnv::FnvHashMap<u32, TenantConfig>,
+
+    // tenants that have issued requests, ordered by identifier
+    tenants: collections::BTreeMap<u32, Tenant>,
+
+    // virtual start time of the request admitted last
+    virtual_time: u64,
+
+    // the number of waiting requests across tenants
+    waiting: usize,
+}
+
+impl FairQueue {
+    pub(crate) fn new(request_cost: u64, configs: fnv::FnvHashMap<u32, TenantConfig>) -> FairQueue {
+        FairQueue {
+            request_cost,
+            configs,
+            tenants: collections::BTreeMap::new(),
+            virtual_time: 0,
+            waiting: 0,
+        }
+    }
+}
+
+impl Discipline for FairQueue {
+    fn push(&mut self, waiter: sync::Arc<Waiter>) {
+        let id = waiter.info.tenant;
+        let configs = &self.configs;
+
+        let tenant = self.tenants.entry(id).or_insert_with(|| {
+            let default = TenantConfig::default();
+            Tenant::new(id, configs.get(&id).unwrap_or(&default), waiter.enqueued)
+        });
+
+        tenant.lanes[waiter.info.priority.lane()].push_back(waiter);
+        self.waiting += 1;
+    }
+
+    fn pop(&mut self, now: time::Instant, eligible: Eligible) -> Option<sync::Arc<Waiter>> {
+        let mut next: Option<(u64, u32)> = None;
+
+        for (&id, tenant) in self.tenants.iter_mut() {
+            if tenant.head(eligible).is_none() {
+                continue;
+            }
+
+            if tenant.ready_at(now, eligible).is_some() {
+                // the tenant is scanned on every dispatch, but each request counts once
+                let held = tenant.head(eligible).unwrap();
+
+                if !held.throttled.swap(true, Ordering::Relaxed) {
+                    tenant.stats.throttled += 1;
+                }
+
+                continue;
+            }
+
+            let start = cmp::max(self.virtual_time, tenant.finish);
+
+            if next.map_or(true, |(earliest, _)| start < earliest) {
+                next = Some((start, id));
+            }
+        }
+
+        let (start, id) = next?;
+        let tenant = self.tenants.get_mut(&id).unwrap();
+        let waiter = tenant
+            .lanes
+            .iter_mut()
+            .filter_map(|lane| take_first(lane, eligible))
+            .next()
+            .unwrap();
+
+        let len = waiter.info.len;
+        let cost = self.request_cost + len;
+
+        self.virtual_time = start;
+        self.waiting -= 1;
+        tenant.finish = start + cost.saturating_mul(VIRTUAL_TIME_SCALE) / tenant.weight;
+
+        if let Some(ref mut bucket) = tenant.iops {
+            bucket.take(1);
+        }
+
+        if let Some(ref mut bucket) = tenant.bandwidth {
+            bucket.take(len);
+        }
+
+        let wait = now.duration_since(waiter.enqueued);
+        let stats = &mut tenant.stats;
+        stats.in_flight += 1;
+        stats.admitted += 1;
+        stats.bytes_admitted += len;
+        stats.total_wait += wait;
+        stats.max_wait = stats.max_wait.max(wait);
+
+        Some(waiter)
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        for tenant in self.tenants.values_mut() {
+            for lane in tenant.lanes.iter_mut() {
+                if let Some(index) = lane.iter().position(|waiter| waiter.id == id) {
+                    lane.remove(index);
+                    self.waiting -= 1;
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    fn len(&self) -> usize {
+        self.waiting
+    }
+
+    fn report(&self, stats: &mut AioStats) {
+        stats.tenants = self
+            .tenants
+            .values()
+            .map(|tenant| TenantStats {
+                waiting: tenant.lanes.iter().map(collections::VecDeque::len).sum(),
+                ..tenant.stats.clone()
+            })
+            .collect();
+    }
+
+    fn next_eligible(&mut self, now: time::Instant) -> Option<time::Instant> {
+        self.tenants
+            .values_mut()
+            .filter_map(|tenant| tenant.ready_at(now, &|_| true))
+            .min()
+    }
+
+    fn completed(&mut self, info: &RequestInfo) {
+        if let Some(tenant) = self.tenants.get_mut(&info.tenant) {
+            tenant.stats.in_flight -= 1;
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Earliest deadline first
+// -----------------------------------------------------------------------------------------------
+
+// Ordering key for deadline scheduling: requests with a deadline come first, ordered by deadline;
+// requests without one follow in order of arrival
+type DeadlineKey = (bool, Option<time::Instant>, u64);
+
+// Waiting requests ordered by deadline
+pub(crate) struct DeadlineQueue {
+    queue: collections::BTreeMap<DeadlineKey, sync::Arc<Waiter>>,
+
+    // ordering keys of the waiting requests
+    keys: fnv::FnvHashMap<u64, DeadlineKey>,
+}
+
+impl DeadlineQueue {
+    pub(crate) fn new() -> DeadlineQueue {
+        DeadlineQueue {
+            queue: collections::BTreeMap::new(),
+            keys: fnv::FnvHashMap::default(),
+        }
+    }
+}
+
+impl Discipline for DeadlineQueue {
+    fn push(&mut self, waiter: sync::Arc<Waiter>) {
+        let key = (waiter.info.deadline.is_none(), waiter.info.deadline, waiter.id);
+        self.keys.insert(waiter.id, key);
+        self.queue.insert(key, waiter);
+    }
+
+    fn pop(&mut self, _now: time::Instant, eligible: Eligible) -> Option<sync::Arc<Waiter>> {
+        let key = *self.queue.iter().find(|&(_, waiter)| eligible(&waiter.info))?.0;
+        let waiter = self.queue.remove(&key).unwrap();
+        self.keys.remove(&waiter.id);
+        Some(waiter)
+    }
+
+    fn admits_directly(&self) -> bool {
+        true
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        match self.keys.remove(&id) {
+            Some(key) => self.queue.remove(&key).is_some(),
+            None => false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn report(&self, _stats: &mut AioStats) {}
+}
+
+// -----------------------------------------------------------------------------------------------
+// Scheduler
+// -----------------------------------------------------------------------------------------------
+
+// Queue-depth accounting of an operation class; the requests in flight are counted outside
+// the lock
+#[derive(Clone, Debug, Default)]
+struct ClassState {
+    limit: ClassLimit,
+    waiting: usize,
+}
+
+struct SchedulerState {
+    // the waiting requests
+    queue: Box<dyn Discipline>,
+
+    // accounting per operation class
+    classes: [ClassState; NUM_CLASSES],
+
+    // adaptive limit on the requests in flight, if enabled
+    controller: Option<Controller>,
+
+    // identifier for the next waiter
+    next_id: u64,
+
+    // the earliest pending timer wakeup
+    wakeup: Option<time::Instant>,
+}
+
+// User-space admission control in front of the submission slots of a context. Requests wait
+// in the queueing discipline of the scheduler, which hands over slot permits as they become
+// available.
+//
+// The queue and the state of the discipline are kept under a lock. As long as nobody is waiting,
+// a request is admitted and released without taking it, unless the discipline, class limits or
+// an adaptive limit need to see the request. A request joins the queue only after publishing
+// that it waits and trying for a slot once more, while a completion checks for waiting requests
+// after returning its slot, so that either of them sees the other.
+pub(crate) struct Scheduler {
+    // the semaphore guarding the submission slots
+    slots: Semaphore,
+
+    state: parking_lot::Mutex<SchedulerState>,
+
+    // whether requests are admitted and released without the lock while nobody is waiting
+    direct: bool,
+
+    // the number of requests in the queue; only changed under the lock
+    queued: AtomicUsize,
+
+    // the number of admitted requests per operation class that have not completed yet
+    in_flight: [AtomicUsize; NUM_CLASSES],
+
+    // the number of requests per priority admitted without queueing
+    admitted_directly: [AtomicU64; NUM_LANES],
+
+    // wakes the scheduler when requests held back by the discipline become eligible
+    timer: sync::Arc<Timer>,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Scheduler {{ waiting: {} }}", self.waiting())
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        slots: Semaphore,
+        queue: Box<dyn Discipline>,
+        limits: [ClassLimit; NUM_CLASSES],
+        controller: Option<Controller>,
+        timer: sync::Arc<Timer>,
+    ) -> Scheduler {
+        let mut classes: [ClassState; NUM_CLASSES] = Default::default();
+
+        for (class, limit) in classes.iter_mut().zip(limits.iter()) {
+            class.limit = *limit;
+        }
+
+        let unlimited = limits.iter().all(|limit| *limit == ClassLimit::default());
+        let direct = queue.admits_directly() && unlimited && controller.is_none();
+
+        Scheduler {
+            slots,
+            state: parking_lot::Mutex::new(SchedulerState {
+                queue,
+                classes,
+                controller,
+                next_id: 0,
+                wakeup: None,
+            }),
+            direct,
+            queued: AtomicUsize::new(0),
+            in_flight: Default::default(),
+            admitted_directly: Default::default(),
+            timer,
+        }
+    }
+
+    // Request admission for a request with the given scheduling information
+    pub(crate) fn admit(scheduler: &sync::Arc<Scheduler>, info: RequestInfo) -> Admission {
+        Admission {
+            scheduler: scheduler.clone(),
+            info: Some(info),
+            waiter: None,
+            expiry: None,
+        }
+    }
+
+    // Return the slot permit of a completed request and admit waiting requests
+    pub(crate) fn release(scheduler: &sync::Arc<Scheduler>, permit: SemaphorePermit, info: &RequestInfo) {
+        drop(permit);
+        scheduler.in_flight[info.class.index()].fetch_sub(1, Ordering::Relaxed);
+
+        if scheduler.direct {
+            // pairs with the fence of a request joining the queue
+            atomic::fence(Ordering::SeqCst);
+
+            if scheduler.queued.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+        }
+
+        let mut state = scheduler.state.lock();
+        state.queue.completed(info);
+        Scheduler::dispatch(scheduler, &mut state);
+    }
+
+    // The timer waking up requests that wait for their turn
+    pub(crate) fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    // The number of submission slots that are currently available
+    #[cfg(test)]
+    pub(crate) fn available_slots(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    // The number of requests waiting for admission
+    pub(crate) fn waiting(&self) -> usize {
+        self.state.lock().queue.len()
+    }
+
+    // Feed the latency of a completed request to the adaptive controller, if any. This is called
+    // before the slot of the request is released, which admits waiting requests under the
+    // adjusted limit.
+    pub(crate) fn observe(&self, latency: time::Duration) {
+        let in_flight = self.total_in_flight();
+        let mut state = self.state.lock();
+
+        if let Some(ref mut controller) = state.controller {
+            controller.record(latency, in_flight);
+        }
+    }
+
+    // Add information on the waiting requests to the statistics of the context
+    pub(crate) fn report(&self, stats: &mut AioStats) {
+        let state = self.state.lock();
+        state.queue.report(stats);
+        stats.concurrency = state.controller.as_ref().map(Controller::stats);
+
+        for (lane, admitted) in stats.lanes.iter_mut().zip(self.admitted_directly.iter()) {
+            lane.admitted += admitted.load(Ordering::Relaxed);
+        }
+
+        stats.classes = CLASSES
+            .iter()
+            .map(|&class| {
+                let accounting = &state.classes[class.index()];
+
+                ClassStats {
+                    class,
+                    limit: accounting.limit,
+                    in_flight: self.in_flight[class.index()].load(Ordering::Relaxed),
+                    waiting: accounting.waiting,
+                }
+            })
+            .collect();
+    }
+
+    // The number of slots that remain available after handing out the current one; only needed,
+    // and thus only determined, if operation classes have slots reserved
+    fn slots_left(&self, state: &SchedulerState) -> usize {
+        if state.classes.iter().any(|class| class.limit.reserved > 0) {
+            self.slots.available_permits()
+        } else {
+            0
+        }
+    }
+
+    // The number of admitted requests that have not completed yet
+    fn total_in_flight(&self) -> usize {
+        self.in_flight.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    // Is the adaptive limit on the requests in flight reached?
+    fn at_limit(&self, state: &SchedulerState) -> bool {
+        state.controller.as_ref().map_or(false, |controller| self.total_in_flight() >= controller.limit())
+    }
+
+    // Count a request that is admitted without queueing
+    fn admitted(&self, info: &RequestInfo) {
+        self.in_flight[info.class.index()].fetch_add(1, Ordering::Relaxed);
+        self.admitted_directly[info.priority.lane()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Determine which operation classes may take the slot about to be handed out, given the
+    // number of slots that remain available afterwards
+    fn admissible(&self, classes: &[ClassState; NUM_CLASSES], available: usize) -> [bool; NUM_CLASSES] {
+        let in_flight: Vec<usize> = self.in_flight.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+
+        // slots reserved for classes that have not used up their reservation
+        let mut outstanding = [0; NUM_CLASSES];
+
+        for (index, class) in classes.iter().enumerate() {
+            outstanding[index] = class.limit.reserved.saturating_sub(in_flight[index]);
+        }
+
+        let total: usize = outstanding.iter().sum();
+
+        let mut admissible = [false; NUM_CLASSES];
+
+        for (index, class) in classes.iter().enumerate() {
+            let below_max = class.limit.max.map_or(true, |max| in_flight[index] < max);
+            let reserved_for_others = total - outstanding[index];
+            admissible[index] = below_max && available >= reserved_for_others;
+        }
+
+        admissible
+    }
+
+    // Admit a request right away if a slot is available to its class; the caller ensures that no
+    // other request is waiting
+    fn admit_now(&self, state: &mut SchedulerState, info: &RequestInfo) -> Option<SemaphorePermit> {
+        if !state.queue.admits_directly() || self.at_limit(state) {
+            return None;
+        }
+
+        let permit = self.slots.try_acquire().ok()?;
+        let admissible = self.admissible(&state.classes, self.slots_left(state));
+
+        if !admissible[info.class.index()] {
+            return None;
+        }
+
+        self.admitted(info);
+        Some(permit)
+    }
+
+    // Remove a request from the queue; returns false if it is no longer waiting
+    fn withdraw(&self, waiter: &Waiter) -> bool {
+        let mut state = self.state.lock();
+        let removed = state.queue.remove(waiter.id);
+
+        if removed {
+            state.classes[waiter.info.class.index()].waiting -= 1;
+            self.queued.store(state.queue.len(), Ordering::SeqCst);
+        }
+
+        removed
+    }
+
+    // Hand over available slots to waiting requests
+    fn dispatch(scheduler: &sync::Arc<Scheduler>, state: &mut SchedulerState) {
+        let now = time::Instant::now();
+
+        // publish the requests that wait before looking for slots; pairs with the fence of a
+        // completion that returns a slot without the lock
+        scheduler.queued.store(state.queue.len(), Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+
+        let mut permit = None;
+
+        while state.queue.len() > 0 {
+            if permit.is_none() {
+                match scheduler.slots.try_acquire() {
+                    Ok(acquired) => permit = Some(acquired),
+                    Err(_) => break,
+                }
+            }
+
+            if scheduler.at_limit(state) {
+                break;
+            }
+
+            let admissible = scheduler.admissible(&state.classes, scheduler.slots_left(state));
+
+            if !admissible.iter().any(|&admissible| admissible) {
+                break;
+            }
+
+            match state.queue.pop(now, &|info| admissible[info.class.index()]) {
+                Some(ref waiter) if waiter.info.expired(now) => {
+                    // too late; the slot goes to the next request
+                    state.queue.completed(&waiter.info);
+                    state.classes[waiter.info.class.index()].waiting -= 1;
+                    waiter.expired.store(true, Ordering::Release);
+                    waiter.task.notify();
+                }
+                Some(waiter) => {
+                    state.classes[waiter.info.class.index()].waiting -= 1;
+                    scheduler.in_flight[waiter.info.class.index()].fetch_add(1, Ordering::Relaxed);
+
+                    *waiter.permit.lock() = permit.take();
+                    waiter.granted.store(true, Ordering::Release);
+                    waiter.task.notify();
+                }
+                None => {
+                    // everybody waiting is held back; come back once somebody becomes eligible
+                    if let Some(at) = state.queue.next_eligible(now) {
+                        if state.wakeup.map_or(true, |wakeup| at < wakeup) {
+                            let weak = sync::Arc::downgrade(scheduler);
+                            state.wakeup = Some(at);
+
+                            scheduler.timer.schedule(at, move || {
+                                if let Some(scheduler) = weak.upgrade() {
+                                    let mut state = scheduler.state.lock();
+
+                                    if state.wakeup == Some(at) {
+                                        state.wakeup = None;
+                                    }
+
+                                    Scheduler::dispatch(&scheduler, &mut state);
+                                }
+                            });
+                        }
+                    }
+
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Future resolving to a slot permit once the scheduler admits the request.
+pub(crate) struct Admission {
+    scheduler: sync::Arc<Scheduler>,
+
+    // scheduling information, until we have joined the queue
+    info: Option<RequestInfo>,
+
+    // our entry in the queue
+    waiter: Option<sync::Arc<Waiter>>,
+
+    // wakes us up when the deadline passes while we are waiting
+    expiry: Option<Delay>,
+}
+
+impl futures::Future for Admission {
+    type Item = SemaphorePermit;
+
+    // the deadline of the request passed before it was admitted
+    type Error = ();
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        if let Some(info) = self.info.take() {
+            // without competition, there is no need to queue up or to take the lock
+            if self.scheduler.direct && self.scheduler.queued.load(Ordering::SeqCst) == 0 {
+                if let Ok(permit) = self.scheduler.slots.try_acquire() {
+                    self.scheduler.admitted(&info);
+                    return Ok(futures::Async::Ready(permit));
+                }
+            }
+
+            let mut state = self.scheduler.state.lock();
+
+            // without competition, there is no need to queue up either if class limits or an
+            // adaptive limit allow for the request
+            if state.queue.len() == 0 {
+                if let Some(permit) = self.scheduler.admit_now(&mut state, &info) {
+                    return Ok(futures::Async::Ready(permit));
+                }
+            }
+
+            let waiter = sync::Arc::new(Waiter {
+                id: state.next_id,
+                info,
+                enqueued: time::Instant::now(),
+                permit: parking_lot::Mutex::new(None),
+                granted: AtomicBool::new(false),
+                expired: AtomicBool::new(false),
+                throttled: AtomicBool::new(false),
+                task: futures::task::AtomicTask::new(),
+            });
+
+            state.next_id += 1;
+            state.classes[waiter.info.class.index()].waiting += 1;
+            waiter.task.register();
+            state.queue.push(waiter.clone());
+            self.waiter = Some(waiter);
+
+            Scheduler::dispatch(&self.scheduler, &mut state);
+        }
+
+        let waiter = self.waiter.clone().unwrap();
+        waiter.task.register();
+
+        if let Some(deadline) = waiter.info.deadline {
+            let timer = &self.scheduler.timer;
+            let expiry = self.expiry.get_or_insert_with(|| Delay::new(deadline));
+
+            if expiry.poll(timer).is_ready() && self.scheduler.withdraw(&waiter) {
+                // the deadline passed while we were waiting
+                self.waiter = None;
+                return Err(());
+            }
+        }
+
+        if waiter.granted.load(Ordering::Acquire) {
+            self.waiter = None;
+            Ok(futures::Async::Ready(waiter.permit.lock().take().unwrap()))
+        } else if waiter.expired.load(Ordering::Acquire) {
+            self.waiter = None;
+            Err(())
+        } else {
+            Ok(futures::Async::NotReady)
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            if !self.scheduler.withdraw(&waiter) && waiter.granted.load(Ordering::Acquire) {
+                // we have been handed a slot that we are never going to use
+                let permit = waiter.permit.lock().take().unwrap();
+                Scheduler::release(&self.scheduler, permit, &waiter.info);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+    use std::thread;
+
+    use futures::executor;
+    use futures::{Async, Future};
+
+    use adaptive::{AdaptiveConcurrency, Decision};
+
+    use super::*;
+
+    struct NoopNotify;
+
+    impl executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    fn poll<F: Future>(future: &mut executor::Spawn<F>) -> Result<Async<F::Item>, F::Error> {
+        future.poll_future_notify(&sync::Arc::new(NoopNotify), 0)
+    }
+
+    fn request(priority: Priority) -> RequestInfo {
+        RequestInfo {
+            priority,
+            ..Default::default()
+        }
+    }
+
+    // Take a slot for a read, bypassing the queue
+    fn occupy(scheduler: &Scheduler) -> SemaphorePermit {
+        let permit = scheduler.slots.try_acquire().unwrap();
+        scheduler.in_flight[OpClass::Read.index()].fetch_add(1, Ordering::Relaxed);
+        permit
+    }
+
+    // Queue up requests behind a single busy slot and record the order of admission
+    fn admission_order(policy: PriorityPolicy, priorities: &[Priority]) -> (Vec<Priority>, AioStats) {
+        let slots = Semaphore::new(1);
+        let scheduler = sync::Arc::new(Scheduler::new(
+            slots.clone(),
+            Box::new(PriorityLanes::new(policy)),
+            Default::default(),
+            None,
+            sync::Arc::new(Timer::new()),
+        ));
+
+        // occupy the slot so that everybody else has to wait
+        let mut permit = occupy(&scheduler);
+
+        let mut waiting: Vec<_> = priorities
+            .iter()
+            .map(|&priority| {
+                let mut admission = executor::spawn(Scheduler::admit(&scheduler, request(priority)));
+                assert!(poll(&mut admission).unwrap().is_not_ready());
+                (priority, admission)
+            })
+            .collect();
+
+        let mut order = Vec::new();
+
+        while !waiting.is_empty() {
+            // complete the request holding the slot, which admits the next one
+            Scheduler::release(&scheduler, permit, &RequestInfo::default());
+
+            let (index, next) = waiting
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(index, &mut (_, ref mut admission))| match poll(admission).unwrap() {
+                    Async::Ready(permit) => Some((index, permit)),
+                    Async::NotReady => None,
+                })
+                .next()
+                .expect("no request admitted");
+
+            order.push(waiting.remove(index).0);
+            permit = next;
+        }
+
+        let mut stats = AioStats::default();
+        scheduler.report(&mut stats);
+        (order, stats)
+    }
+
+    #[test]
+    fn strict_priority() {
+        use self::Priority::*;
+
+        let (order, stats) = admission_order(PriorityPolicy::Strict, &[Low, Normal, High, Low, High]);
+        assert!(order == vec![High, High, Normal, Low, Low]);
+
+        let admitted: Vec<u64> = stats.lanes.iter().map(|lane| lane.admitted).collect();
+        assert!(admitted == vec![2, 1, 2]);
+        assert!(stats.lanes[Normal.lane()].passed_over == 2);
+        assert!(stats.lanes[Low.lane()].passed_over == 3);
+        assert!(stats.lanes.iter().all(|lane| lane.waiting == 0));
+    }
+
+    #[test]
+    fn weighted_priority() {
+        use self::Priority::*;
+
+        let (order, _) = admission_order(
+            PriorityPolicy::Weighted([2, 1, 1]),
+            &[Low, Low, Normal, Normal, High, High, High, High],
+        );
+        assert!(order == vec![High, High, Normal, Low, High, High, Normal, Low]);
+    }
+
+    fn lanes_scheduler(slots: usize) -> sync::Arc<Scheduler> {
+        sync::Arc::new(Scheduler::new(
+            Semaphore::new(slots),
+            Box::new(PriorityLanes::new(PriorityPolicy::Strict)),
+            Default::default(),
+            None,
+            sync::Arc::new(Timer::new()),
+        ))
+    }
+
+    #[test]
+    fn uncontended_admission() {
+        let scheduler = lanes_scheduler(4);
+
+        // requests pass through while somebody else holds the lock
+        let state = scheduler.state.lock();
+        let (sender, receiver) = sync::mpsc::channel();
+
+        let worker = {
+            let scheduler = scheduler.clone();
+
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let info = request(Priority::High);
+                    let permit = executor::spawn(Scheduler::admit(&scheduler, info.clone())).wait_future().unwrap();
+                    Scheduler::release(&scheduler, permit, &info);
+                }
+
+                sender.send(()).unwrap();
+            })
+        };
+
+        assert!(receiver.recv_timeout(time::Duration::from_secs(10)).is_ok());
+        drop(state);
+        worker.join().unwrap();
+
+        let mut stats = AioStats::default();
+        scheduler.report(&mut stats);
+        assert!(stats.lanes[Priority::High.lane()].admitted == 1000);
+        assert!(stats.classes.iter().all(|class| class.in_flight == 0));
+    }
+
+    #[test]
+    fn contended_admission() {
+        let scheduler = lanes_scheduler(2);
+
+        // requests alternate between passing through and queueing up; none of them may be lost
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let info = request(Priority::Normal);
+                        let permit = executor::spawn(Scheduler::admit(&scheduler, info.clone())).wait_future().unwrap();
+                        Scheduler::release(&scheduler, permit, &info);
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(scheduler.slots.available_permits() == 2);
+
+        let mut stats = AioStats::default();
+        scheduler.report(&mut stats);
+        assert!(stats.lanes[Priority::Normal.lane()].admitted == 8 * 2000);
+        assert!(stats.lanes.iter().all(|lane| lane.waiting == 0));
+        assert!(stats.classes.iter().all(|class| class.in_flight == 0 && class.waiting == 0));
+    }
+
+    fn waiter(id: u64, tenant: u32, len: u64, enqueued: time::Instant) -> sync::Arc<Waiter> {
+        let info = RequestInfo {
+            tenant,
+            len,
+            ..Default::default()
+        };
+
+        waiter_for(id, info, enqueued)
+    }
+
+    fn any(_: &RequestInfo) -> bool {
+        true
+    }
+
+    fn waiter_for(id: u64, info: RequestInfo, enqueued: time::Instant) -> sync::Arc<Waiter> {
+        sync::Arc::new(Waiter {
+            id,
+            info,
+            enqueued,
+            permit: parking_lot::Mutex::new(None),
+            granted: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+            throttled: AtomicBool::new(false),
+            task: futures::task::AtomicTask::new(),
+        })
+    }
+
+    #[test]
+    fn fair_share() {
+        let mut configs = fnv::FnvHashMap::default();
+        configs.insert(1, TenantConfig { weight: 3, ..Default::default() });
+        let mut queue = FairQueue::new(DEFAULT_REQUEST_COST, configs);
+
+        let now = time::Instant::now();
+
+        for id in 0..16 {
+            queue.push(waiter(id, 1 + (id % 2) as u32, 4096, now));
+        }
+
+        let admitted: Vec<u32> = (0..8).map(|_| queue.pop(now, &any).unwrap().info.tenant).collect();
+        assert!(admitted.iter().filter(|&&tenant| tenant == 1).count() == 6);
+
+        let mut stats = AioStats::default();
+        queue.report(&mut stats);
+        assert!(stats.tenants.len() == 2);
+        assert!(stats.tenants[0].in_flight == 6 && stats.tenants[0].waiting == 2);
+        assert!(stats.tenants[1].in_flight == 2 && stats.tenants[1].waiting == 6);
+        assert!(stats.tenants[1].bytes_admitted == 2 * 4096);
+    }
+
+    #[test]
+    fn tenant_caps() {
+        let mut configs = fnv::FnvHashMap::default();
+        configs.insert(7, TenantConfig { max_iops: Some(10), ..Default::default() });
+        let mut queue = FairQueue::new(DEFAULT_REQUEST_COST, configs);
+
+        let now = time::Instant::now();
+
+        for id in 0..15 {
+            queue.push(waiter(id, 7, 512, now));
+        }
+
+        // the burst allowance is used up after one second worth of requests
+        for _ in 0..10 {
+            let waiter = queue.pop(now, &any).unwrap();
+            queue.completed(&waiter.info);
+        }
+
+        assert!(queue.pop(now, &any).is_none());
+
+        let eligible = queue.next_eligible(now).unwrap();
+        assert!(eligible > now && eligible <= now + time::Duration::from_millis(101));
+        assert!(queue.pop(eligible, &any).is_some());
+
+        // other tenants are not affected
+        queue.push(waiter(100, 8, 512, now));
+        assert!(queue.pop(eligible, &any).unwrap().info.tenant == 8);
+
+        // scanning the held-back request again does not count it again
+        assert!(queue.pop(eligible, &any).is_none());
+
+        // the requests held back are the 11th and the 12th
+        let mut stats = AioStats::default();
+        queue.report(&mut stats);
+        assert!(stats.tenants[0].throttled == 2);
+        assert!(stats.tenants[0].in_flight == 1);
+    }
+
+    #[test]
+    fn deadline_order() {
+        let now = time::Instant::now();
+        let mut queue = DeadlineQueue::new();
+
+        let deadlines = [Some(30), None, Some(10), Some(20), None];
+
+        for (id, deadline) in deadlines.iter().enumerate() {
+            let info = RequestInfo {
+                deadline: deadline.map(|millis| now + time::Duration::from_millis(millis)),
+                ..Default::default()
+            };
+
+            queue.push(waiter_for(id as u64, info, now));
+        }
+
+        assert!(queue.remove(3));
+        assert!(!queue.remove(3));
+
+        let order: Vec<u64> = (0..4).map(|_| queue.pop(now, &any).unwrap().id).collect();
+        assert!(order == vec![2, 0, 1, 4]);
+        assert!(queue.len() == 0);
+    }
+
+    #[test]
+    fn deadline_expiry() {
+        let slots = Semaphore::new(1);
+        let scheduler = sync::Arc::new(Scheduler::new(
+            slots.clone(),
+            Box::new(DeadlineQueue::new()),
+            Default::default(),
+            None,
+            sync::Arc::new(Timer::new()),
+        ));
+
+        let permit = occupy(&scheduler);
+        let soon = time::Instant::now() + time::Duration::from_millis(20);
+
+        let admit = |deadline| {
+            let info = RequestInfo {
+                deadline,
+                ..Default::default()
+            };
+
+            let mut admission = executor::spawn(Scheduler::admit(&scheduler, info));
+            assert!(poll(&mut admission).unwrap().is_not_ready());
+            admission
+        };
+
+        let mut expiring = admit(Some(soon));
+        let mut skipped = admit(Some(soon));
+        let mut patient = admit(None);
+
+        thread::sleep(time::Duration::from_millis(30));
+
+        // a waiting request notices on its own that it is too late
+        assert!(poll(&mut expiring).is_err());
+
+        // a request that is too late by the time a slot is handed out is skipped
+        Scheduler::release(&scheduler, permit, &RequestInfo::default());
+        assert!(poll(&mut patient).unwrap().is_ready());
+        assert!(poll(&mut skipped).is_err());
+        assert!(scheduler.waiting() == 0);
+    }
+
+    #[test]
+    fn class_limits() {
+        let slots = Semaphore::new(4);
+        let mut limits: [ClassLimit; NUM_CLASSES] = Default::default();
+        limits[OpClass::Write.index()].max = Some(2);
+        limits[OpClass::Sync.index()].reserved = 1;
+
+        let scheduler = sync::Arc::new(Scheduler::new(
+            slots,
+            Box::new(PriorityLanes::new(PriorityPolicy::Strict)),
+            limits,
+            None,
+            sync::Arc::new(Timer::new()),
+        ));
+
+        let admit = |class| {
+            let info = RequestInfo {
+                class,
+                ..Default::default()
+            };
+
+            executor::spawn(Scheduler::admit(&scheduler, info))
+        };
+
+        // writes are capped at two in flight
+        let mut writes: Vec<_> = (0..3).map(|_| admit(OpClass::Write)).collect();
+        let admitted: Vec<_> = writes.iter_mut().map(|write| poll(write).unwrap()).collect();
+        assert!(admitted.iter().filter(|admission| admission.is_ready()).count() == 2);
+
+        // reads cannot take the slot reserved for syncs
+        let mut read = admit(OpClass::Read);
+        let read_permit = poll(&mut read).unwrap();
+        assert!(read_permit.is_ready());
+        let mut blocked_read = admit(OpClass::Read);
+        assert!(poll(&mut blocked_read).unwrap().is_not_ready());
+
+        let mut sync = admit(OpClass::Sync);
+        let sync_permit = match poll(&mut sync).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("sync not admitted"),
+        };
+
+        let mut stats = AioStats::default();
+        scheduler.report(&mut stats);
+        let counts: Vec<_> = stats.classes.iter().map(|class| (class.in_flight, class.waiting)).collect();
+        assert!(counts == vec![(1, 1), (2, 1), (1, 0)]);
+
+        // once the sync completes, the blocked read still waits for the reservation to free up;
+        // the waiting write is capped, so neither is admitted
+        Scheduler::release(&scheduler, sync_permit, &RequestInfo {
+            class: OpClass::Sync,
+            ..Default::default()
+        });
+        assert!(poll(&mut blocked_read).unwrap().is_not_ready());
+        assert!(poll(&mut writes[2]).unwrap().is_not_ready());
+        assert!(scheduler.available_slots() == 1);
+    }
+
+    #[test]
+    fn adaptive_limit() {
+        let mut config = AdaptiveConcurrency::new(time::Duration::from_millis(10));
+        config.window = 2;
+        config.initial_limit = Some(1);
+
+        let scheduler = sync::Arc::new(Scheduler::new(
+            Semaphore::new(4),
+            Box::new(PriorityLanes::new(PriorityPolicy::Strict)),
+            Default::default(),
+            Some(Controller::new(config, 4)),
+            sync::Arc::new(Timer::new()),
+        ));
+
+        let admit = || executor::spawn(Scheduler::admit(&scheduler, RequestInfo::default()));
+
+        let mut first = admit();
+        let permit = match poll(&mut first).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("request not admitted"),
+        };
+
+        // the limit holds back requests although slots are available
+        let mut second = admit();
+        assert!(poll(&mut second).unwrap().is_not_ready());
+
+        // fast completions with the limit used up raise it
+        scheduler.observe(time::Duration::from_millis(1));
+        scheduler.observe(time::Duration::from_millis(1));
+        Scheduler::release(&scheduler, permit, &RequestInfo::default());
+
+        let second_permit = poll(&mut second).unwrap();
+        assert!(second_permit.is_ready());
+        let mut third = admit();
+        let third_permit = poll(&mut third).unwrap();
+        assert!(third_permit.is_ready());
+        let mut fourth = admit();
+        assert!(poll(&mut fourth).unwrap().is_not_ready());
+
+        let mut stats = AioStats::default();
+        scheduler.report(&mut stats);
+        let concurrency = stats.concurrency.unwrap();
+        assert!(concurrency.limit == 2 && concurrency.increases == 1);
+        assert!(concurrency.last_decision == Some(Decision::Increase));
+    }
+}
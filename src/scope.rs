@@ -0,0 +1,252 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::marker;
+use std::sync;
+
+use std::os::unix::io::RawFd;
+
+use futures;
+use parking_lot;
+
+use {AioContext, AioError, AioReadResultFuture, AioWriteResultFuture, SyncLevel};
+
+// -----------------------------------------------------------------------------------------------
+// Tracking of requests that have been handed to the kernel
+// -----------------------------------------------------------------------------------------------
+
+// Counts the requests of a scope that have been submitted to the kernel, but whose completion
+// has not been retrieved yet.
+#[derive(Debug)]
+pub(crate) struct ScopeTracker {
+    outstanding: parking_lot::Mutex<usize>,
+    completed: parking_lot::Condvar,
+}
+
+impl ScopeTracker {
+    fn new() -> ScopeTracker {
+        ScopeTracker {
+            outstanding: parking_lot::Mutex::new(0),
+            completed: parking_lot::Condvar::new(),
+        }
+    }
+
+    // Register a request that is about to be submitted to the kernel
+    pub(crate) fn ticket(tracker: &sync::Arc<ScopeTracker>) -> ScopeTicket {
+        *tracker.outstanding.lock() += 1;
+        ScopeTicket {
+            tracker: tracker.clone(),
+        }
+    }
+
+    // Block the current thread until all registered requests have completed
+    fn wait(&self) {
+        let mut outstanding = self.outstanding.lock();
+
+        while *outstanding > 0 {
+            self.completed.wait(&mut outstanding);
+        }
+    }
+}
+
+// Travels alongside a submitted request and is dropped once the kernel has reported the
+// completion of the request, independent of whether the associated future is still around.
+#[derive(Debug)]
+pub(crate) struct ScopeTicket {
+    tracker: sync::Arc<ScopeTracker>,
+}
+
+impl Drop for ScopeTicket {
+    fn drop(&mut self) {
+        let mut outstanding = self.tracker.outstanding.lock();
+        *outstanding -= 1;
+
+        if *outstanding == 0 {
+            self.tracker.completed.notify_all();
+        }
+    }
+}
+
+// Waits for outstanding requests when leaving the scope, including unwinding after a panic
+struct ScopeGuard {
+    tracker: sync::Arc<ScopeTracker>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        self.tracker.wait();
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Scoped I/O requests
+// -----------------------------------------------------------------------------------------------
+
+/// A scope for I/O requests that operate on borrowed memory, created via `AioContext::scope`.
+///
+/// Futures created through a scope cannot outlive it, and leaving the scope blocks until the kernel
+/// has finished every request of the scope that has been submitted. This holds when futures are
+/// dropped or forgotten while their request is in flight, and when the scope is left by a panic.
+/// A request forgotten while queued for a batch with a zero window is never submitted, though,
+/// and leaving the scope then blocks forever; see `SubmitPolicy::Batched`.
+pub struct Scope<'env> {
+    // the context used to submit requests
+    context: &'env AioContext,
+
+    // outstanding requests submitted through this scope
+    tracker: sync::Arc<ScopeTracker>,
+
+    // invariance over 'env, as in crossbeam's scoped threads
+    _marker: marker::PhantomData<&'env mut &'env ()>,
+}
+
+impl<'env> Scope<'env> {
+    /// Initiate an asynchronous read operation into a borrowed buffer. The buffer also determines
+    /// the number of bytes to be read.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    pub fn read<'scope>(
+        &'scope self,
+        fd: RawFd,
+        offset: u64,
+        buffer: &'env mut [u8],
+    ) -> ScopedReadFuture<'scope, 'env> {
+        let mut inner = self.context.read(fd, offset, buffer);
+        inner.base.scope = Some(self.tracker.clone());
+
+        ScopedReadFuture {
+            inner,
+            _scope: marker::PhantomData,
+        }
+    }
+
+    /// Initiate an asynchronous write operation from a borrowed buffer. The buffer also
+    /// determines the number of bytes to be written.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    pub fn write<'scope>(
+        &'scope self,
+        fd: RawFd,
+        offset: u64,
+        buffer: &'env [u8],
+    ) -> ScopedWriteFuture<'scope, 'env> {
+        self.write_sync(fd, offset, buffer, SyncLevel::None)
+    }
+
+    /// Initiate an asynchronous write operation from a borrowed buffer, applying the given
+    /// synchronization level.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - sync_level: A synchronization level to apply for this write operation
+    pub fn write_sync<'scope>(
+        &'scope self,
+        fd: RawFd,
+        offset: u64,
+        buffer: &'env [u8],
+        sync_level: SyncLevel,
+    ) -> ScopedWriteFuture<'scope, 'env> {
+        let mut inner = self.context.write_sync(fd, offset, buffer, sync_level);
+        inner.base.scope = Some(self.tracker.clone());
+
+        ScopedWriteFuture {
+            inner,
+            _scope: marker::PhantomData,
+        }
+    }
+}
+
+/// Future returned as result of submitting a read request via `Scope::read`.
+pub struct ScopedReadFuture<'scope, 'env: 'scope> {
+    inner: AioReadResultFuture<&'env mut [u8]>,
+    _scope: marker::PhantomData<&'scope Scope<'env>>,
+}
+
+impl<'scope, 'env> ScopedReadFuture<'scope, 'env> {
+    request_options!(|this| Some(&mut this.inner.base));
+}
+
+impl<'scope, 'env> futures::Future for ScopedReadFuture<'scope, 'env> {
+    type Item = &'env mut [u8];
+    type Error = AioError<&'env mut [u8]>;
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
+
+/// Future returned as result of submitting a write request via `Scope::write` or
+/// `Scope::write_sync`.
+pub struct ScopedWriteFuture<'scope, 'env: 'scope> {
+    inner: AioWriteResultFuture<&'env [u8]>,
+    _scope: marker::PhantomData<&'scope Scope<'env>>,
+}
+
+impl<'scope, 'env> ScopedWriteFuture<'scope, 'env> {
+    request_options!(|this| Some(&mut this.inner.base));
+}
+
+impl<'scope, 'env> futures::Future for ScopedWriteFuture<'scope, 'env> {
+    type Item = &'env [u8];
+    type Error = AioError<&'env [u8]>;
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
+
+impl AioContext {
+    /// Create a scope for I/O requests that operate on borrowed memory rather than on owned
+    /// handles.
+    ///
+    /// The call returns only after every request submitted through the scope has been finished
+    /// by the kernel, even if the closure panics or drops a future while its request is in flight.
+    /// Because this blocks the calling thread, the background polling task of the context needs to
+    /// run on a different thread than the one entering the scope.
+    ///
+    /// # Params
+    /// - f: The closure issuing requests through the provided scope
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+        where
+            F: FnOnce(&Scope<'env>) -> R,
+    {
+        let scope = Scope {
+            context: self,
+            tracker: sync::Arc::new(ScopeTracker::new()),
+            _marker: marker::PhantomData,
+        };
+
+        let _guard = ScopeGuard {
+            tracker: scope.tracker.clone(),
+        };
+
+        f(&scope)
+    }
+}
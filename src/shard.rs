@@ -0,0 +1,453 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::cell;
+use std::convert;
+use std::io;
+use std::sync;
+
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+use {
+    AioContext, AioReadResultFuture, AioStats, AioSyncResultFuture, AioWriteResultFuture, ClassStats,
+    ConcurrencyStats, LaneStats, RateLimit, SyncLevel, TenantStats,
+};
+
+#[cfg(feature = "bytes")]
+use bytes;
+
+#[cfg(feature = "bytes")]
+use {AioReadBytesFuture, AioWriteBytesFuture, AlignmentPolicy};
+
+// -----------------------------------------------------------------------------------------------
+// Routing of requests to shards
+// -----------------------------------------------------------------------------------------------
+
+/// How a `ShardedAioContext` determines the shard that serves the requests of the current thread.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShardRouting {
+    /// Each thread sticks to one shard. Threads are spread over the shards in the order in which
+    /// they first issue a request, unless bound to a shard via
+    /// `ShardedAioContext::bind_current_thread`.
+    Thread,
+
+    /// Requests go to the shard of the CPU the thread is running on, as reported by
+    /// `sched_getcpu`, so that threads pinned to a core share its shard
+    Cpu,
+}
+
+impl Default for ShardRouting {
+    fn default() -> ShardRouting {
+        ShardRouting::Thread
+    }
+}
+
+// the number of threads that have been assigned an index for routing
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // the index of the current thread for routing, if assigned already
+    static THREAD_INDEX: cell::Cell<Option<usize>> = cell::Cell::new(None);
+}
+
+// The index of the current thread for routing, assigning the next one on first use
+fn thread_index() -> usize {
+    THREAD_INDEX.with(|index| match index.get() {
+        Some(index) => index,
+        None => {
+            let next = THREADS.fetch_add(1, Ordering::Relaxed);
+            index.set(Some(next));
+            next
+        }
+    })
+}
+
+// -----------------------------------------------------------------------------------------------
+// Sharded contexts
+// -----------------------------------------------------------------------------------------------
+
+/// A set of `AioContext` shards, each with its own kernel context and completion eventfd, which
+/// is driven by the executor the shard has been built with. Requests go to the shard that is
+/// local to the submitting thread, so that in a thread-per-core design each worker submits and
+/// completes its requests without involving the other workers.
+///
+/// Created via `AioContextBuilder::build_sharded`, or via `ShardedAioContext::from_shards` from
+/// contexts built separately. Each shard is configured on its own, so that submission slots,
+/// byte budget and rate limits apply per shard.
+#[derive(Clone, Debug)]
+pub struct ShardedAioContext {
+    shards: sync::Arc<Vec<AioContext>>,
+    routing: ShardRouting,
+}
+
+impl ShardedAioContext {
+    /// Combine contexts that have been built separately into a sharded context, for instance to
+    /// register the completion eventfd of each shard with the reactor of its own worker.
+    ///
+    /// # Params
+    /// - shards: The shards, at least one
+    /// - routing: The routing of requests to the shards
+    pub fn from_shards(
+        shards: Vec<AioContext>,
+        routing: ShardRouting,
+    ) -> Result<ShardedAioContext, io::Error> {
+        if shards.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a sharded context needs at least one shard",
+            ));
+        }
+
+        Ok(ShardedAioContext {
+            shards: sync::Arc::new(shards),
+            routing,
+        })
+    }
+
+    /// Route the requests the current thread issues to sharded contexts under thread routing to
+    /// the given shard, wrapping around for contexts with fewer shards. A worker of a
+    /// thread-per-core design binds itself to the shard driven by its own executor.
+    ///
+    /// # Params
+    /// - shard: The index of the shard
+    pub fn bind_current_thread(shard: usize) {
+        THREAD_INDEX.with(|index| index.set(Some(shard)));
+    }
+
+    /// The shards of this context, in the order of the executors they have been built with.
+    pub fn shards(&self) -> &[AioContext] {
+        &self.shards
+    }
+
+    /// The index of the shard serving requests issued from the current thread.
+    pub fn local_index(&self) -> usize {
+        let index = match self.routing {
+            ShardRouting::Thread => thread_index(),
+            ShardRouting::Cpu => match unsafe { libc::sched_getcpu() } {
+                cpu if cpu >= 0 => cpu as usize,
+                _ => thread_index(),
+            },
+        };
+
+        index % self.shards.len()
+    }
+
+    /// The shard serving requests issued from the current thread.
+    pub fn local(&self) -> &AioContext {
+        &self.shards[self.local_index()]
+    }
+
+    /// Retrieve a snapshot of the activity of all shards combined. Counts and limits are summed
+    /// up, waiting times and latencies cover the worst shard, and the statistics on lanes,
+    /// tenants and operation classes are combined across shards.
+    pub fn stats(&self) -> AioStats {
+        let mut shards = self.shards.iter().map(AioContext::stats);
+        let first = shards.next().unwrap();
+        shards.fold(first, combine)
+    }
+
+    /// Retrieve a snapshot of the activity of each shard.
+    pub fn shard_stats(&self) -> Vec<AioStats> {
+        self.shards.iter().map(AioContext::stats).collect()
+    }
+
+    /// Initiate an asynchronous read operation on the local shard; see `AioContext::read`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    pub fn read<ReadWriteHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadWriteHandle,
+    ) -> AioReadResultFuture<ReadWriteHandle>
+        where
+            ReadWriteHandle: convert::AsMut<[u8]>,
+    {
+        self.local().read(fd, offset, buffer)
+    }
+
+    /// Initiate an asynchronous write operation on the local shard; see `AioContext::write`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    pub fn write<ReadOnlyHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+    ) -> AioWriteResultFuture<ReadOnlyHandle>
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]>,
+    {
+        self.local().write(fd, offset, buffer)
+    }
+
+    /// Initiate an asynchronous write operation with the given synchronization level on the local
+    /// shard; see `AioContext::write_sync`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - sync_level: A synchronization level to apply for this write operation
+    pub fn write_sync<ReadOnlyHandle>(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: ReadOnlyHandle,
+        sync_level: SyncLevel,
+    ) -> AioWriteResultFuture<ReadOnlyHandle>
+        where
+            ReadOnlyHandle: convert::AsRef<[u8]>,
+    {
+        self.local().write_sync(fd, offset, buffer, sync_level)
+    }
+
+    /// Initiate an asynchronous read operation into a `BytesMut` buffer on the local shard; see
+    /// `AioContext::read_bytes`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file from which to read
+    /// - offset: The file offset where we want to read from
+    /// - buffer: A buffer to receive the read results
+    /// - policy: How to deal with memory that is not suitably aligned
+    #[cfg(feature = "bytes")]
+    pub fn read_bytes(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: bytes::BytesMut,
+        policy: AlignmentPolicy,
+    ) -> AioReadBytesFuture {
+        self.local().read_bytes(fd, offset, buffer, policy)
+    }
+
+    /// Initiate an asynchronous write operation from a `Bytes` buffer on the local shard; see
+    /// `AioContext::write_bytes`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to which to write
+    /// - offset: The file offset where we want to write to
+    /// - buffer: A buffer holding the data to be written
+    /// - policy: How to deal with memory that is not suitably aligned
+    #[cfg(feature = "bytes")]
+    pub fn write_bytes(
+        &self,
+        fd: RawFd,
+        offset: u64,
+        buffer: bytes::Bytes,
+        policy: AlignmentPolicy,
+    ) -> AioWriteBytesFuture {
+        self.local().write_bytes(fd, offset, buffer, policy)
+    }
+
+    /// Initiate an asynchronous sync operation on the local shard; see `AioContext::sync`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to sync
+    pub fn sync(&self, fd: RawFd) -> AioSyncResultFuture {
+        self.local().sync(fd)
+    }
+
+    /// Initiate an asynchronous data sync operation on the local shard; see
+    /// `AioContext::data_sync`.
+    ///
+    /// # Params:
+    /// - fd: The file descriptor of the file to sync
+    pub fn data_sync(&self, fd: RawFd) -> AioSyncResultFuture {
+        self.local().data_sync(fd)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Combined statistics
+// -----------------------------------------------------------------------------------------------
+
+// the sum of two optional limits, where no limit on either side means no limit overall
+fn add_limits<T: ::std::ops::Add<Output = T>>(left: Option<T>, right: Option<T>) -> Option<T> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left + right),
+        _ => None,
+    }
+}
+
+fn combine_rate_limits(total: RateLimit, shard: RateLimit) -> RateLimit {
+    RateLimit {
+        bytes_per_sec: add_limits(total.bytes_per_sec, shard.bytes_per_sec),
+        ops_per_sec: add_limits(total.ops_per_sec, shard.ops_per_sec),
+        burst: total.burst.max(shard.burst),
+    }
+}
+
+fn combine_lanes(total: &mut LaneStats, shard: &LaneStats) {
+    total.waiting += shard.waiting;
+    total.admitted += shard.admitted;
+    total.total_wait += shard.total_wait;
+    total.max_wait = total.max_wait.max(shard.max_wait);
+    total.passed_over += shard.passed_over;
+}
+
+fn combine_tenants(total: &mut TenantStats, shard: &TenantStats) {
+    total.waiting += shard.waiting;
+    total.in_flight += shard.in_flight;
+    total.admitted += shard.admitted;
+    total.bytes_admitted += shard.bytes_admitted;
+    total.total_wait += shard.total_wait;
+    total.max_wait = total.max_wait.max(shard.max_wait);
+    total.throttled += shard.throttled;
+}
+
+fn combine_classes(total: &mut ClassStats, shard: &ClassStats) {
+    total.limit.reserved += shard.limit.reserved;
+    total.limit.max = add_limits(total.limit.max, shard.limit.max);
+    total.in_flight += shard.in_flight;
+    total.waiting += shard.waiting;
+}
+
+fn combine_concurrency(total: ConcurrencyStats, shard: ConcurrencyStats) -> ConcurrencyStats {
+    ConcurrencyStats {
+        limit: total.limit + shard.limit,
+        target_latency: total.target_latency,
+        window_latency: total.window_latency.max(shard.window_latency),
+        // the decisions of the shards are only meaningful together if they agree
+        last_decision: if total.last_decision == shard.last_decision {
+            total.last_decision
+        } else {
+            None
+        },
+        increases: total.increases + shard.increases,
+        holds: total.holds + shard.holds,
+        decreases: total.decreases + shard.decreases,
+    }
+}
+
+// Fold the statistics of another shard into the combined statistics
+fn combine(mut total: AioStats, shard: AioStats) -> AioStats {
+    total.slots += shard.slots;
+    total.requests_in_flight += shard.requests_in_flight;
+    total.bytes_in_flight += shard.bytes_in_flight;
+    total.max_bytes_in_flight = add_limits(total.max_bytes_in_flight, shard.max_bytes_in_flight);
+    total.requests_waiting += shard.requests_waiting;
+    total.requests_throttled += shard.requests_throttled;
+    total.requests_expired += shard.requests_expired;
+    total.read_rate_limit = combine_rate_limits(total.read_rate_limit, shard.read_rate_limit);
+    total.write_rate_limit = combine_rate_limits(total.write_rate_limit, shard.write_rate_limit);
+    total.submit_calls += shard.submit_calls;
+    total.submit_calls_saved += shard.submit_calls_saved;
+
+    for (total, shard) in total.lanes.iter_mut().zip(&shard.lanes) {
+        combine_lanes(total, shard);
+    }
+
+    for (total, shard) in total.classes.iter_mut().zip(&shard.classes) {
+        combine_classes(total, shard);
+    }
+
+    // tenants are ordered by identifier within each shard
+    for tenant in shard.tenants {
+        match total.tenants.binary_search_by_key(&tenant.tenant, |existing| existing.tenant) {
+            Ok(index) => combine_tenants(&mut total.tenants[index], &tenant),
+            Err(index) => total.tenants.insert(index, tenant),
+        }
+    }
+
+    total.concurrency = match (total.concurrency.take(), shard.concurrency) {
+        (Some(total), Some(shard)) => Some(combine_concurrency(total, shard)),
+        (total, shard) => total.or(shard),
+    };
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use {ClassLimit, OpClass};
+
+    fn shard_stats(tenants: &[u32], reads: usize) -> AioStats {
+        AioStats {
+            slots: 8,
+            requests_in_flight: reads,
+            max_bytes_in_flight: Some(4096),
+            read_rate_limit: RateLimit {
+                ops_per_sec: Some(100),
+                ..RateLimit::default()
+            },
+            lanes: vec![
+                LaneStats {
+                    admitted: 2,
+                    max_wait: Duration::from_millis(reads as u64),
+                    ..LaneStats::default()
+                };
+                3
+            ],
+            tenants: tenants
+                .iter()
+                .map(|&tenant| TenantStats {
+                    tenant,
+                    admitted: 1,
+                    ..TenantStats::default()
+                })
+                .collect(),
+            classes: vec![ClassStats {
+                class: OpClass::Read,
+                limit: ClassLimit {
+                    reserved: 1,
+                    max: Some(4),
+                },
+                in_flight: reads,
+                waiting: 0,
+            }],
+            ..AioStats::default()
+        }
+    }
+
+    #[test]
+    fn combined_stats() {
+        let stats = combine(shard_stats(&[1, 3], 2), shard_stats(&[2, 3], 5));
+
+        assert!(stats.slots == 16 && stats.requests_in_flight == 7);
+        assert!(stats.max_bytes_in_flight == Some(8192));
+        assert!(stats.read_rate_limit.ops_per_sec == Some(200));
+        assert!(stats.read_rate_limit.bytes_per_sec.is_none());
+
+        assert!(stats.lanes.len() == 3);
+        assert!(stats.lanes[0].admitted == 4 && stats.lanes[0].max_wait == Duration::from_millis(5));
+
+        let tenants: Vec<_> = stats.tenants.iter().map(|tenant| (tenant.tenant, tenant.admitted)).collect();
+        assert!(tenants == vec![(1, 1), (2, 1), (3, 2)]);
+
+        assert!(stats.classes[0].in_flight == 7);
+        assert!(stats.classes[0].limit == ClassLimit { reserved: 2, max: Some(8) });
+        assert!(stats.concurrency.is_none());
+    }
+}
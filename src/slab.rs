@@ -0,0 +1,699 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::cell;
+use std::fmt;
+use std::io;
+use std::sync;
+use std::time;
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use futures;
+use libc;
+
+use raw::RawCompletion;
+use sched::{RequestInfo, Scheduler};
+use scope::ScopeTicket;
+use sync::SemaphorePermit;
+
+// -----------------------------------------------------------------------------------------------
+// Tickets identifying requests in flight
+// -----------------------------------------------------------------------------------------------
+
+// Identifies a request by the index of its slab entry and the generation of the entry at the
+// time the request claimed it. Tickets travel through the kernel in the `aio_data` field of the
+// control block, so that completion events can be matched to entries without any lookup
+// structure, and events for entries that have moved on can be told apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Ticket {
+    index: u32,
+    generation: u32,
+}
+
+impl Ticket {
+    pub(crate) fn to_data(self) -> u64 {
+        (u64::from(self.generation) << 32) | u64::from(self.index)
+    }
+
+    fn from_data(data: u64) -> Ticket {
+        Ticket {
+            index: data as u32,
+            generation: (data >> 32) as u32,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Slab entries
+// -----------------------------------------------------------------------------------------------
+
+// The resources held by a request in flight
+#[derive(Debug)]
+pub(crate) struct Occupant {
+    // the submission slot of the request; returned once the entry is released
+    pub(crate) permit: SemaphorePermit,
+
+    // scheduling information of the request
+    pub(crate) info: RequestInfo,
+
+    // the share of the byte budget of the context occupied by the request, if any
+    pub(crate) bytes: Option<SemaphorePermit>,
+
+    // registration with a scope of borrowed requests, if any
+    pub(crate) scope_ticket: Option<ScopeTicket>,
+
+    // the token under which the completion is reported to a completion driver, if any
+    pub(crate) token: Option<u64>,
+
+    // receives the result in place of the issuing future, for requests issued with a callback
+    pub(crate) callback: Option<Callback>,
+}
+
+// Receives the result of a request issued with a callback rather than awaited through a future;
+// called by whoever retrieves the completion, once the slab entry has been released
+pub(crate) struct Callback(Box<dyn FnOnce(io::Result<usize>) + Send>);
+
+impl Callback {
+    pub(crate) fn new<F>(f: F) -> Callback
+        where
+            F: FnOnce(io::Result<usize>) + Send + 'static,
+    {
+        Callback(Box::new(f))
+    }
+
+    pub(crate) fn call(self, result: io::Result<usize>) {
+        (self.0)(result)
+    }
+
+    // Hand over a result as reported by the kernel
+    fn complete(self, result: i64) {
+        if result < 0 {
+            self.call(Err(io::Error::from_raw_os_error(-result as i32)))
+        } else {
+            self.call(Ok(result as usize))
+        }
+    }
+}
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Callback")
+    }
+}
+
+// Life cycle of an entry, as kept in the lower half of its state word
+//
+// - FREE: available for claiming
+// - SUBMITTED: the request has been handed to the kernel
+// - COMPLETED: the kernel has reported the result, which has not been retrieved yet
+// - ABANDONED: the future that issued the request is gone; the entry is released upon completion
+const FREE: u32 = 0;
+const SUBMITTED: u32 = 1;
+const COMPLETED: u32 = 2;
+const ABANDONED: u32 = 3;
+
+// Combine generation and phase into a state word
+fn pack(generation: u32, phase: u32) -> u64 {
+    (u64::from(generation) << 32) | u64::from(phase)
+}
+
+fn generation(state: u64) -> u32 {
+    (state >> 32) as u32
+}
+
+fn phase(state: u64) -> u32 {
+    state as u32
+}
+
+// Marks the end of the free list
+const NIL: u32 = u32::MAX;
+
+// An entry of the slab. The state word determines which party may access the other fields: the
+// future that claimed the entry while it is SUBMITTED or COMPLETED, and the task polling for
+// completions while it is SUBMITTED or ABANDONED. The completing side only touches the resources
+// tied to the transfer, and hands the entry over by a transition to COMPLETED; the future only
+// touches the entry again once it has observed that transition.
+#[derive(Debug)]
+struct Entry {
+    // the generation and phase of the entry
+    state: AtomicU64,
+
+    // the resources of the request occupying the entry
+    occupant: cell::UnsafeCell<Option<Occupant>>,
+
+    // the time the request claimed the entry, for tracking completion latency
+    claimed: cell::UnsafeCell<time::Instant>,
+
+    // the result reported by the kernel; published by the transition to COMPLETED
+    result: cell::UnsafeCell<i64>,
+
+    // the next entry on the free list
+    next: AtomicU32,
+
+    // the task waiting for the result
+    task: futures::task::AtomicTask,
+}
+
+// -----------------------------------------------------------------------------------------------
+// Capacity
+// -----------------------------------------------------------------------------------------------
+
+// Pre-allocated state for the requests in flight of a context: one slab entry per submission
+// slot, handed out against the slot permits of the scheduler. An entry is occupied from
+// submission until the result has been retrieved by the issuing future, or until completion if
+// that future is gone, at which point the slot permit is returned to the scheduler.
+//
+// Claiming, completing and releasing entries is lock-free: entries change hands by atomic
+// transitions of their state words, and free entries are kept on a lock-free stack.
+#[derive(Debug)]
+pub(crate) struct Capacity {
+    entries: Box<[Entry]>,
+
+    // the top of the stack of free entries, tagged with a counter in the upper half that changes
+    // with every update, so that a stale head cannot be mistaken for the current one
+    free: AtomicU64,
+
+    // the scheduler to return slot permits to
+    scheduler: sync::Arc<Scheduler>,
+
+    // whether the scheduler needs to learn about completion latency
+    track_latency: bool,
+
+    // statistics on the requests that have been submitted to the kernel
+    requests_in_flight: AtomicUsize,
+    bytes_in_flight: AtomicUsize,
+}
+
+// Access to the entries is coordinated by their state words.
+unsafe impl Sync for Capacity {}
+
+impl Capacity {
+    pub(crate) fn new(nr: usize, scheduler: sync::Arc<Scheduler>, track_latency: bool) -> Capacity {
+        let now = time::Instant::now();
+
+        let entries: Vec<_> = (0..nr)
+            .map(|index| Entry {
+                state: AtomicU64::new(pack(0, FREE)),
+                occupant: cell::UnsafeCell::new(None),
+                claimed: cell::UnsafeCell::new(now),
+                result: cell::UnsafeCell::new(0),
+                next: AtomicU32::new(if index + 1 < nr { index as u32 + 1 } else { NIL }),
+                task: futures::task::AtomicTask::new(),
+            })
+            .collect();
+
+        Capacity {
+            entries: entries.into_boxed_slice(),
+            free: AtomicU64::new(u64::from(if nr > 0 { 0 } else { NIL })),
+            scheduler,
+            track_latency,
+            requests_in_flight: AtomicUsize::new(0),
+            bytes_in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    // The number of requests that have been submitted and not completed yet
+    pub(crate) fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight.load(Ordering::Relaxed)
+    }
+
+    // The number of bytes transferred by the requests in flight
+    pub(crate) fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight.load(Ordering::Relaxed)
+    }
+
+    // The number of entries available for claiming; only accurate while nobody claims or
+    // releases entries
+    #[cfg(test)]
+    pub(crate) fn free_entries(&self) -> usize {
+        let mut count = 0;
+        let mut index = self.free.load(Ordering::Acquire) as u32;
+
+        while index != NIL {
+            count += 1;
+            index = self.entries[index as usize].next.load(Ordering::Relaxed);
+        }
+
+        count
+    }
+
+    // Take an entry off the free list
+    fn pop_free(&self) -> Option<u32> {
+        let mut head = self.free.load(Ordering::Acquire);
+
+        loop {
+            let index = head as u32;
+
+            if index == NIL {
+                return None;
+            }
+
+            let next = self.entries[index as usize].next.load(Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+
+            let new = (tag << 32) | u64::from(next);
+
+            match self.free.compare_exchange_weak(head, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(index),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // Put an entry onto the free list
+    fn push_free(&self, index: u32) {
+        let mut head = self.free.load(Ordering::Relaxed);
+
+        loop {
+            self.entries[index as usize].next.store(head as u32, Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+
+            let new = (tag << 32) | u64::from(index);
+
+            match self.free.compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // Claim an entry for a request that holds a slot permit. Returns the ticket of the request,
+    // which goes into the `aio_data` field of its control block.
+    pub(crate) fn claim(&self, occupant: Occupant) -> Ticket {
+        let index = self.pop_free().expect("Each slot permit is backed by a slab entry");
+        let entry = &self.entries[index as usize];
+        let len = occupant.info.len as usize;
+
+        let state = entry.state.load(Ordering::Acquire);
+        debug_assert!(phase(state) == FREE);
+
+        let ticket = Ticket {
+            index,
+            generation: generation(state),
+        };
+
+        // the entry is ours until we hand it to the kernel
+        unsafe {
+            *entry.occupant.get() = Some(occupant);
+            *entry.claimed.get() = time::Instant::now();
+        }
+
+        entry.state.store(pack(ticket.generation, SUBMITTED), Ordering::Release);
+
+        self.requests_in_flight.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_flight.fetch_add(len, Ordering::Relaxed);
+
+        ticket
+    }
+
+    // Release the entry of a request that the kernel did not accept, reporting the error to the
+    // callback of the request, if any
+    pub(crate) fn cancel(&self, ticket: Ticket, error: i32) {
+        let entry = &self.entries[ticket.index as usize];
+
+        let (len, callback) = unsafe {
+            (*entry.occupant.get())
+                .as_mut()
+                .map_or((0, None), |occupant| (occupant.info.len, occupant.callback.take()))
+        };
+
+        self.requests_in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_in_flight.fetch_sub(len as usize, Ordering::Relaxed);
+        self.release(ticket);
+
+        if let Some(callback) = callback {
+            callback.complete(-i64::from(error));
+        }
+    }
+
+    // Record the completion event reported by the kernel for the given `aio_data`. Returns false
+    // for events that do not match a submitted request. Completion events are processed by a
+    // single task at a time; the only other caller is the submitter, for requests of a batch
+    // that the kernel refused and hence never reports.
+    pub(crate) fn complete(&self, data: u64, result: i64) -> bool {
+        self.record(data, result).is_some()
+    }
+
+    // Record a completion like `complete`, returning the token of the request, if any; None if
+    // the event does not match a submitted request
+    fn record(&self, data: u64, result: i64) -> Option<Option<u64>> {
+        let ticket = Ticket::from_data(data);
+
+        let entry = self.entries.get(ticket.index as usize)?;
+
+        let mut state = entry.state.load(Ordering::Acquire);
+
+        let in_flight = phase(state) == SUBMITTED || phase(state) == ABANDONED;
+
+        if generation(state) != ticket.generation || !in_flight {
+            return None;
+        }
+
+        // the kernel is done with the request, so the resources tied to the transfer go now
+        let (bytes, scope_ticket, token, callback, len, claimed) = unsafe {
+            let occupant = (*entry.occupant.get()).as_mut().unwrap();
+            *entry.result.get() = result;
+
+            (
+                occupant.bytes.take(),
+                occupant.scope_ticket.take(),
+                occupant.token,
+                occupant.callback.take(),
+                occupant.info.len,
+                *entry.claimed.get(),
+            )
+        };
+
+        self.requests_in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_in_flight.fetch_sub(len as usize, Ordering::Relaxed);
+
+        if self.track_latency {
+            self.scheduler.observe(claimed.elapsed());
+        }
+
+        loop {
+            // nobody polls for the result of a request with a callback
+            if phase(state) == ABANDONED || callback.is_some() {
+                self.release(ticket);
+                break;
+            }
+
+            let completed = pack(ticket.generation, COMPLETED);
+
+            match entry.state.compare_exchange(state, completed, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    entry.task.notify();
+                    break;
+                }
+
+                // the future has given up on the request in the meantime
+                Err(current) => state = current,
+            }
+        }
+
+        drop(bytes);
+        drop(scope_ticket);
+
+        if let Some(callback) = callback {
+            callback.complete(result);
+        }
+
+        Some(token)
+    }
+
+    // Record a batch of completion events retrieved from the kernel, passing the token and
+    // result of requests that carry a token to `reaped`
+    pub(crate) fn complete_all<F>(&self, events: &[RawCompletion], mut reaped: F)
+        where
+            F: FnMut(u64, i64),
+    {
+        for event in events {
+            match self.record(event.tag, event.res) {
+                None => println!("WARN: received event for a request that is not in flight"),
+                Some(Some(token)) => reaped(token, event.res),
+                Some(None) => {}
+            }
+        }
+    }
+
+    // Retrieve the result of a request, releasing its entry once available
+    pub(crate) fn poll_result(&self, ticket: Ticket) -> futures::Async<i64> {
+        let entry = &self.entries[ticket.index as usize];
+        entry.task.register();
+
+        let state = entry.state.load(Ordering::Acquire);
+        assert!(generation(state) == ticket.generation, "AIO request polled after completion");
+
+        if phase(state) == COMPLETED {
+            let result = unsafe { *entry.result.get() };
+            self.release(ticket);
+            futures::Async::Ready(result)
+        } else {
+            futures::Async::NotReady
+        }
+    }
+
+    // Give up on the result of a request, as its future is dropped
+    pub(crate) fn abandon(&self, ticket: Ticket) {
+        let entry = &self.entries[ticket.index as usize];
+        let submitted = pack(ticket.generation, SUBMITTED);
+        let abandoned = pack(ticket.generation, ABANDONED);
+
+        match entry.state.compare_exchange(submitted, abandoned, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => (),
+            Err(state) if state == pack(ticket.generation, COMPLETED) => self.release(ticket),
+            Err(_) => (),
+        }
+    }
+
+    // Fail all requests in flight, as the kernel is never going to report their completion. Only
+    // valid once the kernel context has been destroyed, which waits for the requests in flight,
+    // as their buffers and scopes are released here.
+    pub(crate) fn shutdown(&self) {
+        for (index, entry) in self.entries.iter().enumerate() {
+            let state = entry.state.load(Ordering::Acquire);
+
+            if phase(state) == SUBMITTED || phase(state) == ABANDONED {
+                let ticket = Ticket {
+                    index: index as u32,
+                    generation: generation(state),
+                };
+
+                self.complete(ticket.to_data(), -i64::from(libc::ECANCELED));
+            }
+        }
+    }
+
+    // Return an entry to the free list, followed by its slot permit, so that every permit
+    // handed out by the scheduler is guaranteed to find a free entry. The caller has exclusive
+    // access to the entry.
+    fn release(&self, ticket: Ticket) {
+        let entry = &self.entries[ticket.index as usize];
+        let occupant = unsafe { (*entry.occupant.get()).take().unwrap() };
+
+        entry
+            .state
+            .store(pack(ticket.generation.wrapping_add(1), FREE), Ordering::Release);
+        self.push_free(ticket.index);
+
+        Scheduler::release(&self.scheduler, occupant.permit, &occupant.info);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use futures::executor;
+    use futures::{future, Async, Future};
+
+    use sched::{PriorityLanes, PriorityPolicy};
+    use sync::Semaphore;
+    use timer::Timer;
+
+    use super::*;
+
+    struct NoopNotify;
+
+    impl executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    // Run a closure within a task, as needed for registering interest in completion
+    fn in_task<T, F: FnMut() -> T>(mut f: F) -> T {
+        let mut task = executor::spawn(future::poll_fn(|| Ok::<_, ()>(Async::Ready(f()))));
+
+        match task.poll_future_notify(&sync::Arc::new(NoopNotify), 0) {
+            Ok(Async::Ready(value)) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn capacity(nr: usize) -> (sync::Arc<Scheduler>, Capacity) {
+        let scheduler = sync::Arc::new(Scheduler::new(
+            Semaphore::new(nr),
+            Box::new(PriorityLanes::new(PriorityPolicy::Strict)),
+            Default::default(),
+            None,
+            sync::Arc::new(Timer::new()),
+        ));
+
+        (scheduler.clone(), Capacity::new(nr, scheduler, false))
+    }
+
+    fn occupant(scheduler: &sync::Arc<Scheduler>) -> Occupant {
+        let mut admission = Scheduler::admit(scheduler, RequestInfo::default());
+
+        match in_task(|| admission.poll()) {
+            Ok(Async::Ready(permit)) => Occupant {
+                permit,
+                info: RequestInfo::default(),
+                bytes: None,
+                scope_ticket: None,
+                token: None,
+                callback: None,
+            },
+            _ => panic!("no slot available"),
+        }
+    }
+
+    #[test]
+    fn complete_and_retrieve() {
+        let (scheduler, capacity) = capacity(2);
+
+        let ticket = capacity.claim(occupant(&scheduler));
+        let data = ticket.to_data();
+        assert!(capacity.requests_in_flight() == 1);
+        assert!(in_task(|| capacity.poll_result(ticket)).is_not_ready());
+
+        // the result is kept until retrieved; a duplicate event is rejected
+        assert!(capacity.complete(data, 42));
+        assert!(!capacity.complete(data, 42));
+        assert!(capacity.requests_in_flight() == 0);
+        assert!(scheduler.available_slots() == 1);
+
+        assert!(in_task(|| capacity.poll_result(ticket)) == Async::Ready(42));
+        assert!(scheduler.available_slots() == 2);
+        assert!(capacity.free_entries() == 2);
+
+        // a stale event for an entry that has been released is rejected
+        assert!(!capacity.complete(data, 42));
+        assert!(!capacity.complete(u64::MAX, 0));
+    }
+
+    #[test]
+    fn abandoned_requests() {
+        let (scheduler, capacity) = capacity(1);
+
+        let ticket = capacity.claim(occupant(&scheduler));
+        let data = ticket.to_data();
+
+        // an abandoned request keeps its slot until the kernel reports completion
+        capacity.abandon(ticket);
+        assert!(scheduler.available_slots() == 0);
+        assert!(capacity.complete(data, 0));
+        assert!(scheduler.available_slots() == 1);
+
+        // the entry is reused under a new generation
+        let reused = capacity.claim(occupant(&scheduler));
+        assert!(reused != ticket && reused.to_data() != data);
+        assert!(!capacity.complete(data, 0));
+
+        // requests in flight fail when the context shuts down
+        capacity.shutdown();
+        let result = in_task(|| capacity.poll_result(reused));
+        assert!(result == Async::Ready(-i64::from(libc::ECANCELED)));
+        assert!(capacity.free_entries() == 1);
+    }
+
+    #[test]
+    fn concurrent_requests() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        const THREADS: usize = 4;
+        const REQUESTS: usize = 2000;
+
+        let (scheduler, capacity) = capacity(8);
+        let capacity = sync::Arc::new(capacity);
+        let (sender, receiver) = mpsc::channel::<u64>();
+
+        // plays the kernel: completes requests in order of submission, some of them twice
+        let completer = {
+            let capacity = capacity.clone();
+
+            thread::spawn(move || {
+                let mut duplicates = 0;
+
+                for (count, data) in receiver.iter().enumerate() {
+                    assert!(capacity.complete(data, data as i64));
+
+                    if count % 5 == 0 && !capacity.complete(data, 0) {
+                        duplicates += 1;
+                    }
+                }
+
+                duplicates
+            })
+        };
+
+        let clients: Vec<_> = (0..THREADS)
+            .map(|client| {
+                let scheduler = scheduler.clone();
+                let capacity = capacity.clone();
+                let sender = sender.clone();
+
+                thread::spawn(move || {
+                    for request in 0..REQUESTS {
+                        let mut admission = Scheduler::admit(&scheduler, RequestInfo::default());
+
+                        let permit = loop {
+                            match in_task(|| admission.poll()) {
+                                Ok(Async::Ready(permit)) => break permit,
+                                _ => thread::yield_now(),
+                            }
+                        };
+
+                        let ticket = capacity.claim(Occupant {
+                            permit,
+                            info: RequestInfo::default(),
+                            bytes: None,
+                            scope_ticket: None,
+                            token: None,
+                            callback: None,
+                        });
+
+                        let data = ticket.to_data();
+                        sender.send(data).unwrap();
+
+                        if (client + request) % 3 == 0 {
+                            capacity.abandon(ticket);
+                            continue;
+                        }
+
+                        loop {
+                            match in_task(|| capacity.poll_result(ticket)) {
+                                Async::Ready(result) => {
+                                    assert!(result == data as i64);
+                                    break;
+                                }
+                                Async::NotReady => thread::yield_now(),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        drop(sender);
+        assert!(completer.join().unwrap() == THREADS * REQUESTS / 5);
+
+        assert!(capacity.requests_in_flight() == 0);
+        assert!(capacity.free_entries() == 8);
+        assert!(scheduler.available_slots() == 8);
+    }
+}
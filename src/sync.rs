@@ -20,82 +20,617 @@
 // SOFTWARE.
 // ===============================================================================================
 
-use std::sync;
-use std::io;
+
+//! Synchronization primitives that are workable with futures.
+//!
+//! The main type is `Semaphore`, an asynchronous counting semaphore that is used internally to
+//! limit the number of requests in flight for an `AioContext`, and that can be used by clients
+//! for their own admission control.
+
+use std::cmp;
 use std::collections;
+use std::error;
+use std::fmt;
+use std::sync;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use futures;
+use parking_lot;
 
 // -----------------------------------------------------------------------------------------------
 // Semaphore that's workable with Futures
-//
-// Currently this is rather barebones; may consider expanding it into something library-grade
-// and then exposing it by itself.
 // -----------------------------------------------------------------------------------------------
 
+// A task waiting for permits to become available. Permits are handed over to a waiter by the
+// releasing party while holding the semaphore lock; the waiter merely picks them up.
 #[derive(Debug)]
-struct SemaphoreInner {
-    capacity: usize,
-    waiters: collections::VecDeque<futures::task::Task>,
+struct Waiter {
+    // number of permits requested
+    permits: usize,
+
+    // set once the requested permits have been transferred to this waiter
+    granted: AtomicBool,
+
+    // set if the semaphore has been closed while waiting
+    closed: AtomicBool,
+
+    // the task to notify upon handoff or close
+    task: futures::task::AtomicTask,
 }
 
+// Flags kept in the low bits of the state word of a semaphore, below the number of available
+// permits
+const CLOSED: usize = 1;
+const WAITING: usize = 2;
+const FLAG_BITS: u32 = 2;
+
+// the largest number of permits a semaphore can hold
+pub(crate) const MAX_PERMITS: usize = usize::MAX >> FLAG_BITS;
+
+fn available(state: usize) -> usize {
+    state >> FLAG_BITS
+}
+
+// The state shared by the handles of a semaphore. Without waiters, permits are acquired and
+// released by atomic updates of the state word alone. Once a task has to wait, the `WAITING`
+// flag sends everybody through the lock, which then guards all changes of the permits, so that
+// released permits are handed over to the waiters in order.
 #[derive(Debug)]
+struct SemaphoreInner {
+    // the number of available permits, along with the flags
+    state: AtomicUsize,
+
+    // the number of permits the semaphore holds in total, available or not, and the number of
+    // permits detached from their guards, whose release does not add to the total
+    total: AtomicUsize,
+    detached: AtomicUsize,
+
+    // waiters in order of arrival
+    waiters: parking_lot::Mutex<collections::VecDeque<sync::Arc<Waiter>>>,
+}
+
+impl SemaphoreInner {
+    // Take permits without waiting, unless the semaphore is closed or others are waiting
+    fn try_take(&self, permits: usize) -> Result<(), TryAcquireError> {
+        let mut state = self.state.load(Ordering::SeqCst);
+
+        loop {
+            if state & CLOSED != 0 {
+                return Err(TryAcquireError::Closed);
+            }
+
+            if state & WAITING != 0 || available(state) < permits {
+                return Err(TryAcquireError::NoPermits);
+            }
+
+            let next = state - (permits << FLAG_BITS);
+
+            match self.state.compare_exchange_weak(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    // Return permits, handing them over to waiters if there are any
+    fn give(&self, permits: usize) {
+        let mut state = self.state.load(Ordering::SeqCst);
+
+        while state & WAITING == 0 {
+            let next = state + (permits << FLAG_BITS);
+
+            match self.state.compare_exchange_weak(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(current) => state = current,
+            }
+        }
+
+        let mut waiters = self.waiters.lock();
+        self.state.fetch_add(permits << FLAG_BITS, Ordering::SeqCst);
+        self.dispatch(&mut waiters);
+    }
+
+    // Hand over available permits to waiters in FIFO order, with the lock held. We stop at the
+    // first waiter that cannot be satisfied, so that a weighted request is not starved by smaller
+    // ones behind it. Once nobody waits anymore, permits change hands without the lock again.
+    fn dispatch(&self, waiters: &mut collections::VecDeque<sync::Arc<Waiter>>) {
+        while let Some(permits) = waiters.front().map(|waiter| waiter.permits) {
+            if permits > available(self.state.load(Ordering::SeqCst)) {
+                break;
+            }
+
+            self.state.fetch_sub(permits << FLAG_BITS, Ordering::SeqCst);
+
+            let waiter = waiters.pop_front().unwrap();
+            waiter.granted.store(true, Ordering::Release);
+            waiter.task.notify();
+        }
+
+        if waiters.is_empty() {
+            self.state.fetch_and(!WAITING, Ordering::SeqCst);
+        }
+    }
+}
+
+/// An asynchronous counting semaphore.
+///
+/// Permits are acquired via futures that resolve to a `SemaphorePermit` guard, which returns the
+/// permits to the semaphore when dropped. Waiters are served in strict FIFO order, and permits are
+/// handed over directly to the waiter at the head of the queue upon release, so that a waiter can
+/// neither be overtaken nor lose its turn because of spurious wakeups. As long as nobody waits,
+/// acquiring and releasing permits takes no lock.
+///
+/// `Semaphore` is a cheap handle; clones refer to the same set of permits.
+#[derive(Clone, Debug)]
 pub struct Semaphore {
-    inner: parking_lot::RwLock<SemaphoreInner>,
+    inner: sync::Arc<SemaphoreInner>,
 }
 
 impl Semaphore {
-    pub fn new(initial: usize) -> Semaphore {
+    /// Create a new semaphore with the given number of permits, which needs to be at most a
+    /// quarter of `usize::MAX`
+    pub fn new(permits: usize) -> Semaphore {
+        assert!(permits <= MAX_PERMITS, "too many permits for a semaphore");
+
         Semaphore {
-            inner: parking_lot::RwLock::new(SemaphoreInner {
-                capacity: initial,
-                waiters: collections::VecDeque::new(),
+            inner: sync::Arc::new(SemaphoreInner {
+                state: AtomicUsize::new(permits << FLAG_BITS),
+                total: AtomicUsize::new(permits),
+                detached: AtomicUsize::new(0),
+                waiters: parking_lot::Mutex::new(collections::VecDeque::new()),
             }),
         }
     }
 
-    pub fn acquire(&self) -> SemaphoreHandle {
-        let mut guard = self.inner.write();
-        if guard.capacity > 0 {
-            guard.capacity -= 1;
-            SemaphoreHandle::Completed(futures::future::result(Ok(())))
-        } else {
-            guard.waiters.push_back(futures::task::current());
-            SemaphoreHandle::Waiting
+    /// Acquire a single permit.
+    pub fn acquire(&self) -> Acquire {
+        self.acquire_many(1)
+    }
+
+    /// Acquire the given number of permits at once. The request is queued as a unit, and
+    /// it is satisfied only once all of the permits are available. Requesting more permits than
+    /// the semaphore holds in total fails right away, as the request could never be satisfied.
+    ///
+    /// The returned future is cancellation safe: dropping it while waiting removes the request
+    /// from the queue, and permits that had already been handed over are released again.
+    pub fn acquire_many(&self, permits: usize) -> Acquire {
+        Acquire {
+            semaphore: self.clone(),
+            permits,
+            waiter: None,
+        }
+    }
+
+    /// Attempt to acquire a single permit without waiting.
+    pub fn try_acquire(&self) -> Result<SemaphorePermit, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Attempt to acquire the given number of permits without waiting. In order to preserve
+    /// fairness, this fails if other tasks are already waiting for permits.
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit, TryAcquireError> {
+        self.inner.try_take(permits)?;
+
+        Ok(SemaphorePermit {
+            semaphore: self.clone(),
+            permits,
+        })
+    }
+
+    /// Return permits to the semaphore; this is used to add permits, or to release permits
+    /// detached via `SemaphorePermit::forget`.
+    pub fn release(&self, permits: usize) {
+        // permits beyond the ones detached from their guards are new
+        let mut detached = self.inner.detached.load(Ordering::SeqCst);
+
+        loop {
+            let returned = cmp::min(detached, permits);
+
+            match self.inner.detached.compare_exchange_weak(
+                detached,
+                detached - returned,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    self.inner.total.fetch_add(permits - returned, Ordering::SeqCst);
+                    break;
+                }
+                Err(current) => detached = current,
+            }
+        }
+
+        self.inner.give(permits);
+    }
+
+    /// Close the semaphore. All pending and future acquisitions fail with an error. Permits
+    /// that are currently held remain valid.
+    pub fn close(&self) {
+        let mut waiters = self.inner.waiters.lock();
+        self.inner.state.fetch_or(CLOSED, Ordering::SeqCst);
+
+        for waiter in waiters.drain(..) {
+            waiter.closed.store(true, Ordering::Release);
+            waiter.task.notify();
         }
+
+        self.inner.state.fetch_and(!WAITING, Ordering::SeqCst);
+    }
+
+    /// Has the semaphore been closed?
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.load(Ordering::SeqCst) & CLOSED != 0
+    }
+
+    /// The number of permits currently available
+    pub fn available_permits(&self) -> usize {
+        available(self.inner.state.load(Ordering::SeqCst))
     }
 
-    pub fn release(&self) {
-        let mut guard = self.inner.write();
-        if !guard.waiters.is_empty() {
-            guard.waiters.pop_front().unwrap().notify();
-        } else {
-            guard.capacity += 1;
+    /// The number of acquisitions currently waiting for permits
+    pub fn waiting(&self) -> usize {
+        self.inner.waiters.lock().len()
+    }
+}
+
+/// A guard representing permits acquired from a `Semaphore`; the permits are released when the
+/// guard is dropped.
+#[derive(Debug)]
+pub struct SemaphorePermit {
+    semaphore: Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit {
+    /// The number of permits held by this guard
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Detach the permits from the guard without releasing them, returning their number. The
+    /// caller becomes responsible for eventually calling `Semaphore::release`.
+    pub fn forget(mut self) -> usize {
+        let permits = self.permits;
+        self.semaphore.inner.detached.fetch_add(permits, Ordering::SeqCst);
+        self.permits = 0;
+        permits
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.inner.give(self.permits);
         }
     }
+}
+
+/// Future returned by `Semaphore::acquire` and `Semaphore::acquire_many`.
+#[derive(Debug)]
+pub struct Acquire {
+    semaphore: Semaphore,
+    permits: usize,
+
+    // our entry in the wait queue, once we had to wait
+    waiter: Option<sync::Arc<Waiter>>,
+}
+
+impl Acquire {
+    // Take the permits, or join the wait queue if they are not available
+    fn take_or_wait(&mut self) -> Result<futures::Async<()>, AcquireError> {
+        match self.semaphore.inner.try_take(self.permits) {
+            Ok(()) => return Ok(futures::Async::Ready(())),
+            Err(TryAcquireError::Closed) => return Err(AcquireError::Closed),
+            Err(TryAcquireError::NoPermits) => {}
+        }
+
+        // waiting for more permits than there are would hold up everybody queued behind us
+        if self.permits > self.semaphore.inner.total.load(Ordering::SeqCst) {
+            return Err(AcquireError::TooManyPermits);
+        }
+
+        let inner = &self.semaphore.inner;
+        let mut waiters = inner.waiters.lock();
+        let mut state = inner.state.load(Ordering::SeqCst);
 
-    // For testing code
-    pub fn current_capacity(&self) -> usize {
-        self.inner.read().capacity
+        // with the flag set, permits only change hands under the lock, so the waiter cannot miss
+        // a release
+        loop {
+            if state & CLOSED != 0 {
+                return Err(AcquireError::Closed);
+            }
+
+            if state & WAITING == 0 && available(state) >= self.permits {
+                let next = state - (self.permits << FLAG_BITS);
+
+                match inner.state.compare_exchange_weak(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(_) => return Ok(futures::Async::Ready(())),
+                    Err(current) => state = current,
+                }
+            } else {
+                match inner.state.compare_exchange_weak(state, state | WAITING, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(_) => break,
+                    Err(current) => state = current,
+                }
+            }
+        }
+
+        let waiter = sync::Arc::new(Waiter {
+            permits: self.permits,
+            granted: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            task: futures::task::AtomicTask::new(),
+        });
+
+        waiter.task.register();
+        waiters.push_back(waiter.clone());
+        self.waiter = Some(waiter);
+
+        Ok(futures::Async::NotReady)
     }
 }
 
-pub enum SemaphoreHandle {
-    Waiting,
-    Completed(futures::future::FutureResult<(), io::Error>),
+impl futures::Future for Acquire {
+    type Item = SemaphorePermit;
+    type Error = AcquireError;
+
+    fn poll(&mut self) -> Result<futures::Async<Self::Item>, Self::Error> {
+        if let Some(ref waiter) = self.waiter {
+            waiter.task.register();
+
+            if waiter.granted.load(Ordering::Acquire) {
+                // fall through to hand out the permits
+            } else if waiter.closed.load(Ordering::Acquire) {
+                return Err(AcquireError::Closed);
+            } else {
+                return Ok(futures::Async::NotReady);
+            }
+        } else if self.take_or_wait()?.is_not_ready() {
+            return Ok(futures::Async::NotReady);
+        }
+
+        self.waiter = None;
+        let permits = self.permits;
+        self.permits = 0;
+
+        Ok(futures::Async::Ready(SemaphorePermit {
+            semaphore: self.semaphore.clone(),
+            permits,
+        }))
+    }
 }
 
-impl futures::Future for SemaphoreHandle {
-    type Item = ();
-    type Error = io::Error;
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let inner = &self.semaphore.inner;
+            let mut waiters = inner.waiters.lock();
 
-    fn poll(&mut self) -> Result<futures::Async<()>, io::Error> {
-        match self {
-            &mut SemaphoreHandle::Completed(_) => Ok(futures::Async::Ready(())),
-            &mut SemaphoreHandle::Waiting => {
-                *self = SemaphoreHandle::Completed(futures::future::result(Ok(())));
-                Ok(futures::Async::NotReady)
+            if waiter.granted.load(Ordering::Acquire) {
+                // we have been handed permits that we are never going to use
+                inner.state.fetch_add(waiter.permits << FLAG_BITS, Ordering::SeqCst);
+            } else {
+                waiters.retain(|other| !sync::Arc::ptr_eq(other, &waiter));
             }
+
+            // removing a waiter from the head of the queue may allow others to proceed
+            inner.dispatch(&mut waiters);
         }
     }
 }
+
+/// Error returned by an `Acquire` future.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AcquireError {
+    /// The semaphore has been closed
+    Closed,
+
+    /// More permits were requested than the semaphore holds in total
+    TooManyPermits,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AcquireError::Closed => write!(f, "semaphore closed"),
+            AcquireError::TooManyPermits => write!(f, "more permits requested than the semaphore holds"),
+        }
+    }
+}
+
+impl error::Error for AcquireError {}
+
+/// Error returned by `Semaphore::try_acquire` and `Semaphore::try_acquire_many`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// The semaphore has been closed
+    Closed,
+
+    /// Not enough permits are available right now
+    NoPermits,
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            TryAcquireError::Closed => write!(f, "semaphore closed"),
+            TryAcquireError::NoPermits => write!(f, "no permits available"),
+        }
+    }
+}
+
+impl error::Error for TryAcquireError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+
+    use futures;
+    use futures::executor;
+    use futures::{Async, Future};
+
+    use super::*;
+
+    struct NoopNotify;
+
+    impl executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    fn poll<F: Future>(future: &mut executor::Spawn<F>) -> Result<Async<F::Item>, F::Error> {
+        future.poll_future_notify(&sync::Arc::new(NoopNotify), 0)
+    }
+
+    #[test]
+    fn permits_released_on_drop() {
+        let semaphore = Semaphore::new(2);
+
+        let permit = semaphore.try_acquire_many(2).unwrap();
+        assert!(semaphore.available_permits() == 0);
+        assert!(semaphore.try_acquire().err() == Some(TryAcquireError::NoPermits));
+
+        drop(permit);
+        assert!(semaphore.available_permits() == 2);
+    }
+
+    #[test]
+    fn fifo_handoff() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.try_acquire().unwrap();
+
+        let mut first = executor::spawn(semaphore.acquire());
+        let mut second = executor::spawn(semaphore.acquire());
+        assert!(poll(&mut first).unwrap().is_not_ready());
+        assert!(poll(&mut second).unwrap().is_not_ready());
+
+        // spurious polls must not hand out permits
+        assert!(poll(&mut second).unwrap().is_not_ready());
+        assert!(poll(&mut first).unwrap().is_not_ready());
+
+        // the released permit goes to the first waiter, and a newcomer cannot barge in
+        drop(permit);
+        assert!(semaphore.available_permits() == 0);
+        assert!(semaphore.try_acquire().err() == Some(TryAcquireError::NoPermits));
+        assert!(poll(&mut second).unwrap().is_not_ready());
+
+        let permit = match poll(&mut first).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("permit not handed over"),
+        };
+
+        drop(permit);
+        assert!(poll(&mut second).unwrap().is_ready());
+    }
+
+    #[test]
+    fn weighted_acquire() {
+        let semaphore = Semaphore::new(4);
+        let permit = semaphore.try_acquire_many(3).unwrap();
+
+        let mut large = executor::spawn(semaphore.acquire_many(3));
+        let mut small = executor::spawn(semaphore.acquire());
+        assert!(poll(&mut large).unwrap().is_not_ready());
+
+        // the small request queues up behind the large one
+        assert!(poll(&mut small).unwrap().is_not_ready());
+
+        drop(permit);
+        let large_permit = match poll(&mut large).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("permits not handed over"),
+        };
+        assert!(large_permit.permits() == 3);
+        assert!(poll(&mut small).unwrap().is_ready());
+    }
+
+    #[test]
+    fn cancelled_waiter_passes_permits_on() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.try_acquire().unwrap();
+
+        let mut first = executor::spawn(semaphore.acquire());
+        let mut second = executor::spawn(semaphore.acquire());
+        assert!(poll(&mut first).unwrap().is_not_ready());
+        assert!(poll(&mut second).unwrap().is_not_ready());
+
+        // the permit is handed to the first waiter, which goes away without picking it up
+        drop(permit);
+        drop(first);
+
+        assert!(poll(&mut second).unwrap().is_ready());
+    }
+
+    #[test]
+    fn oversized_acquire() {
+        let semaphore = Semaphore::new(4);
+        let permit = semaphore.try_acquire().unwrap();
+
+        // a request that can never be satisfied fails instead of holding up the queue
+        let mut oversized = executor::spawn(semaphore.acquire_many(5));
+        assert!(poll(&mut oversized).err() == Some(AcquireError::TooManyPermits));
+        assert!(semaphore.waiting() == 0);
+        assert!(semaphore.acquire_many(3).wait().is_ok());
+
+        // returning detached permits does not add to the total, but releasing new ones does
+        let detached = permit.forget();
+        semaphore.release(detached);
+        assert!(semaphore.acquire_many(5).wait().err() == Some(AcquireError::TooManyPermits));
+
+        semaphore.release(1);
+        assert!(semaphore.acquire_many(5).wait().unwrap().permits() == 5);
+    }
+
+    #[test]
+    fn close_fails_waiters() {
+        let semaphore = Semaphore::new(1);
+        let _permit = semaphore.try_acquire().unwrap();
+
+        let mut waiting = executor::spawn(semaphore.acquire());
+        assert!(poll(&mut waiting).unwrap().is_not_ready());
+
+        semaphore.close();
+        assert!(poll(&mut waiting).err() == Some(AcquireError::Closed));
+        assert!(semaphore.acquire().wait().is_err());
+        assert!(semaphore.try_acquire().err() == Some(TryAcquireError::Closed));
+    }
+
+    // Many weighted acquisitions from several threads, some of them abandoned while waiting;
+    // permits in use must never exceed the capacity, and all permits are returned eventually.
+    #[test]
+    fn contended_acquire() {
+        use std::sync::atomic::AtomicUsize;
+
+        let semaphore = Semaphore::new(3);
+        let in_use = sync::Arc::new(AtomicUsize::new(0));
+        let pool = ::futures_cpupool::CpuPool::new(8);
+
+        let futures: Vec<_> = (0..2000)
+            .map(|index| {
+                let semaphore = semaphore.clone();
+                let in_use = in_use.clone();
+                let acquire = semaphore.acquire_many(1 + index % 3);
+
+                if index % 5 == 4 {
+                    // poll once and give up
+                    let mut acquire = Some(acquire);
+                    return pool.spawn(futures::future::poll_fn(move || {
+                        let _ = acquire.take().unwrap().poll();
+                        Ok(Async::Ready(()))
+                    }));
+                }
+
+                pool.spawn(acquire.map(move |permit| {
+                    let total = in_use.fetch_add(permit.permits(), Ordering::SeqCst) + permit.permits();
+                    assert!(total <= 3);
+                    ::std::thread::yield_now();
+                    in_use.fetch_sub(permit.permits(), Ordering::SeqCst);
+                }))
+            })
+            .collect();
+
+        assert!(futures::future::join_all(futures).wait().is_ok());
+        assert!(semaphore.available_permits() == 3);
+        assert!(semaphore.waiting() == 0);
+    }
+}
@@ -0,0 +1,234 @@
+// ===============================================================================================
+// Copyright (c) 2018 Hans-Martin Will
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+// ===============================================================================================
+
+use std::cmp;
+use std::collections;
+use std::sync;
+use std::thread;
+use std::time;
+
+use futures;
+use parking_lot;
+
+// -----------------------------------------------------------------------------------------------
+// Timer thread
+// -----------------------------------------------------------------------------------------------
+
+// A pending callback, ordered by due time and then by registration
+struct Entry {
+    at: time::Instant,
+    seq: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // reversed, so that the binary heap yields the earliest entry first
+    fn cmp(&self, other: &Entry) -> cmp::Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+struct TimerState {
+    // pending callbacks
+    entries: collections::BinaryHeap<Entry>,
+
+    // sequence number for the next entry
+    seq: u64,
+
+    // is the background thread running?
+    running: bool,
+
+    // has the owner gone away?
+    shutdown: bool,
+}
+
+struct TimerShared {
+    state: parking_lot::Mutex<TimerState>,
+    changed: parking_lot::Condvar,
+}
+
+// One-shot callbacks at given points in time, invoked on a lazily started background thread.
+// This keeps time-based admission decisions independent of the executor that drives a context.
+pub(crate) struct Timer {
+    shared: sync::Arc<TimerShared>,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Timer {
+        Timer {
+            shared: sync::Arc::new(TimerShared {
+                state: parking_lot::Mutex::new(TimerState {
+                    entries: collections::BinaryHeap::new(),
+                    seq: 0,
+                    running: false,
+                    shutdown: false,
+                }),
+                changed: parking_lot::Condvar::new(),
+            }),
+        }
+    }
+
+    // Arrange for `callback` to be invoked at or after the given instant
+    pub(crate) fn schedule<F>(&self, at: time::Instant, callback: F)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.state.lock();
+
+        let seq = state.seq;
+        state.seq += 1;
+        state.entries.push(Entry {
+            at,
+            seq,
+            callback: Box::new(callback),
+        });
+
+        if state.running {
+            self.shared.changed.notify_one();
+        } else {
+            state.running = true;
+
+            let shared = self.shared.clone();
+            thread::spawn(move || Timer::run(&shared));
+        }
+    }
+
+    fn run(shared: &TimerShared) {
+        let mut state = shared.state.lock();
+
+        while !state.shutdown {
+            let next = state.entries.peek().map(|entry| entry.at);
+
+            match next {
+                None => {
+                    shared.changed.wait(&mut state);
+                }
+                Some(at) if at <= time::Instant::now() => {
+                    let entry = state.entries.pop().unwrap();
+                    parking_lot::MutexGuard::unlocked(&mut state, entry.callback);
+                }
+                Some(at) => {
+                    shared.changed.wait_until(&mut state, at);
+                }
+            }
+        }
+
+        state.entries.clear();
+        state.running = false;
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.shutdown = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Delays
+// -----------------------------------------------------------------------------------------------
+
+// Future completing at a given point in time, woken up via a timer
+pub(crate) struct Delay {
+    // when the delay has elapsed
+    at: time::Instant,
+
+    // the task waiting for the delay; registered with the timer on first poll
+    task: Option<sync::Arc<futures::task::AtomicTask>>,
+}
+
+impl Delay {
+    pub(crate) fn new(at: time::Instant) -> Delay {
+        Delay { at, task: None }
+    }
+
+    pub(crate) fn poll(&mut self, timer: &Timer) -> futures::Async<()> {
+        if time::Instant::now() >= self.at {
+            return futures::Async::Ready(());
+        }
+
+        match self.task {
+            Some(ref task) => task.register(),
+            None => {
+                let task = sync::Arc::new(futures::task::AtomicTask::new());
+                task.register();
+
+                let notify = task.clone();
+                timer.schedule(self.at, move || notify.notify());
+                self.task = Some(task);
+            }
+        }
+
+        futures::Async::NotReady
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn callbacks_in_order() {
+        let timer = Timer::new();
+        let (sender, receiver) = mpsc::channel();
+
+        let start = Instant::now();
+
+        for delay in &[50, 20, 40] {
+            let sender = parking_lot::Mutex::new(sender.clone());
+            let delay = *delay;
+
+            timer.schedule(start + Duration::from_millis(delay), move || {
+                sender.lock().send((delay, Instant::now())).unwrap();
+            });
+        }
+
+        for expected in &[20, 40, 50] {
+            let (delay, fired) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(delay == *expected);
+            assert!(fired >= start + Duration::from_millis(delay));
+        }
+
+        // dropping the timer discards pending callbacks
+        timer.schedule(start + Duration::from_secs(3600), move || panic!("timer outlived"));
+        drop(timer);
+    }
+}